@@ -0,0 +1,324 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use super::{Errors, GlulxTerp};
+
+// What the ACCELPARAM indices hold, all set up by the Inform library.
+mod param {
+    pub const CLASSES_TABLE: usize = 0;
+    pub const INDIV_PROP_START: usize = 1;
+    pub const CLASS_METACLASS: usize = 2;
+    pub const OBJECT_METACLASS: usize = 3;
+    pub const ROUTINE_METACLASS: usize = 4;
+    pub const STRING_METACLASS: usize = 5;
+    // Address of the `self` global.
+    pub const SELF: usize = 6;
+    pub const NUM_ATTR_BYTES: usize = 7;
+    // Default values of the common properties.
+    pub const CPV_START: usize = 8;
+}
+
+// Native replacements for Inform's veneer routines, which stories register with
+// ACCELFUNC. Calls to a registered address skip the bytecode entirely.
+#[derive(Debug, Default)]
+pub struct Accel {
+    // Function index by address.
+    functions: BTreeMap<u32, u32>,
+    params: [u32; 9],
+}
+
+impl Accel {
+    pub fn is_supported(index: u32) -> bool {
+        (1..=13).contains(&index)
+    }
+
+    // Index 0 cancels the acceleration of `addr`, unsupported ones are ignored.
+    pub fn set_function(&mut self, index: u32, addr: u32) {
+        if index == 0 {
+            self.functions.remove(&addr);
+        } else if Self::is_supported(index) {
+            self.functions.insert(addr, index);
+        }
+    }
+
+    pub fn set_param(&mut self, index: u32, value: u32) {
+        if let Some(param) = self.params.get_mut(index as usize) {
+            *param = value;
+        }
+    }
+
+    fn param(&self, index: usize) -> u32 {
+        self.params[index]
+    }
+
+    pub fn function(&self, addr: u32) -> Option<u32> {
+        self.functions.get(&addr).copied()
+    }
+}
+
+impl GlulxTerp {
+    // The result of the accelerated function at `addr`, or None if it isn't one.
+    // Missing arguments are zero, like unset locals would be.
+    pub(in crate::glulx_terp) fn call_accelerated(&mut self, addr: u32, args: &[u32]) -> Result<Option<u32>, Errors> {
+        let Some(index) = self.accel.function(addr) else { return Ok(None) };
+        let arg = |index: usize| args.get(index).copied().unwrap_or(0);
+        let (obj, id) = (arg(0), arg(1));
+        // 2 to 7 predate NUM_ATTR_BYTES and assume Inform 6's default of 7 attribute bytes,
+        // 8 to 13 are the same functions reading it from the parameters.
+        let attr_bytes = if index < 8 { 7 } else { self.accel.param(param::NUM_ATTR_BYTES) };
+        let value = match index {
+            1 => self.z_region(obj)?,
+            2 | 8 => self.cp_tab(obj, id, attr_bytes)?,
+            3 | 9 => self.ra_pr(obj, id, attr_bytes)?,
+            4 | 10 => self.rl_pr(obj, id, attr_bytes)?,
+            5 | 11 => self.oc_cl(obj, id, attr_bytes)?,
+            6 | 12 => self.rv_pr(obj, id, attr_bytes)?,
+            7 | 13 => self.op_pr(obj, id, attr_bytes)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    fn word(&self, addr: u32) -> Result<u32, Errors> {
+        self.memory.get_u32(addr).map_err(Errors::MemoryError)
+    }
+
+    // The veneer prints these and carries on, returning 0.
+    fn accel_error(&mut self, message: &str) -> Result<u32, Errors> {
+        for ch in format!("\n{message}\n").chars() {
+            self.glk.put_current_char(&mut self.memory, ch).map_err(Errors::Glk)?;
+        }
+        Ok(0)
+    }
+
+    // Z__Region: 1 for an object, 2 for a function, 3 for a string, 0 for anything else.
+    fn z_region(&self, addr: u32) -> Result<u32, Errors> {
+        if addr < 36 || addr >= self.memory.size() { return Ok(0) }
+        let kind = self.memory.get_u8(addr).map_err(Errors::MemoryError)?;
+        Ok(match kind {
+            0xE0.. => 3,
+            0xC0.. => 2,
+            0x70..=0x7F if addr >= self.memory.get_header().ram_start => 1,
+            _ => 0,
+        })
+    }
+
+    // Classes are the objects whose parent class, right after the attributes, is Class.
+    fn obj_in_class(&self, obj: u32, attr_bytes: u32) -> Result<bool, Errors> {
+        Ok(self.word(obj.wrapping_add(13 + attr_bytes))? == self.accel.param(param::CLASS_METACLASS))
+    }
+
+    // Individual properties from INDIV_PROP_START to 7 past it are the ones every class has.
+    fn is_class_property(&self, id: u32) -> bool {
+        id.wrapping_sub(self.accel.param(param::INDIV_PROP_START)) < 8
+    }
+
+    // CP__Tab: the address of the object's property entry for `id`, 0 if it has none.
+    fn cp_tab(&mut self, obj: u32, id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        if self.z_region(obj)? != 1 {
+            return self.accel_error("[** Programming error: tried to find the \".\" of (something) **]")
+        }
+
+        let table = self.word(obj.wrapping_add(4 * (3 + attr_bytes / 4)))?;
+        if table == 0 { return Ok(0) }
+        let count = self.word(table)?;
+
+        // Entries are 10 bytes sorted by their 2-byte id, the veneer finds them with BINARYSEARCH.
+        let (start, key) = (table.wrapping_add(4), id & 0xFFFF);
+        let (mut low, mut high) = (0u32, count);
+        while low < high {
+            let index = low + (high - low) / 2;
+            let entry = start.wrapping_add(index.wrapping_mul(10));
+            match (self.memory.get_u16(entry).map_err(Errors::MemoryError)? as u32).cmp(&key) {
+                Ordering::Equal => return Ok(entry),
+                Ordering::Less => low = index + 1,
+                Ordering::Greater => high = index,
+            }
+        }
+        Ok(0)
+    }
+
+    // The property entry `id` resolves to, as visible from the current `self`. Ids with
+    // a high half are Class::property, looked up on that class if the object belongs to it.
+    fn get_prop(&mut self, mut obj: u32, mut id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let mut class = 0;
+        if id & 0xFFFF_0000 != 0 {
+            class = self.word(self.accel.param(param::CLASSES_TABLE).wrapping_add((id & 0xFFFF) * 4))?;
+            if self.oc_cl(obj, class, attr_bytes)? == 0 { return Ok(0) }
+            id >>= 16;
+            obj = class;
+        }
+
+        let prop = self.cp_tab(obj, id, attr_bytes)?;
+        if prop == 0 { return Ok(0) }
+        if self.obj_in_class(obj, attr_bytes)? && class == 0 && !self.is_class_property(id) { return Ok(0) }
+
+        // Private properties are only visible to the object itself.
+        if self.word(self.accel.param(param::SELF))? != obj {
+            let flags = self.memory.get_u8(prop.wrapping_add(9)).map_err(Errors::MemoryError)?;
+            if flags & 1 != 0 { return Ok(0) }
+        }
+        Ok(prop)
+    }
+
+    // RA__Pr: the address of the property's value.
+    fn ra_pr(&mut self, obj: u32, id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let prop = self.get_prop(obj, id, attr_bytes)?;
+        if prop == 0 { return Ok(0) }
+        self.word(prop.wrapping_add(4))
+    }
+
+    // RL__Pr: the length of the property's value in bytes.
+    fn rl_pr(&mut self, obj: u32, id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let prop = self.get_prop(obj, id, attr_bytes)?;
+        if prop == 0 { return Ok(0) }
+        let words = self.memory.get_u16(prop.wrapping_add(2)).map_err(Errors::MemoryError)?;
+        Ok(4 * words as u32)
+    }
+
+    // OC__Cl: `ofclass`, 1 if `obj` belongs to `class`.
+    fn oc_cl(&mut self, obj: u32, class: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let [class_metaclass, object_metaclass, routine_metaclass, string_metaclass] =
+            [param::CLASS_METACLASS, param::OBJECT_METACLASS, param::ROUTINE_METACLASS, param::STRING_METACLASS]
+                .map(|index| self.accel.param(index));
+        match self.z_region(obj)? {
+            3 => return Ok((class == string_metaclass) as u32),
+            2 => return Ok((class == routine_metaclass) as u32),
+            1 => {}
+            _ => return Ok(0),
+        }
+
+        // Every object is either a Class (classes and the four metaclasses) or an Object.
+        if class == class_metaclass || class == object_metaclass {
+            let metaclasses = [class_metaclass, string_metaclass, routine_metaclass, object_metaclass];
+            let is_class = self.obj_in_class(obj, attr_bytes)? || metaclasses.contains(&obj);
+            return Ok((is_class == (class == class_metaclass)) as u32)
+        }
+        if class == string_metaclass || class == routine_metaclass { return Ok(0) }
+        if !self.obj_in_class(class, attr_bytes)? {
+            return self.accel_error("[** Programming error: tried to apply 'ofclass' with non-class **]")
+        }
+
+        // Property 2 lists the classes the object inherits from.
+        let prop = self.get_prop(obj, 2, attr_bytes)?;
+        if prop == 0 { return Ok(0) }
+        let list = self.word(prop.wrapping_add(4))?;
+        if list == 0 { return Ok(0) }
+        let len = self.memory.get_u16(prop.wrapping_add(2)).map_err(Errors::MemoryError)? as u32;
+        for index in 0..len {
+            if self.word(list.wrapping_add(4 * index))? == class { return Ok(1) }
+        }
+        Ok(0)
+    }
+
+    // RV__Pr: the property's first word, falling back to the default of common properties.
+    fn rv_pr(&mut self, obj: u32, id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let addr = self.ra_pr(obj, id, attr_bytes)?;
+        if addr != 0 { return self.word(addr) }
+
+        if id > 0 && id < self.accel.param(param::INDIV_PROP_START) {
+            return self.word(self.accel.param(param::CPV_START).wrapping_add(4 * id))
+        }
+        self.accel_error("[** Programming error: tried to read (something) **]")
+    }
+
+    // OP__Pr: `provides`, 1 if the object has the property.
+    fn op_pr(&mut self, obj: u32, id: u32, attr_bytes: u32) -> Result<u32, Errors> {
+        let indiv_start = self.accel.param(param::INDIV_PROP_START);
+        match self.z_region(obj)? {
+            // Strings provide print and print_to_array, functions provide call.
+            3 => return Ok((id == indiv_start.wrapping_add(6) || id == indiv_start.wrapping_add(7)) as u32),
+            2 => return Ok((id == indiv_start.wrapping_add(5)) as u32),
+            1 => {}
+            _ => return Ok(0),
+        }
+
+        if self.is_class_property(id) && self.obj_in_class(obj, attr_bytes)? { return Ok(1) }
+        Ok((self.ra_pr(obj, id, attr_bytes)? != 0) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::param;
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn accelerated_result_goes_to_the_store_operand() {
+        // The function at 0x80 would return 77, Z__Region replaces it.
+        let mut code = function(&[(4, 1)], 0xC1, &[
+            op(0x180, &[Arg::Const(1), Arg::Const(0x80)]),
+            op(0x161, &[Arg::Const(0x80), Arg::Const(0x40), Arg::Stack]),
+            op(0x161, &[Arg::Const(0x80), Arg::Const(0x1000), Arg::Local(0)]),
+        ]);
+        code.resize(0x40, 0);
+        code.extend(function(&[(4, 1)], 0xC1, &[op(0x31, &[Arg::Const(77)])]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.stack.set_local(0, 4, 0xFF).unwrap();
+        let sp = terp.stack.sp();
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.sp(), sp + 4);
+        assert_eq!(terp.stack.pop().unwrap(), 2);
+
+        terp.step().unwrap();
+        assert_eq!(terp.stack.sp(), sp);
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 0);
+    }
+
+    // One object at 0x180 with 7 attribute bytes and two properties: 3, whose value
+    // 42 is at 0x1E0, and the private 5, whose value 43 is at 0x1E4.
+    fn object_story() -> GlulxTerp {
+        let mut ram = vec![0u8; 0x100];
+        let mut put = |addr: usize, bytes: &[u8]| ram[addr - 0x100..addr - 0x100 + bytes.len()].copy_from_slice(bytes);
+        put(0x180, &[0x70]);
+        put(0x190, &0x1A0_u32.to_be_bytes());
+        put(0x1A0, &2_u32.to_be_bytes());
+        // Entries are id, length in words, address of the value and flags.
+        put(0x1A4, &[0, 3, 0, 1, 0, 0, 0x01, 0xE0, 0, 0]);
+        put(0x1AE, &[0, 5, 0, 2, 0, 0, 0x01, 0xE4, 0, 1]);
+        put(0x1E0, &[0, 0, 0, 42, 0, 0, 0, 43]);
+        // The default of common property 4.
+        put(0x1D0, &99_u32.to_be_bytes());
+
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &ram)).unwrap();
+        for (index, value) in [
+            (param::INDIV_PROP_START, 0x100), (param::CLASS_METACLASS, 0x1B8), (param::OBJECT_METACLASS, 0x1B0),
+            (param::ROUTINE_METACLASS, 0x1B4), (param::STRING_METACLASS, 0x1BC), (param::SELF, 0x1F0),
+            (param::NUM_ATTR_BYTES, 7), (param::CPV_START, 0x1C0),
+        ] {
+            terp.accel.set_param(index as u32, value);
+        }
+        terp
+    }
+
+    #[test]
+    fn veneer_functions_read_the_object_tables() {
+        let mut terp = object_story();
+        // Both versions of each function, from CP__Tab (2) to OP__Pr (7).
+        for first in [2, 8] {
+            for offset in 0..6 {
+                terp.accel.set_function(first + offset, 0x1000 + offset);
+            }
+            let mut call = |offset: u32, obj: u32, id: u32| terp.call_accelerated(0x1000 + offset, &[obj, id]).unwrap().unwrap();
+            assert_eq!(call(0, 0x180, 3), 0x1A4);
+            assert_eq!(call(0, 0x180, 4), 0);
+            assert_eq!(call(1, 0x180, 3), 0x1E0);
+            assert_eq!(call(2, 0x180, 3), 4);
+            assert_eq!(call(3, 0x180, 0x1B0), 1);
+            assert_eq!(call(3, 0x180, 0x1B8), 0);
+            assert_eq!(call(3, 0x40, 0x1B4), 1);
+            assert_eq!(call(4, 0x180, 3), 42);
+            assert_eq!(call(4, 0x180, 4), 99);
+            assert_eq!(call(5, 0x180, 3), 1);
+
+            // Property 5 is only visible once the object is `self`.
+            assert_eq!(call(4, 0x180, 5), 0);
+            assert_eq!(call(5, 0x180, 5), 0);
+            terp.memory.set_u32(0x1F0, 0x180).unwrap();
+            let mut call = |offset: u32, obj: u32, id: u32| terp.call_accelerated(0x1000 + offset, &[obj, id]).unwrap().unwrap();
+            assert_eq!(call(4, 0x180, 5), 43);
+            assert_eq!(call(2, 0x180, 5), 8);
+            assert_eq!(call(5, 0x180, 5), 1);
+            terp.memory.set_u32(0x1F0, 0).unwrap();
+        }
+    }
+}
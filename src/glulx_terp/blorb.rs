@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum BlorbError {
+    NotBlorb,
+    Truncated(u32),
+    MissingIndex,
+    // Neither the index nor the top level of the form has a GLUL chunk.
+    MissingExecutable,
+}
+
+pub mod usage {
+    pub const PICT: [u8; 4] = *b"Pict";
+    pub const SND: [u8; 4] = *b"Snd ";
+    pub const DATA: [u8; 4] = *b"Data";
+    pub const EXEC: [u8; 4] = *b"Exec";
+}
+
+// One chunk of the file, as pointed to by the resource index.
+#[derive(Debug, Clone, Copy)]
+pub struct Resource {
+    pub kind: [u8; 4],
+    // Position and length of the chunk's data, after its 8-byte header.
+    pub start: u32,
+    pub len: u32,
+}
+
+// An IFF "FORM"/"IFRS" container with its RIdx resource index parsed.
+#[derive(Debug)]
+pub struct Blorb {
+    raw: Vec<u8>,
+    resources: BTreeMap<([u8; 4], u32), Resource>,
+}
+
+fn read_u32(raw: &[u8], pos: u32) -> Result<u32, BlorbError> {
+    let pos = pos as usize;
+    raw.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(BlorbError::Truncated(pos as u32))
+}
+
+fn read_id(raw: &[u8], pos: u32) -> Result<[u8; 4], BlorbError> {
+    read_u32(raw, pos).map(u32::to_be_bytes)
+}
+
+impl Blorb {
+    pub fn is_blorb(raw: &[u8]) -> bool {
+        raw.len() >= 12 && &raw[0..4] == b"FORM" && &raw[8..12] == b"IFRS"
+    }
+
+    pub fn parse(raw: Vec<u8>) -> Result<Self, BlorbError> {
+        if !Self::is_blorb(&raw) { return Err(BlorbError::NotBlorb) }
+
+        // The index is the first chunk of the form.
+        if read_id(&raw, 12)? != *b"RIdx" { return Err(BlorbError::MissingIndex) }
+        let count = read_u32(&raw, 20)?;
+
+        let mut resources = BTreeMap::new();
+        for index in 0..count {
+            let entry = 24 + index * 12;
+            let usage = read_id(&raw, entry)?;
+            let number = read_u32(&raw, entry + 4)?;
+            let start = read_u32(&raw, entry + 8)?;
+
+            let kind = read_id(&raw, start)?;
+            let len = read_u32(&raw, start + 4)?;
+            if (start as u64 + 8 + len as u64) > raw.len() as u64 { return Err(BlorbError::Truncated(start)) }
+            resources.insert((usage, number), Resource { kind, start: start + 8, len });
+        }
+
+        Ok(Self { raw, resources })
+    }
+
+    // The Glulx story, the index's Exec 0 resource or else the first top-level GLUL chunk.
+    pub fn executable(&self) -> Result<&[u8], BlorbError> {
+        if let Some(resource) = self.resource(usage::EXEC, 0).filter(|resource| &resource.kind == b"GLUL") {
+            return Ok(self.data(resource))
+        }
+
+        // Chunks are padded to an even length.
+        let end = (read_u32(&self.raw, 4)? as u64 + 8).min(self.raw.len() as u64) as u32;
+        let mut pos = 12;
+        while pos + 8 <= end {
+            let len = read_u32(&self.raw, pos + 4)?;
+            let start = pos + 8;
+            if start as u64 + len as u64 > self.raw.len() as u64 { return Err(BlorbError::Truncated(pos)) }
+            if read_id(&self.raw, pos)? == *b"GLUL" {
+                return Ok(&self.raw[start as usize..(start + len) as usize])
+            }
+            pos = start + len + (len & 1);
+        }
+        Err(BlorbError::MissingExecutable)
+    }
+
+    pub fn resource(&self, usage: [u8; 4], number: u32) -> Option<Resource> {
+        self.resources.get(&(usage, number)).copied()
+    }
+
+    pub fn data(&self, resource: Resource) -> &[u8] {
+        &self.raw[resource.start as usize..(resource.start + resource.len) as usize]
+    }
+
+    // Natural size of a PNG or JPEG picture, read from its header.
+    pub fn image_size(&self, number: u32) -> Option<(u32, u32)> {
+        let resource = self.resource(usage::PICT, number)?;
+        let data = self.data(resource);
+        match &resource.kind {
+            b"PNG " => png_size(data),
+            b"JPEG" => jpeg_size(data),
+            _ => None,
+        }
+    }
+}
+
+fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    // Signature, then the IHDR chunk whose data starts with width and height.
+    if data.get(12..16)? != b"IHDR" { return None }
+    Some((read_u32(data, 16).ok()?, read_u32(data, 20).ok()?))
+}
+
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF { return None }
+        let marker = *data.get(pos + 1)?;
+        let len = u16::from_be_bytes(data.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        // Any start-of-frame marker except DHT, JPG and DAC carries the dimensions.
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes(data.get(pos + 5..pos + 7)?.try_into().ok()?) as u32;
+            let width = u16::from_be_bytes(data.get(pos + 7..pos + 9)?.try_into().ok()?) as u32;
+            return Some((width, height))
+        }
+        pos += 2 + len;
+    }
+}
@@ -0,0 +1,32 @@
+#[derive(Debug, Clone)]
+pub struct TerpConfig {
+    // Refuse to load a story whose checksum doesn't match the header. Off by default like
+    // in other interpreters, the story can still check itself with VERIFY.
+    pub verify_checksum: bool,
+    // Reject headers that don't follow the spec's layout rules (alignment, ordering, version).
+    pub strict: bool,
+    // Maximum number of instructions `run` executes before giving up.
+    pub cycle_limit: Option<u64>,
+    // Turn panics raised while executing an instruction into Errors::InternalPanic.
+    pub catch_panics: bool,
+    // Seed for RANDOM, None seeds from the system like SETRANDOM 0 does.
+    pub random_seed: Option<u32>,
+    // How many SAVEUNDO states are kept, the oldest go first. 0 disables undo.
+    pub undo_depth: usize,
+    // Most bytes MALLOC hands out at once, None leaves only the memory size limit.
+    pub heap_limit: Option<u32>,
+}
+
+impl Default for TerpConfig {
+    fn default() -> Self {
+        Self {
+            verify_checksum: false,
+            strict: false,
+            cycle_limit: None,
+            catch_panics: false,
+            random_seed: None,
+            undo_depth: 32,
+            heap_limit: None,
+        }
+    }
+}
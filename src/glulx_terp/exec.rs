@@ -0,0 +1,208 @@
+use super::{glk::{backend::HeadlessBackend, event::GlkEvent}, Errors, GlulxTerp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    // Blocked in glk_select until the front end provides an event.
+    AwaitingInput,
+    CycleLimit,
+    // The predicate given to run_until asked to stop.
+    Condition,
+    // DEBUGTRAP with its operand, for a debugger to look around before resuming.
+    DebugTrap(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecState {
+    Running,
+    Paused(PauseReason),
+    // The outermost function returned, nothing is left to execute.
+    Halted,
+    // QUIT ended the story.
+    Terminated,
+}
+
+impl GlulxTerp {
+    pub fn state(&self) -> ExecState {
+        self.state
+    }
+
+    // Core of every run loop: steps until the story halts, blocks waiting for input
+    // the backend can't read itself, or `pause` returns true. The predicate is checked before each instruction.
+    pub fn run_until(&mut self, mut pause: impl FnMut(&Self) -> bool) -> Result<ExecState, Errors> {
+        loop {
+            if self.has_halted() {
+                return Ok(self.state)
+            }
+            if self.glk.poll_timer(&mut self.memory).map_err(Errors::Glk)? {
+                continue
+            }
+            if self.glk.is_waiting() {
+                if self.glk.read_backend_line(&mut self.memory).map_err(Errors::Glk)? {
+                    continue
+                }
+                self.state = ExecState::Paused(PauseReason::AwaitingInput);
+                return Ok(self.state)
+            }
+            if pause(self) {
+                self.state = ExecState::Paused(PauseReason::Condition);
+                return Ok(self.state)
+            }
+
+            self.state = ExecState::Running;
+            self.step()?;
+            if matches!(self.state, ExecState::Paused(PauseReason::DebugTrap(_))) {
+                return Ok(self.state)
+            }
+        }
+    }
+
+    pub fn run_with_limit(&mut self, max_steps: Option<u64>) -> Result<ExecState, Errors> {
+        let Some(max_steps) = max_steps else { return self.run_until(|_| false) };
+
+        let start = self.stats.instructions;
+        let state = self.run_until(|terp| terp.stats.instructions - start >= max_steps)?;
+        if state == ExecState::Paused(PauseReason::Condition) {
+            self.state = ExecState::Paused(PauseReason::CycleLimit);
+        }
+        Ok(self.state)
+    }
+
+    // Runs until glk_select hands an event to the story and returns it. None means the
+    // story halted or is waiting for input that hasn't been provided yet.
+    pub fn run_until_event(&mut self) -> Result<Option<GlkEvent>, Errors> {
+        self.run_until(|terp| terp.glk.has_delivered_event())?;
+        Ok(self.glk.take_delivered_event())
+    }
+
+    // Plays a recorded walkthrough: each command is typed at the next prompt, and
+    // once they've all been used the story runs to its next prompt (or its end).
+    // Returns everything printed, echoed commands included, for comparing with a
+    // known good transcript. Output goes to a fresh HeadlessBackend while replaying,
+    // the backend set before is put back afterwards, even when the replay fails.
+    pub fn replay(&mut self, commands: &[&str]) -> Result<String, Errors> {
+        let previous = self.glk.replace_backend(Box::new(HeadlessBackend::default()));
+        let played = self.type_commands(commands);
+        let transcript = self.glk_backend::<HeadlessBackend>().map(HeadlessBackend::transcript).unwrap_or_default().to_string();
+        self.glk.set_backend(previous);
+        played.map(|_| transcript)
+    }
+
+    fn type_commands(&mut self, commands: &[&str]) -> Result<(), Errors> {
+        let mut commands = commands.iter();
+        while self.run_until(|_| false)? == ExecState::Paused(PauseReason::AwaitingInput) {
+            let Some(command) = commands.next() else { break };
+            self.provide_input(command)?;
+        }
+        Ok(())
+    }
+
+    // Runs under the configured cycle limit, where running out of cycles is a fault.
+    pub fn run(&mut self) -> Result<ExecState, Errors> {
+        match self.run_with_limit(self.config.cycle_limit)? {
+            ExecState::Paused(PauseReason::CycleLimit) => {
+                Err(Errors::CycleLimitReached(self.config.cycle_limit.unwrap_or_default()))
+            }
+            state => Ok(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ExecState, PauseReason};
+    use crate::glulx_terp::{glk::{clock::ManualClock, event::evtype, grid_snapshot::GridSnapshotBackend}, test_support::{echo_story, function, glk, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn input_pauses_and_resumes_until_halted() {
+        let mut terp = GlulxTerp::from_bytes(echo_story(2)).unwrap();
+        assert_eq!(terp.state(), ExecState::Running);
+        for name in ["Ada", "Grace"] {
+            assert_eq!(terp.run_until(|_| false).unwrap(), ExecState::Paused(PauseReason::AwaitingInput));
+            terp.provide_input(name).unwrap();
+            assert_eq!(terp.state(), ExecState::Running);
+        }
+        assert_eq!(terp.run_until(|_| false).unwrap(), ExecState::Halted);
+        assert!(terp.has_halted());
+    }
+
+    #[test]
+    fn debugtrap_pauses_until_the_next_step() {
+        let code = function(&[], 0xC1, &[
+            op(0x101, &[Arg::Const(7)]),
+            op(0x10, &[Arg::Const(1), Arg::Const(1), Arg::Stack]),
+            op(0x101, &[Arg::Const(8)]),
+            op(0x120, &[]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        assert_eq!(terp.run_until(|_| false).unwrap(), ExecState::Paused(PauseReason::DebugTrap(7)));
+        assert_eq!(terp.state(), ExecState::Paused(PauseReason::DebugTrap(7)));
+
+        assert_eq!(terp.step().unwrap(), ExecState::Running);
+        assert_eq!(terp.stack.peek(0).unwrap(), 2);
+        assert_eq!(terp.step().unwrap(), ExecState::Paused(PauseReason::DebugTrap(8)));
+        assert_eq!(terp.run_until(|_| false).unwrap(), ExecState::Terminated);
+    }
+
+    #[test]
+    fn step_does_nothing_once_the_story_is_over() {
+        // QUIT, with code after it that must never run.
+        let code = function(&[], 0xC1, &[op(0x120, &[]), op(0x10, &[Arg::Const(1), Arg::Const(1), Arg::Stack])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        assert_eq!(terp.step().unwrap(), ExecState::Terminated);
+        let pc = terp.pc();
+        for _ in 0..3 {
+            assert_eq!(terp.step().unwrap(), ExecState::Terminated);
+        }
+        assert_eq!((terp.pc(), terp.stack.count(), terp.stats().instructions), (pc, 0, 1));
+
+        // Past the return of the start function the pc points at whatever follows it.
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(0x31, &[Arg::Zero])]), &[])).unwrap();
+        assert_eq!(terp.step().unwrap(), ExecState::Halted);
+        assert_eq!(terp.step().unwrap(), ExecState::Halted);
+        assert_eq!(terp.stats().instructions, 1);
+    }
+
+    #[test]
+    fn replay_types_each_command_at_a_prompt() {
+        let mut terp = GlulxTerp::from_bytes(echo_story(3)).unwrap();
+        let transcript = terp.replay(&["Ada", "Grace", "Alan"]).unwrap();
+        let expected: String = ["Ada", "Grace", "Alan"].iter()
+            .map(|name| format!("What is your name?\n>{name}\nHello, {name}!\n"))
+            .collect();
+        assert_eq!(transcript, expected);
+        assert!(terp.has_halted());
+
+        // Running out of commands stops at the next prompt.
+        let mut terp = GlulxTerp::from_bytes(echo_story(3)).unwrap();
+        terp.set_glk_backend(Box::new(GridSnapshotBackend::new(20, 4)));
+        assert_eq!(terp.replay(&["Ada"]).unwrap(), "What is your name?\n>Ada\nHello, Ada!\nWhat is your name?\n>");
+        assert_eq!(terp.state(), ExecState::Paused(PauseReason::AwaitingInput));
+        // The backend from before the replay is back in place.
+        assert!(terp.glk_backend::<GridSnapshotBackend>().is_some());
+    }
+
+    #[test]
+    fn timer_ends_a_select_waiting_for_input() {
+        // Asks for timer ticks every 100ms and for a line, then waits in glk_select.
+        let code = function(&[(4, 1)], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Local(0)),
+            glk(0xD6, vec![Arg::Const(100)], Arg::Zero),
+            glk(0xD0, vec![Arg::Local(0), Arg::Const(0x100), Arg::Const(16), Arg::Zero], Arg::Zero),
+            glk(0xC0, vec![Arg::Const(0x120)], Arg::Zero),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 0x30])).unwrap();
+        let clock = ManualClock::default();
+        terp.set_clock(Box::new(clock.clone()));
+
+        assert_eq!(terp.run_until_event().unwrap(), None);
+        assert_eq!(terp.state(), ExecState::Paused(PauseReason::AwaitingInput));
+        clock.advance(Duration::from_millis(150));
+        let event = terp.run_until_event().unwrap().unwrap();
+        assert_eq!(event.kind, evtype::TIMER);
+        assert_eq!(terp.memory.get_u32(0x120).unwrap(), evtype::TIMER);
+        assert_eq!(terp.run_until(|_| false).unwrap(), ExecState::Halted);
+    }
+}
@@ -0,0 +1,90 @@
+use super::{accel::Accel, heap::Heap, iosys::mode, GlulxTerp};
+
+pub mod selector {
+    pub const GLULX_VERSION: u32 = 0;
+    pub const TERP_VERSION: u32 = 1;
+    pub const RESIZE_MEM: u32 = 2;
+    pub const UNDO: u32 = 3;
+    pub const IO_SYSTEM: u32 = 4;
+    pub const UNICODE: u32 = 5;
+    pub const MEM_COPY: u32 = 6;
+    pub const MALLOC: u32 = 7;
+    pub const MALLOC_HEAP: u32 = 8;
+    pub const ACCELERATION: u32 = 9;
+    pub const ACCEL_FUNC: u32 = 10;
+    pub const FLOAT: u32 = 11;
+    pub const EXT_UNDO: u32 = 12;
+    pub const DOUBLE: u32 = 13;
+}
+
+impl GlulxTerp {
+    // GESTALT: what the interpreter supports. Unknown selectors answer 0, as the spec wants.
+    pub fn gestalt(&self, selector: u32, arg: u32) -> u32 {
+        match selector {
+            // Spec version 3.1.3.
+            selector::GLULX_VERSION => 0x0003_0103,
+            selector::TERP_VERSION => 0x0000_0100,
+            selector::RESIZE_MEM => 1,
+            selector::UNDO => 1,
+            selector::IO_SYSTEM => matches!(arg, mode::NULL | mode::FILTER | mode::GLK) as u32,
+            // STREAMUNICHAR and E2 strings.
+            selector::UNICODE => 1,
+            selector::MEM_COPY => 1,
+            selector::MALLOC => 1,
+            // Where the heap starts, 0 while nothing is allocated.
+            selector::MALLOC_HEAP => self.heap.as_ref().map_or(0, Heap::start),
+            selector::ACCELERATION => 1,
+            selector::ACCEL_FUNC => Accel::is_supported(arg) as u32,
+            // HASUNDO, DISCARDUNDO and several levels of RESTOREUNDO.
+            selector::EXT_UNDO => 1,
+            selector::FLOAT => 1,
+            selector::DOUBLE => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::selector::*;
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn gestalt_answers() {
+        let terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[])).unwrap();
+        for (selector, arg, expected) in [
+            (GLULX_VERSION, 0, 0x0003_0103),
+            (TERP_VERSION, 0, 0x100),
+            (RESIZE_MEM, 0, 1),
+            (UNDO, 0, 1),
+            (IO_SYSTEM, 0, 1),
+            (IO_SYSTEM, 2, 1),
+            (IO_SYSTEM, 20, 0),
+            (UNICODE, 0, 1),
+            (MEM_COPY, 0, 1),
+            (MALLOC, 0, 1),
+            (MALLOC_HEAP, 0, 0),
+            (ACCELERATION, 0, 1),
+            (ACCEL_FUNC, 1, 1),
+            (ACCEL_FUNC, 13, 1),
+            (ACCEL_FUNC, 14, 0),
+            (FLOAT, 0, 1),
+            (EXT_UNDO, 0, 1),
+            (DOUBLE, 0, 1),
+            (999, 0, 0),
+        ] {
+            assert_eq!(terp.gestalt(selector, arg), expected, "selector {selector} with {arg}");
+        }
+    }
+
+    #[test]
+    fn malloc_heap_reports_the_start_once_allocated() {
+        let code = function(&[], 0xC1, &[
+            op(0x178, &[Arg::Const(0x10), Arg::Zero]),
+            op(0x100, &[Arg::Const(MALLOC_HEAP as i32), Arg::Zero, Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.pop().unwrap(), 0x200);
+    }
+}
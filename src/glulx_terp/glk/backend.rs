@@ -0,0 +1,117 @@
+use std::any::Any;
+
+// What the front-end behind the Glk layer is able to do, reported through glk_gestalt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub mouse_input: bool,
+    pub graphics: bool,
+    pub graphics_transparency: bool,
+    pub graphics_char_input: bool,
+}
+
+// A picture placed by glk_image_draw or glk_image_draw_scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDraw {
+    pub window: u32,
+    pub image: u32,
+    // Position in graphics windows, alignment (and unused) in text buffers.
+    pub val1: u32,
+    pub val2: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub trait GlkBackend: Any {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    // Arguments are those of glk_window_open, `split` being 0 for the root window.
+    fn window_opened(&mut self, _window: u32, _kind: u32, _split: u32, _method: u32, _size: u32) {}
+
+    // glk_window_set_arrangement changed the split that opened `window`.
+    fn window_arranged(&mut self, _window: u32, _method: u32, _size: u32) {}
+
+    // Called for every window that goes away, pairs and the windows inside them included.
+    fn window_closed(&mut self, _window: u32) {}
+
+    fn put_char(&mut self, window: u32, ch: char);
+
+    // Blocks until the player entered a line. Backends without an input device of
+    // their own return None, their input comes through GlulxTerp::provide_input.
+    fn read_line(&mut self) -> Option<String> {
+        None
+    }
+
+    // Whether the input device already shows what was typed (a terminal does), in
+    // which case Glk doesn't echo the line into the window again.
+    fn echoes_input(&self) -> bool {
+        false
+    }
+
+    fn clear_window(&mut self, _window: u32) {}
+
+    // Text printed to `window` from now on is in `style`.
+    fn set_style(&mut self, _window: u32, _style: u32) {}
+
+    // glk_style_distinguish: whether the two styles look different in `window`.
+    fn style_distinguish(&self, _window: u32, _style1: u32, _style2: u32) -> bool {
+        false
+    }
+
+    // Text printed to `window` from now on links to `link`, 0 ending the link.
+    fn set_hyperlink(&mut self, _window: u32, _link: u32) {}
+
+    // Width and height in characters for text windows, in pixels for graphics ones.
+    fn window_size(&self, _window: u32) -> (u32, u32) {
+        (0, 0)
+    }
+
+    // Only meaningful for text grid windows.
+    fn move_cursor(&mut self, _window: u32, _x: u32, _y: u32) {}
+
+    // Returns whether the picture could be drawn.
+    fn draw_image(&mut self, _draw: ImageDraw) -> bool {
+        false
+    }
+
+    // glk_style_measure: how `hint` of `style` actually looks in `window`, given the
+    // value the story requested (if any). Backends that can't tell return None.
+    fn style_measure(&self, _window: u32, _style: u32, _hint: u32, _requested: Option<u32>) -> Option<u32> {
+        None
+    }
+}
+
+// Backend without any display, used when the interpreter is embedded or tested.
+// Everything printed to any window ends up in the transcript.
+#[derive(Debug, Default)]
+pub struct HeadlessBackend {
+    transcript: String,
+    images: Vec<ImageDraw>,
+}
+
+impl HeadlessBackend {
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    pub fn images(&self) -> &[ImageDraw] {
+        &self.images
+    }
+}
+
+impl GlkBackend for HeadlessBackend {
+    fn put_char(&mut self, _window: u32, ch: char) {
+        self.transcript.push(ch);
+    }
+
+    // Nothing is rendered, so every hint is taken exactly as requested.
+    fn style_measure(&self, _window: u32, _style: u32, _hint: u32, requested: Option<u32>) -> Option<u32> {
+        requested
+    }
+
+    fn draw_image(&mut self, draw: ImageDraw) -> bool {
+        self.images.push(draw);
+        true
+    }
+}
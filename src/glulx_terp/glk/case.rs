@@ -0,0 +1,63 @@
+// Case conversion for glk_buffer_to_*_case_uni. Mappings may change the
+// number of characters (e.g. 'ß' uppercases to "SS").
+
+pub fn to_lower(text: &[char]) -> Vec<char> {
+    text.iter().flat_map(|ch| ch.to_lowercase()).collect()
+}
+
+pub fn to_upper(text: &[char]) -> Vec<char> {
+    text.iter().flat_map(|ch| ch.to_uppercase()).collect()
+}
+
+// The first character of each word is title-cased, words being separated by
+// whitespace. The rest is lowercased or left as is.
+pub fn to_title(text: &[char], lower_rest: bool) -> Vec<char> {
+    let mut result = Vec::with_capacity(text.len());
+    let mut word_start = true;
+    for &ch in text {
+        if word_start && !ch.is_whitespace() {
+            result.extend(title_char(ch));
+        } else if lower_rest {
+            result.extend(ch.to_lowercase());
+        } else {
+            result.push(ch);
+        }
+        word_start = ch.is_whitespace();
+    }
+    result
+}
+
+// Rust has no title case mapping, it only differs from uppercase for a few
+// digraphs and for characters whose uppercase is several letters.
+fn title_char(ch: char) -> Vec<char> {
+    match ch {
+        '\u{01C4}'..='\u{01C6}' => vec!['\u{01C5}'],
+        '\u{01C7}'..='\u{01C9}' => vec!['\u{01C8}'],
+        '\u{01CA}'..='\u{01CC}' => vec!['\u{01CB}'],
+        '\u{01F1}'..='\u{01F3}' => vec!['\u{01F2}'],
+        _ => {
+            let mut upper = ch.to_uppercase();
+            let first = upper.next().unwrap_or(ch);
+            std::iter::once(first).chain(upper.flat_map(char::to_lowercase)).collect()
+        }
+    }
+}
+
+// glk_char_to_lower and glk_char_to_upper work on Latin-1 only. Characters without
+// a single Latin-1 counterpart ('ß', 'ÿ') are returned unchanged.
+pub fn latin1_to_lower(ch: u32) -> u32 {
+    convert_latin1(ch, char::to_lowercase)
+}
+
+pub fn latin1_to_upper(ch: u32) -> u32 {
+    convert_latin1(ch, char::to_uppercase)
+}
+
+fn convert_latin1<I: Iterator<Item = char>>(ch: u32, convert: impl Fn(char) -> I) -> u32 {
+    if ch > 0xFF { return ch }
+    let mut mapped = convert(ch as u8 as char);
+    match (mapped.next(), mapped.next()) {
+        (Some(single), None) if (single as u32) < 0x100 => single as u32,
+        _ => ch,
+    }
+}
@@ -0,0 +1,42 @@
+use std::{cell::Cell, rc::Rc, time::{Duration, Instant}};
+
+// Source of time for timer events, swappable so tests don't depend on the wall clock.
+pub trait Clock {
+    // Time elapsed since some fixed starting point.
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+// Only moves when told to. Clones share the same time, so a test can keep one
+// and hand the other to the interpreter.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use super::backend::GlkBackend;
+
+// Plays in a terminal: every window prints to the output, line input is read from
+// the input. The story only gets to run again once a line was entered.
+pub struct ConsoleBackend {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    // A terminal shows typed lines by itself, piped input has to be echoed.
+    echoes: bool,
+}
+
+impl ConsoleBackend {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self { input, output, echoes: false }
+    }
+
+    pub fn stdio() -> Self {
+        Self {
+            echoes: io::stdin().is_terminal(),
+            input: Box::new(io::stdin().lock()),
+            output: Box::new(io::stdout()),
+        }
+    }
+}
+
+impl GlkBackend for ConsoleBackend {
+    fn put_char(&mut self, _window: u32, ch: char) {
+        let mut buffer = [0; 4];
+        // Output errors (like a closed pipe) aren't the story's concern.
+        let _ = self.output.write_all(ch.encode_utf8(&mut buffer).as_bytes());
+    }
+
+    // None at the end of the input, leaving the story waiting.
+    fn read_line(&mut self) -> Option<String> {
+        let _ = self.output.flush();
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let len = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(len);
+                Some(line)
+            }
+        }
+    }
+
+    fn echoes_input(&self) -> bool {
+        self.echoes
+    }
+}
+
+impl Drop for ConsoleBackend {
+    fn drop(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    use super::*;
+    use crate::glulx_terp::{exec::ExecState, test_support::{function, glk, op, story, Arg}, GlulxTerp};
+
+    // Output the test can still read once the backend is dropped.
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn typed_line_reaches_the_story() {
+        let code = function(&[(4, 1)], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Local(0)),
+            glk(0x2F, vec![Arg::Local(0)], Arg::Zero),
+            glk(0x80, vec![Arg::Const('>' as i32)], Arg::Zero),
+            glk(0xD0, vec![Arg::Local(0), Arg::Const(0x100), Arg::Const(10), Arg::Zero], Arg::Zero),
+            glk(0xC0, vec![Arg::Const(0x120)], Arg::Zero),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 0x40])).unwrap();
+        terp.set_glk_backend(Box::new(ConsoleBackend::new(Box::new(Cursor::new(b"look\r\n".to_vec())), Box::new(Shared(output.clone())))));
+
+        assert_eq!(terp.run().unwrap(), ExecState::Halted);
+        assert_eq!(terp.memory.get_u32(0x128).unwrap(), 4);
+        assert_eq!((0x100..0x104).map(|addr| terp.memory.get_u8(addr).unwrap()).collect::<Vec<_>>(), b"look");
+        drop(terp);
+        assert_eq!(String::from_utf8(output.take()).unwrap(), ">look\n");
+    }
+}
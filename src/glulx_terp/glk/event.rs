@@ -0,0 +1,34 @@
+pub mod evtype {
+    pub const NONE: u32 = 0;
+    pub const TIMER: u32 = 1;
+    pub const CHAR_INPUT: u32 = 2;
+    pub const LINE_INPUT: u32 = 3;
+    pub const MOUSE_INPUT: u32 = 4;
+    pub const ARRANGE: u32 = 5;
+    pub const REDRAW: u32 = 6;
+    pub const SOUND_NOTIFY: u32 = 7;
+    pub const HYPERLINK: u32 = 8;
+    pub const VOLUME_NOTIFY: u32 = 9;
+}
+
+// Special keys, as reported in val2 of a line event ended by a terminator.
+pub mod keycode {
+    pub const RETURN: u32 = 0xFFFF_FFFA;
+    pub const ESCAPE: u32 = 0xFFFF_FFF8;
+    pub const FUNC1: u32 = 0xFFFF_FFEF;
+    pub const FUNC12: u32 = 0xFFFF_FFE4;
+
+    // Escape and the function keys are the only keys that can end line input.
+    pub fn is_terminator(key: u32) -> bool {
+        key == ESCAPE || (FUNC12..=FUNC1).contains(&key)
+    }
+}
+
+// Mirrors the four words of Glk's event_t as written back to the story.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlkEvent {
+    pub kind: u32,
+    pub window: u32,
+    pub val1: u32,
+    pub val2: u32,
+}
@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+
+pub mod fileusage {
+    pub const DATA: u32 = 0x00;
+    pub const SAVED_GAME: u32 = 0x01;
+    pub const TRANSCRIPT: u32 = 0x02;
+    pub const INPUT_RECORD: u32 = 0x03;
+    pub const TYPE_MASK: u32 = 0x0F;
+    pub const TEXT_MODE: u32 = 0x100;
+}
+
+#[derive(Debug)]
+pub struct Fileref {
+    pub path: PathBuf,
+    pub usage: u32,
+    pub rock: u32,
+}
+
+impl Fileref {
+    // Story-provided names are reduced to a safe file name with an extension matching the usage.
+    pub fn from_name(name: &str, usage: u32, rock: u32) -> Self {
+        let mut stem: String = name.chars()
+            .take_while(|&ch| ch != '.')
+            .filter(|ch| !matches!(ch, '/' | '\\' | '<' | '>' | ':' | '|' | '?' | '*' | '"' | '\0'))
+            .collect();
+        if stem.is_empty() {
+            stem.push_str("null");
+        }
+
+        Self { path: PathBuf::from(format!("{stem}.{}", Self::extension(usage))), usage, rock }
+    }
+
+    // Files the story asks the player to pick. There's nobody to ask, so they go next to
+    // the story file and are named after it, like `story.glksave`.
+    pub fn next_to(story: &Path, usage: u32, rock: u32) -> Self {
+        Self { path: story.with_extension(Self::extension(usage)), usage, rock }
+    }
+
+    fn extension(usage: u32) -> &'static str {
+        match usage & fileusage::TYPE_MASK {
+            fileusage::SAVED_GAME => "glksave",
+            fileusage::TRANSCRIPT | fileusage::INPUT_RECORD => "txt",
+            _ => "glkdata",
+        }
+    }
+
+    pub fn temporary(id: u32, usage: u32, rock: u32) -> Self {
+        let path = std::env::temp_dir().join(format!("glulx-terp-{}-{id}.tmp", std::process::id()));
+        Self { path, usage, rock }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.usage & fileusage::TEXT_MODE != 0
+    }
+}
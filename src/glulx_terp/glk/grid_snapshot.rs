@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+
+use super::{backend::GlkBackend, window::wintype};
+
+pub mod winmethod {
+    pub const LEFT: u32 = 0x00;
+    pub const RIGHT: u32 = 0x01;
+    pub const ABOVE: u32 = 0x02;
+    pub const BELOW: u32 = 0x03;
+    pub const DIR_MASK: u32 = 0x0F;
+    pub const FIXED: u32 = 0x10;
+    pub const PROPORTIONAL: u32 = 0x20;
+    pub const DIVISION_MASK: u32 = 0xF0;
+}
+
+// The combining diacritical mark blocks. Every other character, wide ones included,
+// takes exactly one cell.
+fn is_combining(ch: char) -> bool {
+    matches!(ch, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+// How a window was opened, kept to redo the layout when a split changes.
+#[derive(Debug, Clone, Copy)]
+struct Split {
+    window: u32,
+    split: u32,
+    method: u32,
+    size: u32,
+}
+
+#[derive(Debug)]
+struct GridWindow {
+    kind: u32,
+    rect: Rect,
+    cursor_x: u32,
+    cursor_y: u32,
+}
+
+// Renders every window onto one fixed-size character screen, like a terminal would,
+// so tests can assert on the exact layout (status line included) after a turn.
+// Text buffers wrap at their width and scroll up once full, grids are drawn in place.
+#[derive(Debug)]
+pub struct GridSnapshotBackend {
+    width: u32,
+    height: u32,
+    // Each cell holds a character and the combining marks on it.
+    screen: Vec<Vec<String>>,
+    windows: BTreeMap<u32, GridWindow>,
+    splits: Vec<Split>,
+}
+
+impl GridSnapshotBackend {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            screen: vec![vec![" ".to_string(); width as usize]; height as usize],
+            windows: BTreeMap::new(),
+            splits: Vec::new(),
+        }
+    }
+
+    // The screen row by row, without trailing spaces.
+    pub fn screen_text(&self) -> String {
+        self.screen.iter()
+            .map(|row| row.concat().trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // What one cell of the screen shows, a space when it's empty.
+    pub fn cell(&self, x: u32, y: u32) -> &str {
+        &self.screen[y as usize][x as usize]
+    }
+
+    fn fill(&mut self, rect: Rect, ch: char) {
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.screen[y as usize][x as usize] = ch.to_string();
+            }
+        }
+    }
+
+    // Takes the space of a new window out of `parent`, which is left with the rest.
+    // Without a parent the window gets the whole screen.
+    fn split(&mut self, parent: u32, method: u32, size: u32) -> Rect {
+        let Some(parent) = self.windows.get_mut(&parent) else {
+            return Rect { x: 0, y: 0, width: self.width, height: self.height }
+        };
+        let vertical = matches!(method & winmethod::DIR_MASK, winmethod::ABOVE | winmethod::BELOW);
+        let available = if vertical { parent.rect.height } else { parent.rect.width };
+        let taken = match method & winmethod::DIVISION_MASK {
+            winmethod::PROPORTIONAL => available * size.min(100) / 100,
+            _ => size.min(available),
+        };
+
+        let old = parent.rect;
+        let (new, rest) = match method & winmethod::DIR_MASK {
+            winmethod::ABOVE => (Rect { height: taken, ..old }, Rect { y: old.y + taken, height: old.height - taken, ..old }),
+            winmethod::BELOW => (Rect { y: old.y + old.height - taken, height: taken, ..old }, Rect { height: old.height - taken, ..old }),
+            winmethod::LEFT => (Rect { width: taken, ..old }, Rect { x: old.x + taken, width: old.width - taken, ..old }),
+            _ => (Rect { x: old.x + old.width - taken, width: taken, ..old }, Rect { width: old.width - taken, ..old }),
+        };
+        parent.rect = rest;
+        parent.cursor_x = parent.cursor_x.min(rest.width.saturating_sub(1));
+        parent.cursor_y = parent.cursor_y.min(rest.height.saturating_sub(1));
+        new
+    }
+
+    // Lays every window out again, in the order they were opened.
+    fn relayout(&mut self) {
+        for split in self.splits.clone() {
+            let rect = self.split(split.split, split.method, split.size);
+            if let Some(target) = self.windows.get_mut(&split.window) {
+                target.rect = rect;
+                target.cursor_x = target.cursor_x.min(rect.width.saturating_sub(1));
+                target.cursor_y = target.cursor_y.min(rect.height.saturating_sub(1));
+            }
+        }
+    }
+
+    // Moves the lines of a text buffer up by one, blanking the last one.
+    fn scroll(&mut self, rect: Rect) {
+        for y in rect.y..rect.y + rect.height - 1 {
+            for x in rect.x..rect.x + rect.width {
+                self.screen[y as usize][x as usize] = self.screen[y as usize + 1][x as usize].clone();
+            }
+        }
+        self.fill(Rect { y: rect.y + rect.height - 1, height: 1, ..rect }, ' ');
+    }
+}
+
+impl GlkBackend for GridSnapshotBackend {
+    fn window_opened(&mut self, window: u32, kind: u32, split: u32, method: u32, size: u32) {
+        let rect = self.split(split, method, size);
+        self.windows.insert(window, GridWindow { kind, rect, cursor_x: 0, cursor_y: 0 });
+        self.splits.push(Split { window, split, method, size });
+    }
+
+    // What's on screen stays until it's redrawn.
+    fn window_arranged(&mut self, window: u32, method: u32, size: u32) {
+        let Some(changed) = self.splits.iter_mut().find(|split| split.window == window) else { return };
+        changed.method = method;
+        changed.size = size;
+        self.relayout();
+    }
+
+    // A window split off the closed one takes the place of their pair, later ones split
+    // that window instead. What the closed window showed stays on screen.
+    fn window_closed(&mut self, window: u32) {
+        self.windows.remove(&window);
+        let Some(index) = self.splits.iter().position(|split| split.window == window) else { return };
+        let closed = self.splits.remove(index);
+        if let Some(heir) = self.splits.iter().position(|split| split.split == window) {
+            let heir = Split { split: closed.split, method: closed.method, size: closed.size, ..self.splits.remove(heir) };
+            self.splits.insert(index, heir);
+            for split in self.splits.iter_mut().filter(|split| split.split == window) {
+                split.split = heir.window;
+            }
+        }
+        self.relayout();
+    }
+
+    fn put_char(&mut self, window: u32, ch: char) {
+        let Some(target) = self.windows.get_mut(&window) else { return };
+        let rect = target.rect;
+        if !matches!(target.kind, wintype::TEXT_BUFFER | wintype::TEXT_GRID) || rect.width == 0 || rect.height == 0 { return }
+        let buffer = target.kind == wintype::TEXT_BUFFER;
+
+        if ch == '\n' {
+            target.cursor_x = 0;
+            target.cursor_y += 1;
+            return
+        }
+        // Combining marks go on the character before them instead of taking a cell.
+        // A cursor moved past the right edge of a grid has no character before it.
+        if is_combining(ch) && target.cursor_x > 0 && target.cursor_x <= rect.width && target.cursor_y < rect.height {
+            let (x, y) = (target.cursor_x - 1, target.cursor_y);
+            self.screen[(rect.y + y) as usize][(rect.x + x) as usize].push(ch);
+            return
+        }
+
+        // Buffers wrap onto the next line, grids drop what goes past the right edge.
+        if target.cursor_x >= rect.width {
+            if !buffer { return }
+            target.cursor_x = 0;
+            target.cursor_y += 1;
+        }
+        let scroll = buffer && target.cursor_y >= rect.height;
+        if scroll {
+            target.cursor_y = rect.height - 1;
+        }
+
+        let (x, y) = (target.cursor_x, target.cursor_y);
+        target.cursor_x += 1;
+        if scroll {
+            self.scroll(rect);
+        }
+        if y < rect.height {
+            self.screen[(rect.y + y) as usize][(rect.x + x) as usize] = ch.to_string();
+        }
+    }
+
+    fn window_size(&self, window: u32) -> (u32, u32) {
+        self.windows.get(&window).map_or((0, 0), |target| (target.rect.width, target.rect.height))
+    }
+
+    fn clear_window(&mut self, window: u32) {
+        let Some(target) = self.windows.get_mut(&window) else { return };
+        target.cursor_x = 0;
+        target.cursor_y = 0;
+        let rect = target.rect;
+        self.fill(rect, ' ');
+    }
+
+    fn move_cursor(&mut self, window: u32, x: u32, y: u32) {
+        let Some(target) = self.windows.get_mut(&window) else { return };
+        if target.kind != wintype::TEXT_GRID { return }
+        target.cursor_x = x;
+        target.cursor_y = y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GridSnapshotBackend;
+    use crate::glulx_terp::{test_support::{function, glk, op, print, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn status_line_is_drawn_above_the_story() {
+        // A one line status grid above the main window, then a prompt in the main window.
+        let code = function(&[(4, 2)], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Local(0)),
+            glk(0x23, vec![Arg::Local(0), Arg::Const(0x12), Arg::Const(1), Arg::Const(4), Arg::Zero], Arg::Local(4)),
+            op(0x149, &[Arg::Const(2), Arg::Zero]),
+            glk(0x2F, vec![Arg::Local(4)], Arg::Zero),
+            glk(0x2B, vec![Arg::Local(4), Arg::Const(2), Arg::Zero], Arg::Zero),
+            print("Kitchen"),
+            glk(0x2B, vec![Arg::Local(4), Arg::Const(14), Arg::Zero], Arg::Zero),
+            print("T:42"),
+            glk(0x2F, vec![Arg::Local(0)], Arg::Zero),
+            print("Hi.\n>"),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.set_glk_backend(Box::new(GridSnapshotBackend::new(20, 4)));
+        terp.run().unwrap();
+
+        let screen = terp.glk_backend::<GridSnapshotBackend>().unwrap().screen_text();
+        assert_eq!(screen, "  Kitchen     T:42\nHi.\n>\n");
+    }
+
+    #[test]
+    fn combining_marks_stay_inside_the_grid() {
+        // A 10x2 grid: "ex" in its last two cells, then marks at the edge and past it.
+        let code = function(&[(4, 1)], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(4), Arg::Zero], Arg::Local(0)),
+            op(0x149, &[Arg::Const(2), Arg::Zero]),
+            glk(0x2F, vec![Arg::Local(0)], Arg::Zero),
+            glk(0x2B, vec![Arg::Local(0), Arg::Const(8), Arg::Zero], Arg::Zero),
+            op(0x73, &[Arg::Const('e' as i32)]),
+            op(0x73, &[Arg::Const('x' as i32)]),
+            op(0x73, &[Arg::Const(0x301)]),
+            glk(0x2B, vec![Arg::Local(0), Arg::Const(15), Arg::Const(1)], Arg::Zero),
+            op(0x73, &[Arg::Const(0x301)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.set_glk_backend(Box::new(GridSnapshotBackend::new(10, 2)));
+        terp.run().unwrap();
+
+        let backend = terp.glk_backend::<GridSnapshotBackend>().unwrap();
+        assert_eq!(backend.cell(8, 0), "e");
+        assert_eq!(backend.cell(9, 0), "x\u{301}");
+        assert!((0..10).all(|x| backend.cell(x, 1) == " "));
+    }
+}
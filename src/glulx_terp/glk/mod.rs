@@ -0,0 +1,1283 @@
+pub mod backend;
+mod case;
+pub mod clock;
+pub mod console;
+pub mod event;
+pub mod fileref;
+pub mod grid_snapshot;
+pub mod stream;
+pub mod window;
+use std::{collections::{BTreeMap, VecDeque}, fs::OpenOptions, path::PathBuf, time::Duration};
+
+use backend::{GlkBackend, ImageDraw};
+use clock::{Clock, SystemClock};
+use event::{evtype, keycode, GlkEvent};
+use fileref::Fileref;
+use stream::{filemode, latin1, Stream, StreamKind};
+use window::{wintype, Arrangement, LineRequest, Window};
+
+use super::{blorb::{usage, Blorb}, memory::{Memory, MemoryError}};
+
+// Functions hand results back to the story in two ways: their return value, which
+// the GLK opcode stores, and words written through pointer arguments. A null pointer
+// means the story doesn't want those.
+fn write_out(memory: &mut Memory, addr: u32, values: &[u32]) -> Result<(), GlkError> {
+    if addr == 0 { return Ok(()) }
+    for (index, &value) in values.iter().enumerate() {
+        memory.set_u32(addr + 4 * index as u32, value).map_err(GlkError::MemoryError)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum GlkError {
+    UnknownSelector(u32),
+    MissingArgument(u32),
+    InvalidObject(u32),
+    NoInputRequest,
+    // The key can't end the pending line, it isn't one of its terminators.
+    NotATerminator(u32),
+    MemoryError(MemoryError),
+    IOError(std::io::Error),
+}
+
+pub mod gestalt {
+    pub const VERSION: u32 = 0;
+    pub const CHAR_INPUT: u32 = 1;
+    pub const LINE_INPUT: u32 = 2;
+    pub const CHAR_OUTPUT: u32 = 3;
+    pub const MOUSE_INPUT: u32 = 4;
+    pub const TIMER: u32 = 5;
+    pub const GRAPHICS: u32 = 6;
+    pub const DRAW_IMAGE: u32 = 7;
+    pub const HYPERLINKS: u32 = 11;
+    pub const HYPERLINK_INPUT: u32 = 12;
+    pub const GRAPHICS_TRANSPARENCY: u32 = 14;
+    pub const UNICODE: u32 = 15;
+    pub const LINE_INPUT_ECHO: u32 = 17;
+    pub const LINE_TERMINATORS: u32 = 18;
+    pub const LINE_TERMINATOR_KEY: u32 = 19;
+    pub const GRAPHICS_CHAR_INPUT: u32 = 23;
+}
+
+pub struct Glk {
+    backend: Box<dyn GlkBackend>,
+    // Windows, streams and filerefs share one id space, 0 being "none".
+    next_id: u32,
+    windows: BTreeMap<u32, Window>,
+    streams: BTreeMap<u32, Stream>,
+    filerefs: BTreeMap<u32, Fileref>,
+    current_stream: u32,
+    events: VecDeque<GlkEvent>,
+    // Address of the event struct of a glk_select that is still waiting for an event.
+    select_address: Option<u32>,
+    // Last event glk_select handed to the story, until someone takes it.
+    delivered: Option<GlkEvent>,
+    // Hints for windows opened from now on, keyed by (wintype, style, hint).
+    style_hints: BTreeMap<(u32, u32, u32), u32>,
+    // Pictures and sounds available to the story, from its Blorb file.
+    resources: Option<Blorb>,
+    clock: Box<dyn Clock>,
+    timer: Option<Timer>,
+    // The story file, which files asked for by prompt are named after.
+    story_path: PathBuf,
+}
+
+// Requested with glk_request_timer_events. Missed ticks don't pile up, at most
+// one timer event is pending at a time.
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    interval: Duration,
+    next: Duration,
+}
+
+impl Glk {
+    pub fn new(backend: Box<dyn GlkBackend>) -> Self {
+        Self {
+            backend,
+            next_id: 1,
+            windows: BTreeMap::new(),
+            streams: BTreeMap::new(),
+            filerefs: BTreeMap::new(),
+            current_stream: 0,
+            events: VecDeque::new(),
+            select_address: None,
+            delivered: None,
+            style_hints: BTreeMap::new(),
+            resources: None,
+            clock: Box::new(SystemClock::default()),
+            timer: None,
+            story_path: PathBuf::from("story"),
+        }
+    }
+
+    pub fn set_backend(&mut self, backend: Box<dyn GlkBackend>) {
+        self.backend = backend;
+    }
+
+    // Installs `backend` and hands back the one it replaces.
+    pub fn replace_backend(&mut self, backend: Box<dyn GlkBackend>) -> Box<dyn GlkBackend> {
+        std::mem::replace(&mut self.backend, backend)
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn set_story_path(&mut self, path: PathBuf) {
+        self.story_path = path;
+    }
+
+    pub fn set_resources(&mut self, resources: Blorb) {
+        self.resources = Some(resources);
+    }
+
+    pub fn backend(&self) -> &dyn GlkBackend {
+        self.backend.as_ref()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.select_address.is_some()
+    }
+
+    pub fn wants_line(&self) -> bool {
+        self.windows.values().any(|window| window.line_request.is_some())
+    }
+
+    // Asks the backend itself for the line a window is waiting for. Returns whether
+    // one was delivered.
+    pub fn read_backend_line(&mut self, memory: &mut Memory) -> Result<bool, GlkError> {
+        if !self.wants_line() { return Ok(false) }
+        let Some(line) = self.backend.read_line() else { return Ok(false) };
+        self.provide_line(memory, &line)?;
+        Ok(true)
+    }
+
+    pub fn has_delivered_event(&self) -> bool {
+        self.delivered.is_some()
+    }
+
+    pub fn take_delivered_event(&mut self) -> Option<GlkEvent> {
+        self.delivered.take()
+    }
+
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // Anything not listed isn't supported.
+    pub fn gestalt(&self, selector: u32, arg: u32) -> u32 {
+        let capabilities = self.backend.capabilities();
+        // Backends take any char, so every code point but the control characters can
+        // be typed and printed as itself.
+        let printable = char::from_u32(arg).is_some_and(|ch| !ch.is_control());
+        match selector {
+            gestalt::VERSION => 0x0007_0600,
+            // Single key presses can't be requested yet.
+            gestalt::CHAR_INPUT => 0,
+            gestalt::LINE_INPUT => printable as u32,
+            // gestalt_CharOutput_ExactPrint or gestalt_CharOutput_CannotPrint.
+            gestalt::CHAR_OUTPUT => 2 * printable as u32,
+            // Clicks can only be requested in grid and graphics windows.
+            gestalt::MOUSE_INPUT => (capabilities.mouse_input && matches!(arg, wintype::TEXT_GRID | wintype::GRAPHICS)) as u32,
+            gestalt::TIMER => 1,
+            gestalt::LINE_INPUT_ECHO => 1,
+            gestalt::LINE_TERMINATORS => 1,
+            gestalt::LINE_TERMINATOR_KEY => keycode::is_terminator(arg) as u32,
+            // The _uni functions, case conversion included, take any code point.
+            gestalt::UNICODE => 1,
+            gestalt::HYPERLINKS => 1,
+            // Links can only be clicked in text windows.
+            gestalt::HYPERLINK_INPUT => matches!(arg, wintype::TEXT_BUFFER | wintype::TEXT_GRID) as u32,
+            gestalt::GRAPHICS => capabilities.graphics as u32,
+            // Images can only be drawn in graphics and text buffer windows.
+            gestalt::DRAW_IMAGE => (capabilities.graphics && matches!(arg, wintype::GRAPHICS | wintype::TEXT_BUFFER)) as u32,
+            gestalt::GRAPHICS_TRANSPARENCY => (capabilities.graphics && capabilities.graphics_transparency) as u32,
+            gestalt::GRAPHICS_CHAR_INPUT => (capabilities.graphics && capabilities.graphics_char_input) as u32,
+            _ => 0
+        }
+    }
+
+    // Windows
+    fn open_window(&mut self, kind: u32, rock: u32) -> u32 {
+        let id = self.allocate_id();
+        let stream = self.allocate_id();
+        self.streams.insert(stream, Stream::new(StreamKind::Window(id), 0, true));
+        let style_hints = self.style_hints.range((kind, 0, 0)..=(kind, u32::MAX, u32::MAX))
+            .map(|(&(_, style, hint), &value)| ((style, hint), value))
+            .collect();
+        self.windows.insert(id, Window {
+            kind, rock, parent: 0, arrangement: None, stream, line_request: None, echo_line: true, line_terminators: Vec::new(), hyperlink_request: false, style_hints,
+        });
+        id
+    }
+
+    // Opening a window next to `split` puts both in a new pair window, which takes
+    // the place of `split` in the tree.
+    fn split_window(&mut self, split: u32, method: u32, size: u32, kind: u32, rock: u32) -> Result<u32, GlkError> {
+        if split == 0 {
+            return Ok(self.open_window(kind, rock))
+        }
+        let parent = self.window(split)?.parent;
+        let id = self.open_window(kind, rock);
+        let pair = self.open_window(wintype::PAIR, 0);
+
+        let arrangement = Arrangement { method, size, key: id, first: split, second: id };
+        let pair_window = self.window(pair)?;
+        pair_window.parent = parent;
+        pair_window.arrangement = Some(arrangement);
+        if let Some(arrangement) = self.windows.get_mut(&parent).and_then(|window| window.arrangement.as_mut()) {
+            for child in [&mut arrangement.first, &mut arrangement.second] {
+                if *child == split { *child = pair }
+            }
+        }
+        self.window(split)?.parent = pair;
+        self.window(id)?.parent = pair;
+        Ok(id)
+    }
+
+    // Closing a window takes the windows inside it along, and its sibling takes the
+    // place of their pair in the tree. Only the closed window's stream counts are returned.
+    fn close_window(&mut self, memory: &mut Memory, id: u32, result: u32) -> Result<(), GlkError> {
+        let stream = self.window(id)?.stream;
+        let counts = self.streams.get(&stream).map_or([0, 0], |stream| [stream.read_count, stream.write_count]);
+        write_out(memory, result, &counts)?;
+
+        let parent = self.window(id)?.parent;
+        let mut closed = vec![id];
+        if parent != 0 {
+            let sibling = self.sibling(id)?;
+            let grandparent = self.window(parent)?.parent;
+            self.window(sibling)?.parent = grandparent;
+            if let Some(arrangement) = self.windows.get_mut(&grandparent).and_then(|window| window.arrangement.as_mut()) {
+                for child in [&mut arrangement.first, &mut arrangement.second] {
+                    if *child == parent { *child = sibling }
+                }
+            }
+            closed.push(parent);
+        }
+
+        while let Some(id) = closed.pop() {
+            let Some(window) = self.windows.remove(&id) else { continue };
+            if let Some(arrangement) = window.arrangement {
+                closed.extend([arrangement.first, arrangement.second].into_iter().filter(|&child| self.windows.get(&child).is_some_and(|child| child.parent == id)));
+            }
+            self.streams.remove(&window.stream);
+            if self.current_stream == window.stream {
+                self.current_stream = 0;
+            }
+            // A pair whose key went away keeps its split, without a key.
+            for arrangement in self.windows.values_mut().filter_map(|window| window.arrangement.as_mut()) {
+                if arrangement.key == id { arrangement.key = 0 }
+            }
+            self.backend.window_closed(id);
+        }
+        Ok(())
+    }
+
+    // The other window in the same pair, 0 for the root.
+    fn sibling(&mut self, id: u32) -> Result<u32, GlkError> {
+        let parent = self.window(id)?.parent;
+        if parent == 0 { return Ok(0) }
+        let pair = self.arrangement(parent)?;
+        Ok(if pair.first == id { pair.second } else { pair.first })
+    }
+
+    // The root is the only window without a parent.
+    fn root_window(&self) -> u32 {
+        self.windows.iter().find(|(_, window)| window.parent == 0).map_or(0, |(&id, _)| id)
+    }
+
+    fn arrangement(&mut self, pair: u32) -> Result<&mut Arrangement, GlkError> {
+        self.window(pair)?.arrangement.as_mut().ok_or(GlkError::InvalidObject(pair))
+    }
+
+    // The key window must be inside the pair, 0 keeps the current one.
+    fn set_arrangement(&mut self, memory: &mut Memory, pair: u32, method: u32, size: u32, key: u32) -> Result<(), GlkError> {
+        let mut ancestor = key;
+        while ancestor != 0 && ancestor != pair {
+            ancestor = self.window(ancestor)?.parent;
+        }
+        if key != 0 && ancestor != pair { return Err(GlkError::InvalidObject(key)) }
+
+        let arrangement = self.arrangement(pair)?;
+        arrangement.method = method;
+        arrangement.size = size;
+        if key != 0 {
+            arrangement.key = key;
+        }
+        let second = arrangement.second;
+        self.backend.window_arranged(second, method, size);
+        self.push_event(memory, GlkEvent { kind: evtype::ARRANGE, window: 0, val1: 0, val2: 0 })
+    }
+
+    // wintype_AllTypes stands for both kinds of text window, the only ones hints apply to.
+    fn hinted_types(kind: u32) -> Vec<u32> {
+        match kind {
+            wintype::ALL_TYPES => vec![wintype::TEXT_BUFFER, wintype::TEXT_GRID],
+            kind => vec![kind],
+        }
+    }
+
+    fn style_measure(&mut self, memory: &mut Memory, window: u32, style: u32, hint: u32, result: u32) -> Result<u32, GlkError> {
+        let requested = self.window(window)?.style_hints.get(&(style, hint)).copied();
+        let Some(value) = self.backend.style_measure(window, style, hint, requested) else { return Ok(0) };
+        write_out(memory, result, &[value])?;
+        Ok(1)
+    }
+
+    fn window(&mut self, id: u32) -> Result<&mut Window, GlkError> {
+        self.windows.get_mut(&id).ok_or(GlkError::InvalidObject(id))
+    }
+
+    // glk_window_iterate and the like: the object after `after` (0 for the first one)
+    // and its rock, or 0 once they've all been seen.
+    fn iterate<T>(memory: &mut Memory, objects: &BTreeMap<u32, T>, after: u32, rock_addr: u32, rock: impl Fn(&T) -> u32) -> Result<u32, GlkError> {
+        let Some((&id, object)) = objects.range(after.saturating_add(1)..).next() else {
+            write_out(memory, rock_addr, &[0])?;
+            return Ok(0)
+        };
+        write_out(memory, rock_addr, &[rock(object)])?;
+        Ok(id)
+    }
+
+    // Streams
+    fn stream(&mut self, id: u32) -> Result<&mut Stream, GlkError> {
+        self.streams.get_mut(&id).ok_or(GlkError::InvalidObject(id))
+    }
+
+    fn open_memory_stream(&mut self, buffer: u32, length: u32, rock: u32, unicode: bool) -> u32 {
+        let id = self.allocate_id();
+        self.streams.insert(id, Stream::new(StreamKind::Memory { buffer, length, position: 0 }, rock, unicode));
+        id
+    }
+
+    fn open_file_stream(&mut self, fileref: u32, mode: u32, rock: u32, unicode: bool) -> Result<u32, GlkError> {
+        let fileref = self.filerefs.get(&fileref).ok_or(GlkError::InvalidObject(fileref))?;
+        let mut options = OpenOptions::new();
+        match mode {
+            filemode::READ => options.read(true),
+            filemode::WRITE => options.write(true).create(true).truncate(true),
+            filemode::WRITE_APPEND => options.append(true).create(true),
+            _ => options.read(true).write(true).create(true),
+        };
+
+        // Glk returns a null stream when the file can't be opened, the story is expected to cope.
+        let Ok(file) = options.open(&fileref.path) else { return Ok(0) };
+        let text = fileref.is_text();
+        let id = self.allocate_id();
+        self.streams.insert(id, Stream::new(StreamKind::File { file, text }, rock, unicode));
+        Ok(id)
+    }
+
+    fn close_stream(&mut self, memory: &mut Memory, id: u32, result: u32) -> Result<(), GlkError> {
+        match self.streams.get(&id) {
+            None => return Err(GlkError::InvalidObject(id)),
+            // Window streams only go away with their window.
+            Some(Stream { kind: StreamKind::Window(_), .. }) => return Err(GlkError::InvalidObject(id)),
+            Some(_) => {}
+        }
+
+        let stream = self.streams.remove(&id).unwrap();
+        if self.current_stream == id {
+            self.current_stream = 0;
+        }
+        write_out(memory, result, &[stream.read_count, stream.write_count])
+    }
+
+    // Styles only mean something to windows, other streams ignore them.
+    fn set_style(&mut self, stream: u32, style: u32) -> Result<(), GlkError> {
+        if stream == 0 { return Ok(()) }
+        let stream = self.stream(stream)?;
+        if let StreamKind::Window(window) = stream.kind {
+            self.backend.set_style(window, style);
+        }
+        Ok(())
+    }
+
+    // Like styles, links only mean something in windows.
+    fn set_hyperlink(&mut self, stream: u32, link: u32) -> Result<(), GlkError> {
+        if stream == 0 { return Ok(()) }
+        let stream = self.stream(stream)?;
+        if let StreamKind::Window(window) = stream.kind {
+            self.backend.set_hyperlink(window, link);
+        }
+        Ok(())
+    }
+
+    fn put_char(&mut self, memory: &mut Memory, stream: u32, ch: char) -> Result<(), GlkError> {
+        // Output to no stream at all is silently dropped.
+        if stream == 0 { return Ok(()) }
+        let stream = self.streams.get_mut(&stream).ok_or(GlkError::InvalidObject(stream))?;
+        stream.write_char(memory, ch)?;
+        if let StreamKind::Window(window) = stream.kind {
+            self.backend.put_char(window, ch);
+        }
+        Ok(())
+    }
+
+    // Where the story's own output goes when the Glk I/O system is selected.
+    pub fn put_current_char(&mut self, memory: &mut Memory, ch: char) -> Result<(), GlkError> {
+        self.put_char(memory, self.current_stream, ch)
+    }
+
+    fn put_latin1(&mut self, memory: &mut Memory, stream: u32, addr: u32, len: Option<u32>) -> Result<(), GlkError> {
+        let mut pos = addr;
+        loop {
+            if len.is_some_and(|len| pos - addr >= len) { break }
+            let byte = memory.get_u8(pos).map_err(GlkError::MemoryError)?;
+            if len.is_none() && byte == 0 { break }
+            self.put_char(memory, stream, byte as char)?;
+            pos += 1;
+        }
+        Ok(())
+    }
+
+    fn put_unicode(&mut self, memory: &mut Memory, stream: u32, addr: u32, len: Option<u32>) -> Result<(), GlkError> {
+        let mut pos = addr;
+        loop {
+            if len.is_some_and(|len| (pos - addr) / 4 >= len) { break }
+            let value = memory.get_u32(pos).map_err(GlkError::MemoryError)?;
+            if len.is_none() && value == 0 { break }
+            self.put_char(memory, stream, char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER))?;
+            pos += 4;
+        }
+        Ok(())
+    }
+
+    // Raw bytes for binary data like save files, each one written as a Latin-1 character.
+    pub fn write_bytes(&mut self, memory: &mut Memory, stream: u32, bytes: &[u8]) -> Result<(), GlkError> {
+        self.stream(stream)?;
+        for &byte in bytes {
+            self.put_char(memory, stream, byte as char)?;
+        }
+        Ok(())
+    }
+
+    // Reads everything left in the stream.
+    pub fn read_bytes(&mut self, memory: &Memory, stream: u32) -> Result<Vec<u8>, GlkError> {
+        let stream = self.stream(stream)?;
+        let mut bytes = Vec::new();
+        while let Some(value) = stream.read_char(memory)? {
+            bytes.push(value as u8);
+        }
+        Ok(bytes)
+    }
+
+    // Returns the next code point, or -1 at the end of the stream.
+    fn get_char(&mut self, memory: &Memory, stream: u32, unicode: bool) -> Result<u32, GlkError> {
+        let value = self.stream(stream)?.read_char(memory)?;
+        Ok(match value {
+            None => u32::MAX,
+            Some(value) if !unicode && value >= 0x100 => b'?' as u32,
+            Some(value) => value,
+        })
+    }
+
+    // Converts the first `count` characters of a unicode buffer in place, writing at most
+    // `capacity` of them back, and returns the length of the full result.
+    fn change_case(memory: &mut Memory, buffer: u32, capacity: u32, count: u32, convert: impl Fn(&[char]) -> Vec<char>) -> Result<u32, GlkError> {
+        let mut text = Vec::with_capacity(count as usize);
+        for index in 0..count.min(capacity) {
+            let value = memory.get_u32(buffer + 4 * index).map_err(GlkError::MemoryError)?;
+            text.push(char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+
+        let result = convert(&text);
+        for (index, ch) in result.iter().take(capacity as usize).enumerate() {
+            memory.set_u32(buffer + 4 * index as u32, *ch as u32).map_err(GlkError::MemoryError)?;
+        }
+        Ok(result.len() as u32)
+    }
+
+    fn read_c_string(memory: &Memory, addr: u32) -> Result<String, GlkError> {
+        let mut text = String::new();
+        let mut pos = addr;
+        loop {
+            let byte = memory.get_u8(pos).map_err(GlkError::MemoryError)?;
+            if byte == 0 { break }
+            text.push(byte as char);
+            pos += 1;
+        }
+        Ok(text)
+    }
+
+    // Images
+    fn image_size(&self, image: u32) -> Option<(u32, u32)> {
+        self.resources.as_ref()?.image_size(image)
+    }
+
+    fn image_get_info(&self, memory: &mut Memory, image: u32, width: u32, height: u32) -> Result<u32, GlkError> {
+        let Some(size) = self.image_size(image) else { return Ok(0) };
+        write_out(memory, width, &[size.0])?;
+        write_out(memory, height, &[size.1])?;
+        Ok(1)
+    }
+
+    // Draws at the picture's natural size unless `size` is given.
+    fn image_draw(&mut self, window: u32, image: u32, val1: u32, val2: u32, size: Option<(u32, u32)>) -> Result<u32, GlkError> {
+        self.window(window)?;
+        let exists = self.resources.as_ref().is_some_and(|resources| resources.resource(usage::PICT, image).is_some());
+        let Some((width, height)) = size.or_else(|| self.image_size(image)).filter(|_| exists) else { return Ok(0) };
+        Ok(self.backend.draw_image(ImageDraw { window, image, val1, val2, width, height }) as u32)
+    }
+
+    // Events
+    fn write_event(memory: &mut Memory, addr: u32, event: GlkEvent) -> Result<(), GlkError> {
+        write_out(memory, addr, &[event.kind, event.window, event.val1, event.val2])
+    }
+
+    fn deliver_event(&mut self, memory: &mut Memory, addr: u32, event: GlkEvent) -> Result<(), GlkError> {
+        self.delivered = Some(event);
+        Self::write_event(memory, addr, event)
+    }
+
+    fn push_event(&mut self, memory: &mut Memory, event: GlkEvent) -> Result<(), GlkError> {
+        self.events.push_back(event);
+        if let Some(addr) = self.select_address.take() {
+            let event = self.events.pop_front().unwrap();
+            self.deliver_event(memory, addr, event)?;
+        }
+        Ok(())
+    }
+
+    // Takes the timer event that is due, if any, and schedules the next one.
+    fn due_timer_event(&mut self) -> Option<GlkEvent> {
+        let now = self.clock.now();
+        let timer = self.timer.as_mut().filter(|timer| now >= timer.next)?;
+        timer.next = now + timer.interval;
+        Some(GlkEvent { kind: evtype::TIMER, window: 0, val1: 0, val2: 0 })
+    }
+
+    // Wakes up a waiting glk_select with a timer event if one is due. Events already
+    // queued, like input, take precedence since select only waits when there are none.
+    pub fn poll_timer(&mut self, memory: &mut Memory) -> Result<bool, GlkError> {
+        if !self.is_waiting() { return Ok(false) }
+        let Some(event) = self.due_timer_event() else { return Ok(false) };
+        self.push_event(memory, event)?;
+        Ok(true)
+    }
+
+    // Completes the pending line request with `line`, as if the player typed it.
+    pub fn provide_line(&mut self, memory: &mut Memory, line: &str) -> Result<(), GlkError> {
+        self.provide_terminated_line(memory, line, 0)
+    }
+
+    // Same, with the line ended by `terminator` instead of Enter (0). The key must be
+    // one the request accepts, and is reported in val2 of the event.
+    pub fn provide_terminated_line(&mut self, memory: &mut Memory, line: &str, terminator: u32) -> Result<(), GlkError> {
+        let (&id, window) = self.windows.iter_mut()
+            .find(|(_, window)| window.line_request.is_some())
+            .ok_or(GlkError::NoInputRequest)?;
+        let request = window.line_request.as_ref().unwrap();
+        if terminator != 0 && !request.terminators.contains(&terminator) {
+            return Err(GlkError::NotATerminator(terminator))
+        }
+        let request = window.line_request.take().unwrap();
+
+        let mut length = 0u32;
+        for ch in line.chars().take(request.capacity as usize) {
+            if request.unicode {
+                memory.set_u32(request.buffer + 4 * length, ch as u32).map_err(GlkError::MemoryError)?;
+            } else {
+                memory.set_u8(request.buffer + length, latin1(ch)).map_err(GlkError::MemoryError)?;
+            }
+            length += 1;
+        }
+
+        // Without echo the line still goes to the buffer, it just isn't shown. The
+        // terminator key has no character of its own, the echo ends with the line.
+        if request.echo && !self.backend.echoes_input() {
+            for ch in line.chars().chain(['\n']) {
+                self.backend.put_char(id, ch);
+            }
+        }
+
+        self.push_event(memory, GlkEvent { kind: evtype::LINE_INPUT, window: id, val1: length, val2: terminator })
+    }
+
+    // Delivers a click on the hyperlink `link` in `window`, if the window asked for one.
+    pub fn provide_hyperlink(&mut self, memory: &mut Memory, window: u32, link: u32) -> Result<(), GlkError> {
+        let requested = std::mem::take(&mut self.window(window)?.hyperlink_request);
+        if !requested { return Err(GlkError::NoInputRequest) }
+        self.push_event(memory, GlkEvent { kind: evtype::HYPERLINK, window, val1: link, val2: 0 })
+    }
+
+    pub fn dispatch(&mut self, memory: &mut Memory, selector: u32, args: &[u32]) -> Result<u32, GlkError> {
+        let arg = |index: usize| args.get(index).copied().ok_or(GlkError::MissingArgument(selector));
+        match selector {
+            0x0004 => Ok(self.gestalt(arg(0)?, arg(1)?)),
+            // Only CharOutput has extra results, the number of glyphs printed for the character.
+            0x0005 => {
+                let result = self.gestalt(arg(0)?, arg(1)?);
+                if arg(0)? == gestalt::CHAR_OUTPUT && arg(2)? != 0 && arg(3)? > 0 {
+                    memory.set_u32(arg(2)?, (result != 0) as u32).map_err(GlkError::MemoryError)?;
+                }
+                Ok(result)
+            }
+
+            // Windows
+            0x0020 => Self::iterate(memory, &self.windows, arg(0)?, arg(1)?, |window| window.rock),
+            0x0021 => Ok(self.window(arg(0)?)?.rock),
+            0x0022 => Ok(self.root_window()),
+            0x0023 => {
+                let id = self.split_window(arg(0)?, arg(1)?, arg(2)?, arg(3)?, arg(4)?)?;
+                self.backend.window_opened(id, arg(3)?, arg(0)?, arg(1)?, arg(2)?);
+                Ok(id)
+            }
+            0x0024 => self.close_window(memory, arg(0)?, arg(1)?).map(|_| 0),
+            0x0026 => self.set_arrangement(memory, arg(0)?, arg(1)?, arg(2)?, arg(3)?).map(|_| 0),
+            0x0027 => {
+                let arrangement = *self.arrangement(arg(0)?)?;
+                write_out(memory, arg(1)?, &[arrangement.method])?;
+                write_out(memory, arg(2)?, &[arrangement.size])?;
+                write_out(memory, arg(3)?, &[arrangement.key])?;
+                Ok(0)
+            }
+            0x0028 => Ok(self.window(arg(0)?)?.kind),
+            0x0029 => Ok(self.window(arg(0)?)?.parent),
+            0x0030 => self.sibling(arg(0)?),
+            0x002A => { self.window(arg(0)?)?; self.backend.clear_window(arg(0)?); Ok(0) }
+            0x002B => { self.window(arg(0)?)?; self.backend.move_cursor(arg(0)?, arg(1)?, arg(2)?); Ok(0) }
+            0x0025 => {
+                self.window(arg(0)?)?;
+                let (width, height) = self.backend.window_size(arg(0)?);
+                write_out(memory, arg(1)?, &[width])?;
+                write_out(memory, arg(2)?, &[height])?;
+                Ok(0)
+            }
+            0x002C => Ok(self.window(arg(0)?)?.stream),
+            0x002F => {
+                self.current_stream = match arg(0)? {
+                    0 => 0,
+                    id => self.window(id)?.stream,
+                };
+                Ok(0)
+            }
+
+            // Styles
+            0x0086 => self.set_style(self.current_stream, arg(0)?).map(|_| 0),
+            0x0087 => self.set_style(arg(0)?, arg(1)?).map(|_| 0),
+            0x00B0 => {
+                for kind in Self::hinted_types(arg(0)?) {
+                    self.style_hints.insert((kind, arg(1)?, arg(2)?), arg(3)?);
+                }
+                Ok(0)
+            }
+            0x00B1 => {
+                for kind in Self::hinted_types(arg(0)?) {
+                    self.style_hints.remove(&(kind, arg(1)?, arg(2)?));
+                }
+                Ok(0)
+            }
+            0x00B2 => { self.window(arg(0)?)?; Ok(self.backend.style_distinguish(arg(0)?, arg(1)?, arg(2)?) as u32) }
+            0x00B3 => self.style_measure(memory, arg(0)?, arg(1)?, arg(2)?, arg(3)?),
+
+            // Streams
+            0x0040 => Self::iterate(memory, &self.streams, arg(0)?, arg(1)?, |stream| stream.rock),
+            0x0041 => Ok(self.stream(arg(0)?)?.rock),
+            0x0042 => self.open_file_stream(arg(0)?, arg(1)?, arg(2)?, false),
+            0x0043 => Ok(self.open_memory_stream(arg(0)?, arg(1)?, arg(3)?, false)),
+            0x0044 => self.close_stream(memory, arg(0)?, arg(1)?).map(|_| 0),
+            0x0045 => self.stream(arg(0)?)?.set_position(arg(1)? as i32, arg(2)?).map(|_| 0),
+            0x0046 => self.stream(arg(0)?)?.get_position(),
+            0x0047 => {
+                self.current_stream = match arg(0)? {
+                    0 => 0,
+                    id => { self.stream(id)?; id }
+                };
+                Ok(0)
+            }
+            0x0048 => Ok(self.current_stream),
+            0x0138 => self.open_file_stream(arg(0)?, arg(1)?, arg(2)?, true),
+            0x0139 => Ok(self.open_memory_stream(arg(0)?, arg(1)?, arg(3)?, true)),
+
+            // Filerefs
+            0x0060 => {
+                let id = self.allocate_id();
+                self.filerefs.insert(id, Fileref::temporary(id, arg(0)?, arg(1)?));
+                Ok(id)
+            }
+            0x0061 => {
+                let name = Self::read_c_string(memory, arg(1)?)?;
+                let id = self.allocate_id();
+                self.filerefs.insert(id, Fileref::from_name(&name, arg(0)?, arg(2)?));
+                Ok(id)
+            }
+            // The file mode only matters for the prompt, which isn't shown.
+            0x0062 => {
+                let id = self.allocate_id();
+                self.filerefs.insert(id, Fileref::next_to(&self.story_path, arg(0)?, arg(2)?));
+                Ok(id)
+            }
+            0x0063 => self.filerefs.remove(&arg(0)?).map(|_| 0).ok_or(GlkError::InvalidObject(arg(0)?)),
+            0x0064 => Self::iterate(memory, &self.filerefs, arg(0)?, arg(1)?, |fileref| fileref.rock),
+
+            // Character output
+            0x0080 => self.put_char(memory, self.current_stream, arg(0)? as u8 as char).map(|_| 0),
+            0x0081 => self.put_char(memory, arg(0)?, arg(1)? as u8 as char).map(|_| 0),
+            0x0082 => self.put_latin1(memory, self.current_stream, arg(0)?, None).map(|_| 0),
+            0x0083 => self.put_latin1(memory, arg(0)?, arg(1)?, None).map(|_| 0),
+            0x0084 => self.put_latin1(memory, self.current_stream, arg(0)?, Some(arg(1)?)).map(|_| 0),
+            0x0085 => self.put_latin1(memory, arg(0)?, arg(1)?, Some(arg(2)?)).map(|_| 0),
+            0x0128 => self.put_char(memory, self.current_stream, char::from_u32(arg(0)?).unwrap_or(char::REPLACEMENT_CHARACTER)).map(|_| 0),
+            0x0129 => self.put_unicode(memory, self.current_stream, arg(0)?, None).map(|_| 0),
+            0x012A => self.put_unicode(memory, self.current_stream, arg(0)?, Some(arg(1)?)).map(|_| 0),
+            0x012B => self.put_char(memory, arg(0)?, char::from_u32(arg(1)?).unwrap_or(char::REPLACEMENT_CHARACTER)).map(|_| 0),
+            0x012C => self.put_unicode(memory, arg(0)?, arg(1)?, None).map(|_| 0),
+            0x012D => self.put_unicode(memory, arg(0)?, arg(1)?, Some(arg(2)?)).map(|_| 0),
+
+            // Case conversion
+            0x00A0 => Ok(case::latin1_to_lower(arg(0)?)),
+            0x00A1 => Ok(case::latin1_to_upper(arg(0)?)),
+            0x0120 => Self::change_case(memory, arg(0)?, arg(1)?, arg(2)?, case::to_lower),
+            0x0121 => Self::change_case(memory, arg(0)?, arg(1)?, arg(2)?, case::to_upper),
+            0x0122 => {
+                let lower_rest = arg(3)? != 0;
+                Self::change_case(memory, arg(0)?, arg(1)?, arg(2)?, |text| case::to_title(text, lower_rest))
+            }
+
+            // Character input
+            0x0090 => self.get_char(memory, arg(0)?, false),
+            0x0130 => self.get_char(memory, arg(0)?, true),
+
+            // Events
+            0x00C0 => {
+                match self.events.pop_front().or_else(|| self.due_timer_event()) {
+                    Some(event) => self.deliver_event(memory, arg(0)?, event)?,
+                    None => self.select_address = Some(arg(0)?),
+                }
+                Ok(0)
+            }
+            0x00C1 => {
+                // A poll never returns player input, only events the library raised itself.
+                let internal = self.events.iter()
+                    .position(|event| matches!(event.kind, evtype::TIMER | evtype::ARRANGE | evtype::REDRAW | evtype::SOUND_NOTIFY | evtype::VOLUME_NOTIFY));
+                let event = internal.and_then(|index| self.events.remove(index))
+                    .or_else(|| self.due_timer_event())
+                    .unwrap_or(GlkEvent { kind: evtype::NONE, window: 0, val1: 0, val2: 0 });
+                Self::write_event(memory, arg(0)?, event).map(|_| 0)
+            }
+            // Initial buffer contents aren't supported, the line always starts empty.
+            0x00D0 | 0x0141 => {
+                let window = self.window(arg(0)?)?;
+                let request = LineRequest {
+                    buffer: arg(1)?, capacity: arg(2)?, unicode: selector == 0x0141,
+                    echo: window.echo_line, terminators: window.line_terminators.clone(),
+                };
+                window.line_request = Some(request);
+                Ok(0)
+            }
+            0x0150 => { self.window(arg(0)?)?.echo_line = arg(1)? != 0; Ok(0) }
+            // Keys that can't end a line are left out of the set.
+            0x0151 => {
+                let (addr, count) = (arg(1)?, arg(2)?);
+                let mut terminators = Vec::new();
+                for index in 0..count {
+                    let key = memory.get_u32(addr + 4 * index).map_err(GlkError::MemoryError)?;
+                    if keycode::is_terminator(key) { terminators.push(key) }
+                }
+                self.window(arg(0)?)?.line_terminators = terminators;
+                Ok(0)
+            }
+            0x00D1 => {
+                let id = arg(0)?;
+                let request = self.window(id)?.line_request.take();
+                let event = match request {
+                    Some(_) => GlkEvent { kind: evtype::LINE_INPUT, window: id, val1: 0, val2: 0 },
+                    None => GlkEvent { kind: evtype::NONE, window: 0, val1: 0, val2: 0 },
+                };
+                Self::write_event(memory, arg(1)?, event).map(|_| 0)
+            }
+
+            // Images
+            0x00E0 => self.image_get_info(memory, arg(0)?, arg(1)?, arg(2)?),
+            0x00E1 => self.image_draw(arg(0)?, arg(1)?, arg(2)?, arg(3)?, None),
+            0x00E2 => self.image_draw(arg(0)?, arg(1)?, arg(2)?, arg(3)?, Some((arg(4)?, arg(5)?))),
+
+            // Timers start off and an interval of 0 turns them off again, taking any
+            // tick not delivered yet with it.
+            0x00D6 => {
+                let interval = Duration::from_millis(arg(0)? as u64);
+                self.timer = (!interval.is_zero()).then(|| Timer { interval, next: self.clock.now() + interval });
+                if self.timer.is_none() {
+                    self.events.retain(|event| event.kind != evtype::TIMER);
+                }
+                Ok(0)
+            }
+
+            // Hyperlinks
+            0x0100 => self.set_hyperlink(self.current_stream, arg(0)?).map(|_| 0),
+            0x0101 => self.set_hyperlink(arg(0)?, arg(1)?).map(|_| 0),
+            0x0102 => { self.window(arg(0)?)?.hyperlink_request = true; Ok(0) }
+            0x0103 => { self.window(arg(0)?)?.hyperlink_request = false; Ok(0) }
+
+            _ => Err(GlkError::UnknownSelector(selector))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use super::{*, backend::{Capabilities, HeadlessBackend}};
+    use crate::glulx_terp::test_support::{blorb, png, story};
+
+    fn glk() -> Glk {
+        Glk::new(Box::new(HeadlessBackend::default()))
+    }
+
+    // Memory for a story that does nothing, with `ram` at 0x100.
+    fn memory(ram: &[u8]) -> Memory {
+        Memory::new(story(&[0xC1, 0, 0], ram)).unwrap()
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_be_bytes()).collect()
+    }
+
+    struct GraphicsBackend;
+
+    impl GlkBackend for GraphicsBackend {
+        fn capabilities(&self) -> Capabilities {
+            Capabilities { graphics: true, graphics_transparency: true, ..Default::default() }
+        }
+
+        fn put_char(&mut self, _window: u32, _ch: char) {}
+    }
+
+    #[test]
+    fn graphics_gestalts_follow_the_backend() {
+        let headless = glk();
+        for selector in [gestalt::GRAPHICS, gestalt::GRAPHICS_TRANSPARENCY, gestalt::GRAPHICS_CHAR_INPUT] {
+            assert_eq!(headless.gestalt(selector, 0), 0);
+        }
+        assert_eq!(headless.gestalt(gestalt::DRAW_IMAGE, wintype::GRAPHICS), 0);
+
+        let graphics = Glk::new(Box::new(GraphicsBackend));
+        assert_eq!(graphics.gestalt(gestalt::GRAPHICS, 0), 1);
+        assert_eq!(graphics.gestalt(gestalt::DRAW_IMAGE, wintype::GRAPHICS), 1);
+        assert_eq!(graphics.gestalt(gestalt::DRAW_IMAGE, wintype::TEXT_GRID), 0);
+        assert_eq!(graphics.gestalt(gestalt::GRAPHICS_TRANSPARENCY, 0), 1);
+        assert_eq!(graphics.gestalt(gestalt::GRAPHICS_CHAR_INPUT, 0), 0);
+    }
+
+    #[test]
+    fn input_gestalts_for_the_default_backend() {
+        let glk = glk();
+        assert_eq!(glk.gestalt(gestalt::MOUSE_INPUT, wintype::TEXT_GRID), 0);
+        assert_eq!(glk.gestalt(gestalt::TIMER, 0), 1);
+        assert_eq!(glk.gestalt(gestalt::LINE_INPUT_ECHO, 0), 1);
+        assert_eq!(glk.gestalt(gestalt::LINE_TERMINATORS, 0), 1);
+        assert_eq!(glk.gestalt(gestalt::LINE_TERMINATOR_KEY, keycode::ESCAPE), 1);
+        assert_eq!(glk.gestalt(gestalt::LINE_TERMINATOR_KEY, keycode::RETURN), 0);
+        assert_eq!(glk.gestalt(gestalt::LINE_INPUT, 'a' as u32), 1);
+        assert_eq!(glk.gestalt(gestalt::LINE_INPUT, '\n' as u32), 0);
+        assert_eq!(glk.gestalt(gestalt::LINE_INPUT, 0x3B1), 1);
+        assert_eq!(glk.gestalt(gestalt::CHAR_OUTPUT, 0x1F600), 2);
+        assert_eq!(glk.gestalt(gestalt::CHAR_OUTPUT, 0x9F), 0);
+        assert_eq!(glk.gestalt(gestalt::CHAR_OUTPUT, 0xD800), 0);
+        assert_eq!(glk.gestalt(gestalt::UNICODE, 0), 1);
+        assert_eq!(glk.gestalt(gestalt::HYPERLINKS, 0), 1);
+        assert_eq!(glk.gestalt(gestalt::HYPERLINK_INPUT, wintype::TEXT_BUFFER), 1);
+        assert_eq!(glk.gestalt(gestalt::HYPERLINK_INPUT, wintype::GRAPHICS), 0);
+    }
+
+    #[test]
+    fn get_char_stream_uni_reads_whole_code_points() {
+        let mut memory = memory(&words(&[0x1F600, 0x10FFFF, 'a' as u32]));
+        let mut glk = glk();
+        let uni = glk.dispatch(&mut memory, 0x0139, &[0x100, 3, filemode::READ, 0]).unwrap();
+        for expected in [0x1F600, 0x10FFFF, 'a' as u32, u32::MAX] {
+            assert_eq!(glk.dispatch(&mut memory, 0x0130, &[uni]).unwrap(), expected);
+        }
+
+        // A Latin-1 stream still hands out one byte per character.
+        let bytes = glk.dispatch(&mut memory, 0x0043, &[0x100, 4, filemode::READ, 0]).unwrap();
+        for expected in [0x00, 0x01, 0xF6, 0x00, u32::MAX] {
+            assert_eq!(glk.dispatch(&mut memory, 0x0130, &[bytes]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn uni_file_positions_round_trip() {
+        use fileref::fileusage;
+        let mut memory = memory(&[]);
+        let mut glk = glk();
+        // Text files hold UTF-8 and count bytes, binary ones count 4-byte characters.
+        for (usage, end) in [(fileusage::DATA | fileusage::TEXT_MODE, 3), (fileusage::DATA, 2)] {
+            let fileref = glk.dispatch(&mut memory, 0x0060, &[usage, 0]).unwrap();
+            let stream = glk.dispatch(&mut memory, 0x0138, &[fileref, filemode::WRITE, 0]).unwrap();
+            glk.dispatch(&mut memory, 0x012B, &[stream, 'a' as u32]).unwrap();
+            glk.dispatch(&mut memory, 0x012B, &[stream, 0xE9]).unwrap();
+            assert_eq!(glk.dispatch(&mut memory, 0x0046, &[stream]).unwrap(), end);
+            glk.dispatch(&mut memory, 0x0045, &[stream, 1, 0]).unwrap();
+            assert_eq!(glk.dispatch(&mut memory, 0x0046, &[stream]).unwrap(), 1);
+
+            glk.dispatch(&mut memory, 0x0044, &[stream, 0]).unwrap();
+            std::fs::remove_file(&glk.filerefs[&fileref].path).unwrap();
+        }
+    }
+
+    #[test]
+    fn uni_line_input_stores_code_points() {
+        let mut memory = memory(&[0; 0x80]);
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0141, &[window, 0x100, 3, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x00C0, &[0x140]).unwrap();
+        glk.provide_line(&mut memory, "çaé!").unwrap();
+
+        for (index, expected) in [0xE7, 'a' as u32, 0xE9, 0].into_iter().enumerate() {
+            assert_eq!(memory.get_u32(0x100 + 4 * index as u32).unwrap(), expected);
+        }
+        assert_eq!(memory.get_u32(0x140).unwrap(), evtype::LINE_INPUT);
+        assert_eq!(memory.get_u32(0x148).unwrap(), 3);
+    }
+
+    fn transcript(glk: &Glk) -> String {
+        (glk.backend() as &dyn Any).downcast_ref::<HeadlessBackend>().unwrap().transcript().to_string()
+    }
+
+    #[test]
+    fn line_without_echo_still_fills_the_buffer() {
+        let mut memory = memory(&[0; 0x40]);
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0150, &[window, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x00D0, &[window, 0x100, 20, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x00C0, &[0x120]).unwrap();
+        glk.provide_line(&mut memory, "secret").unwrap();
+        assert_eq!(memory.bytes(0x100, 6).unwrap(), b"secret");
+        assert_eq!(memory.get_u32(0x128).unwrap(), 6);
+        assert_eq!(transcript(&glk), "");
+
+        glk.dispatch(&mut memory, 0x0150, &[window, 1]).unwrap();
+        glk.dispatch(&mut memory, 0x00D0, &[window, 0x100, 20, 0]).unwrap();
+        glk.provide_line(&mut memory, "shown").unwrap();
+        assert_eq!(transcript(&glk), "shown\n");
+    }
+
+    #[test]
+    fn terminator_ends_an_echoed_line() {
+        // Escape and 'A' as the terminators at 0x130, only Escape is one Glk allows.
+        let mut memory = memory(&[vec![0; 0x30], words(&[keycode::ESCAPE, 'A' as u32])].concat());
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0151, &[window, 0x130, 2]).unwrap();
+        glk.dispatch(&mut memory, 0x00D0, &[window, 0x100, 20, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x00C0, &[0x120]).unwrap();
+
+        assert!(matches!(glk.provide_terminated_line(&mut memory, "x", 'A' as u32), Err(GlkError::NotATerminator(0x41))));
+        glk.provide_terminated_line(&mut memory, "look", keycode::ESCAPE).unwrap();
+        assert_eq!(memory.bytes(0x100, 4).unwrap(), b"look");
+        for (offset, expected) in [evtype::LINE_INPUT, window, 4, keycode::ESCAPE].into_iter().enumerate() {
+            assert_eq!(memory.get_u32(0x120 + 4 * offset as u32).unwrap(), expected);
+        }
+        assert_eq!(transcript(&glk), "look\n");
+    }
+
+    #[test]
+    fn iterate_walks_each_kind_of_object_with_its_rock() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 7]).unwrap();
+        let stream = glk.dispatch(&mut memory, 0x0043, &[0x108, 4, filemode::WRITE, 9]).unwrap();
+        let fileref = glk.dispatch(&mut memory, 0x0060, &[0, 11]).unwrap();
+
+        let mut walk = |selector| {
+            let mut found = Vec::new();
+            let mut id = 0;
+            loop {
+                id = glk.dispatch(&mut memory, selector, &[id, 0x100]).unwrap();
+                found.push((id, memory.get_u32(0x100).unwrap()));
+                if id == 0 { return found }
+            }
+        };
+        assert_eq!(walk(0x0020), [(window, 7), (0, 0)]);
+        assert_eq!(walk(0x0040), [(window + 1, 0), (stream, 9), (0, 0)]);
+        assert_eq!(walk(0x0064), [(fileref, 11), (0, 0)]);
+    }
+
+    #[test]
+    fn current_stream_must_be_open_or_none() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        let stream = glk.dispatch(&mut memory, 0x002C, &[window]).unwrap();
+        glk.dispatch(&mut memory, 0x0047, &[stream]).unwrap();
+        assert!(matches!(glk.dispatch(&mut memory, 0x0047, &[window]), Err(GlkError::InvalidObject(_))));
+        assert_eq!(glk.dispatch(&mut memory, 0x0048, &[]).unwrap(), stream);
+        glk.dispatch(&mut memory, 0x0047, &[0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x0048, &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn closing_a_window_gives_its_place_to_the_sibling() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = glk();
+        assert_eq!(glk.dispatch(&mut memory, 0x0022, &[]).unwrap(), 0);
+        let main = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        let status = glk.dispatch(&mut memory, 0x0023, &[main, 0x12, 1, wintype::TEXT_GRID, 7]).unwrap();
+        let pair = glk.dispatch(&mut memory, 0x0029, &[status]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x0022, &[]).unwrap(), pair);
+        assert_eq!(glk.dispatch(&mut memory, 0x0030, &[status]).unwrap(), main);
+        assert_eq!(glk.dispatch(&mut memory, 0x0030, &[pair]).unwrap(), 0);
+        assert_eq!(glk.dispatch(&mut memory, 0x0028, &[status]).unwrap(), wintype::TEXT_GRID);
+        assert_eq!(glk.dispatch(&mut memory, 0x0021, &[status]).unwrap(), 7);
+
+        let stream = glk.dispatch(&mut memory, 0x002C, &[status]).unwrap();
+        glk.dispatch(&mut memory, 0x0047, &[stream]).unwrap();
+        glk.dispatch(&mut memory, 0x0081, &[stream, 'x' as u32]).unwrap();
+        glk.dispatch(&mut memory, 0x0024, &[status, 0x100]).unwrap();
+        assert_eq!((memory.get_u32(0x100).unwrap(), memory.get_u32(0x104).unwrap()), (0, 1));
+
+        assert_eq!(glk.dispatch(&mut memory, 0x0022, &[]).unwrap(), main);
+        assert_eq!(glk.dispatch(&mut memory, 0x0029, &[main]).unwrap(), 0);
+        assert_eq!(glk.dispatch(&mut memory, 0x0048, &[]).unwrap(), 0);
+        assert!(matches!(glk.dispatch(&mut memory, 0x0029, &[pair]), Err(GlkError::InvalidObject(_))));
+
+        // Closing the root takes every window with it.
+        glk.dispatch(&mut memory, 0x0023, &[main, 0x12, 1, wintype::TEXT_GRID, 0]).unwrap();
+        let root = glk.dispatch(&mut memory, 0x0022, &[]).unwrap();
+        glk.dispatch(&mut memory, 0x0024, &[root, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x0020, &[0, 0]).unwrap(), 0);
+        assert_eq!(glk.dispatch(&mut memory, 0x0040, &[0, 0]).unwrap(), 0);
+    }
+
+    #[derive(Default)]
+    struct StyleBackend {
+        styles: Vec<(u32, u32)>,
+        links: Vec<(u32, u32)>,
+    }
+
+    impl GlkBackend for StyleBackend {
+        fn put_char(&mut self, _window: u32, _ch: char) {}
+
+        fn set_style(&mut self, window: u32, style: u32) {
+            self.styles.push((window, style));
+        }
+
+        // Only emphasized text (style 1) stands out.
+        fn style_distinguish(&self, _window: u32, style1: u32, style2: u32) -> bool {
+            (style1 == 1) != (style2 == 1)
+        }
+
+        fn set_hyperlink(&mut self, window: u32, link: u32) {
+            self.links.push((window, link));
+        }
+    }
+
+    #[test]
+    fn styles_reach_the_backend_for_window_streams_only() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = Glk::new(Box::new(StyleBackend::default()));
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0086, &[1]).unwrap();
+        glk.dispatch(&mut memory, 0x002F, &[window]).unwrap();
+        glk.dispatch(&mut memory, 0x0086, &[2]).unwrap();
+        let stream = glk.dispatch(&mut memory, 0x0043, &[0x100, 4, filemode::WRITE, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0087, &[stream, 3]).unwrap();
+        glk.dispatch(&mut memory, 0x0087, &[window + 1, 4]).unwrap();
+
+        let backend = (glk.backend() as &dyn Any).downcast_ref::<StyleBackend>().unwrap();
+        assert_eq!(backend.styles, [(window, 2), (window, 4)]);
+    }
+
+    #[test]
+    fn hyperlinks_and_style_distinctions_come_from_the_backend() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = Glk::new(Box::new(StyleBackend::default()));
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x002F, &[window]).unwrap();
+        glk.dispatch(&mut memory, 0x0100, &[7]).unwrap();
+        let stream = glk.dispatch(&mut memory, 0x0043, &[0x100, 4, filemode::WRITE, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0101, &[stream, 8]).unwrap();
+        glk.dispatch(&mut memory, 0x0101, &[window + 1, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x00B2, &[window, 0, 1]).unwrap(), 1);
+        assert_eq!(glk.dispatch(&mut memory, 0x00B2, &[window, 0, 2]).unwrap(), 0);
+        assert!(matches!(glk.dispatch(&mut memory, 0x00B2, &[stream, 0, 1]), Err(GlkError::InvalidObject(_))));
+
+        let backend = (glk.backend() as &dyn Any).downcast_ref::<StyleBackend>().unwrap();
+        assert_eq!(backend.links, [(window, 7), (window, 0)]);
+        // The default backend tells no styles apart.
+        let mut glk = self::glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x00B2, &[window, 0, 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn char_case_stays_within_latin1() {
+        let mut memory = memory(&[]);
+        let mut glk = glk();
+        for (selector, ch, expected) in [(0x00A0, 'Q', 'q'), (0x00A0, 'É', 'é'), (0x00A1, 'é', 'É'), (0x00A1, 'ß', 'ß'), (0x00A1, 'ÿ', 'ÿ'), (0x00A1, '1', '1')] {
+            assert_eq!(glk.dispatch(&mut memory, selector, &[ch as u32]).unwrap(), expected as u32, "{ch}");
+        }
+    }
+
+    #[test]
+    fn cancelled_hyperlink_request_gets_no_event() {
+        let mut memory = memory(&[0; 0x20]);
+        let mut glk = glk();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0102, &[window]).unwrap();
+        glk.dispatch(&mut memory, 0x0103, &[window]).unwrap();
+        assert!(matches!(glk.provide_hyperlink(&mut memory, window, 5), Err(GlkError::NoInputRequest)));
+        glk.dispatch(&mut memory, 0x00C1, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::NONE);
+
+        glk.dispatch(&mut memory, 0x0102, &[window]).unwrap();
+        glk.provide_hyperlink(&mut memory, window, 5).unwrap();
+        glk.dispatch(&mut memory, 0x00C0, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::HYPERLINK);
+        assert_eq!(memory.get_u32(0x108).unwrap(), 5);
+    }
+
+    #[test]
+    fn results_are_written_through_pointers() {
+        let mut memory = memory(&[0xFF; 0x40]);
+        let mut glk = Glk::new(Box::new(grid_snapshot::GridSnapshotBackend::new(20, 5)));
+        let main = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        let status = glk.dispatch(&mut memory, 0x0023, &[main, 0x12, 1, wintype::TEXT_GRID, 0]).unwrap();
+
+        // A null pointer is skipped, only the height of the main window is written.
+        assert_eq!(glk.dispatch(&mut memory, 0x0025, &[status, 0x100, 0x104]).unwrap(), 0);
+        assert_eq!(glk.dispatch(&mut memory, 0x0025, &[main, 0, 0x108]).unwrap(), 0);
+        assert_eq!((memory.get_u32(0x100).unwrap(), memory.get_u32(0x104).unwrap()), (20, 1));
+        assert_eq!(memory.get_u32(0x108).unwrap(), 4);
+
+        glk.dispatch(&mut memory, 0x00D0, &[main, 0x110, 8, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x00C0, &[0x120]).unwrap(), 0);
+        glk.provide_line(&mut memory, "go").unwrap();
+        for (offset, expected) in [evtype::LINE_INPUT, main, 2, 0].into_iter().enumerate() {
+            assert_eq!(memory.get_u32(0x120 + 4 * offset as u32).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn set_arrangement_resizes_both_children() {
+        let mut memory = memory(&[0; 0x20]);
+        let mut glk = Glk::new(Box::new(grid_snapshot::GridSnapshotBackend::new(20, 5)));
+        let main = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        let status = glk.dispatch(&mut memory, 0x0023, &[main, 0x12, 1, wintype::TEXT_GRID, 0]).unwrap();
+        let pair = glk.dispatch(&mut memory, 0x0029, &[status]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x0029, &[main]).unwrap(), pair);
+
+        glk.dispatch(&mut memory, 0x0026, &[pair, 0x12, 2, 0]).unwrap();
+        glk.dispatch(&mut memory, 0x0025, &[status, 0, 0x100]).unwrap();
+        glk.dispatch(&mut memory, 0x0025, &[main, 0, 0x104]).unwrap();
+        assert_eq!((memory.get_u32(0x100).unwrap(), memory.get_u32(0x104).unwrap()), (2, 3));
+
+        // Zero keeps the key window.
+        glk.dispatch(&mut memory, 0x0027, &[pair, 0x108, 0x10C, 0x110]).unwrap();
+        assert_eq!(memory.get_u32(0x108).unwrap(), 0x12);
+        assert_eq!((memory.get_u32(0x10C).unwrap(), memory.get_u32(0x110).unwrap()), (2, status));
+
+        glk.dispatch(&mut memory, 0x00C0, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::ARRANGE);
+    }
+
+    #[test]
+    fn closed_windows_give_back_their_space() {
+        let mut memory = memory(&[0; 0x20]);
+        let mut glk = Glk::new(Box::new(grid_snapshot::GridSnapshotBackend::new(20, 5)));
+        let main = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        let status = glk.dispatch(&mut memory, 0x0023, &[main, 0x12, 2, wintype::TEXT_GRID, 0]).unwrap();
+        let clock = glk.dispatch(&mut memory, 0x0023, &[status, 0x11, 5, wintype::TEXT_GRID, 0]).unwrap();
+        let aside = glk.dispatch(&mut memory, 0x0023, &[main, 0x13, 1, wintype::TEXT_BUFFER, 0]).unwrap();
+
+        // The clock takes the status line's place across the whole width.
+        glk.dispatch(&mut memory, 0x0024, &[status, 0]).unwrap();
+        let pair = glk.dispatch(&mut memory, 0x0029, &[aside]).unwrap();
+        glk.dispatch(&mut memory, 0x0026, &[pair, 0x13, 2, 0]).unwrap();
+        for (window, size) in [(clock, (20, 2)), (aside, (20, 2)), (main, (20, 1))] {
+            glk.dispatch(&mut memory, 0x0025, &[window, 0x100, 0x104]).unwrap();
+            assert_eq!((memory.get_u32(0x100).unwrap(), memory.get_u32(0x104).unwrap()), size);
+        }
+    }
+
+    #[test]
+    fn zero_interval_cancels_the_timer() {
+        use std::time::Duration;
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = glk();
+        let clock = clock::ManualClock::default();
+        glk.set_clock(Box::new(clock.clone()));
+
+        // Off until asked for.
+        clock.advance(Duration::from_millis(500));
+        glk.dispatch(&mut memory, 0x00C1, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::NONE);
+
+        glk.dispatch(&mut memory, 0x00D6, &[50]).unwrap();
+        clock.advance(Duration::from_millis(60));
+        glk.dispatch(&mut memory, 0x00C1, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::TIMER);
+
+        // A tick already due is dropped along with the timer.
+        clock.advance(Duration::from_millis(60));
+        glk.dispatch(&mut memory, 0x00D6, &[0]).unwrap();
+        clock.advance(Duration::from_millis(600));
+        glk.dispatch(&mut memory, 0x00C1, &[0x100]).unwrap();
+        assert_eq!(memory.get_u32(0x100).unwrap(), evtype::NONE);
+        assert!(!glk.poll_timer(&mut memory).unwrap());
+    }
+
+    #[test]
+    fn style_measure_reads_back_the_hint() {
+        let mut memory = memory(&[0; 0x20]);
+        let mut glk = glk();
+        // stylehint_Weight (4) of style_Emphasized (1) in all window types.
+        glk.dispatch(&mut memory, 0x00B0, &[wintype::ALL_TYPES, 1, 4, 7]).unwrap();
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::TEXT_BUFFER, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x00B3, &[window, 1, 4, 0x100]).unwrap(), 1);
+        assert_eq!(memory.get_u32(0x100).unwrap(), 7);
+
+        // Only hints set before a window opens apply to it.
+        glk.dispatch(&mut memory, 0x00B1, &[wintype::ALL_TYPES, 1, 4]).unwrap();
+        let later = glk.dispatch(&mut memory, 0x0023, &[window, 0x12, 1, wintype::TEXT_BUFFER, 0]).unwrap();
+        assert_eq!(glk.dispatch(&mut memory, 0x00B3, &[later, 1, 4, 0x104]).unwrap(), 0);
+        assert_eq!(memory.get_u32(0x104).unwrap(), 0);
+    }
+
+    // Title-cases `text` at 0x100 with glk_buffer_to_title_case_uni and reads back the result.
+    fn title_case(text: &str, lower_rest: bool) -> String {
+        let chars: Vec<u32> = text.chars().map(u32::from).collect();
+        let mut memory = memory(&words(&chars));
+        let length = glk().dispatch(&mut memory, 0x0122, &[0x100, 32, chars.len() as u32, lower_rest as u32]).unwrap();
+        (0..length).map(|index| char::from_u32(memory.get_u32(0x100 + 4 * index).unwrap()).unwrap()).collect()
+    }
+
+    #[test]
+    fn title_case_can_lowercase_the_rest() {
+        assert_eq!(title_case("hELLO wORLD of gLk", true), "Hello World Of Glk");
+    }
+
+    #[test]
+    fn title_case_can_keep_the_rest() {
+        assert_eq!(title_case("hELLO wORLD of gLk", false), "HELLO WORLD Of GLk");
+    }
+
+    #[test]
+    fn scaled_images_are_recorded_with_their_size() {
+        let mut memory = memory(&[0; 0x10]);
+        let mut glk = glk();
+        glk.set_resources(Blorb::parse(blorb(&[(usage::PICT, 3, *b"PNG ", png(32, 16))])).unwrap());
+        let window = glk.dispatch(&mut memory, 0x0023, &[0, 0, 0, wintype::GRAPHICS, 0]).unwrap();
+
+        assert_eq!(glk.dispatch(&mut memory, 0x00E0, &[3, 0x100, 0x104]).unwrap(), 1);
+        assert_eq!((memory.get_u32(0x100).unwrap(), memory.get_u32(0x104).unwrap()), (32, 16));
+        assert_eq!(glk.dispatch(&mut memory, 0x00E2, &[window, 3, 10, 20, 64, 8]).unwrap(), 1);
+        assert_eq!(glk.dispatch(&mut memory, 0x00E1, &[window, 3, 1, 2]).unwrap(), 1);
+        assert_eq!(glk.dispatch(&mut memory, 0x00E2, &[window, 4, 10, 20, 64, 8]).unwrap(), 0);
+
+        let backend = (glk.backend() as &dyn Any).downcast_ref::<HeadlessBackend>().unwrap();
+        assert_eq!(backend.images(), [
+            ImageDraw { window, image: 3, val1: 10, val2: 20, width: 64, height: 8 },
+            ImageDraw { window, image: 3, val1: 1, val2: 2, width: 32, height: 16 },
+        ]);
+    }
+}
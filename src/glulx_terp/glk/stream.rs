@@ -0,0 +1,164 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}};
+
+use super::{super::memory::Memory, GlkError};
+
+pub mod filemode {
+    pub const WRITE: u32 = 0x01;
+    pub const READ: u32 = 0x02;
+    pub const READ_WRITE: u32 = 0x03;
+    pub const WRITE_APPEND: u32 = 0x05;
+}
+
+pub mod seekmode {
+    pub const START: u32 = 0;
+    pub const CURRENT: u32 = 1;
+    pub const END: u32 = 2;
+}
+
+#[derive(Debug)]
+pub enum StreamKind {
+    Window(u32),
+    // Length and position are counted in characters, i.e. words for unicode streams.
+    Memory { buffer: u32, length: u32, position: u32 },
+    File { file: File, text: bool },
+}
+
+#[derive(Debug)]
+pub struct Stream {
+    pub kind: StreamKind,
+    pub rock: u32,
+    pub unicode: bool,
+    pub read_count: u32,
+    pub write_count: u32,
+}
+
+impl Stream {
+    pub fn new(kind: StreamKind, rock: u32, unicode: bool) -> Self {
+        Self { kind, rock, unicode, read_count: 0, write_count: 0 }
+    }
+
+    // Writes to memory or file streams, window streams are handled by Glk itself.
+    pub fn write_char(&mut self, memory: &mut Memory, ch: char) -> Result<(), GlkError> {
+        self.write_count += 1;
+        match &mut self.kind {
+            StreamKind::Window(_) => Ok(()),
+            StreamKind::Memory { buffer, length, position } => {
+                if *position >= *length { return Ok(()) }
+                if self.unicode {
+                    memory.set_u32(*buffer + 4 * *position, ch as u32).map_err(GlkError::MemoryError)?;
+                } else {
+                    memory.set_u8(*buffer + *position, latin1(ch)).map_err(GlkError::MemoryError)?;
+                }
+                *position += 1;
+                Ok(())
+            }
+            StreamKind::File { file, text } => {
+                let result = match (self.unicode, *text) {
+                    (false, _) => file.write_all(&[latin1(ch)]),
+                    (true, false) => file.write_all(&(ch as u32).to_be_bytes()),
+                    (true, true) => file.write_all(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+                };
+                result.map_err(GlkError::IOError)
+            }
+        }
+    }
+
+    // Returns the next character as a code point, or None at the end of the stream.
+    pub fn read_char(&mut self, memory: &Memory) -> Result<Option<u32>, GlkError> {
+        let value = match &mut self.kind {
+            StreamKind::Window(_) => None,
+            StreamKind::Memory { buffer, length, position } => {
+                if *position >= *length {
+                    None
+                } else {
+                    let value = if self.unicode {
+                        memory.get_u32(*buffer + 4 * *position).map_err(GlkError::MemoryError)?
+                    } else {
+                        memory.get_u8(*buffer + *position).map_err(GlkError::MemoryError)? as u32
+                    };
+                    *position += 1;
+                    Some(value)
+                }
+            }
+            StreamKind::File { file, text } => match (self.unicode, *text) {
+                (false, _) => read_bytes::<1>(file)?.map(|[byte]| byte as u32),
+                (true, false) => read_bytes::<4>(file)?.map(u32::from_be_bytes),
+                (true, true) => read_utf8(file)?,
+            },
+        };
+
+        if value.is_some() {
+            self.read_count += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn get_position(&mut self) -> Result<u32, GlkError> {
+        match &mut self.kind {
+            StreamKind::Window(_) => Ok(0),
+            StreamKind::Memory { position, .. } => Ok(*position),
+            // Unicode text files are UTF-8, positions there are in bytes like set_position takes them.
+            StreamKind::File { file, text } => {
+                let position = file.stream_position().map_err(GlkError::IOError)? as u32;
+                Ok(if self.unicode && !*text { position / 4 } else { position })
+            }
+        }
+    }
+
+    pub fn set_position(&mut self, position: i32, mode: u32) -> Result<(), GlkError> {
+        match &mut self.kind {
+            StreamKind::Window(_) => Ok(()),
+            StreamKind::Memory { length, position: current, .. } => {
+                let base = match mode {
+                    seekmode::CURRENT => *current as i64,
+                    seekmode::END => *length as i64,
+                    _ => 0,
+                };
+                *current = (base + position as i64).clamp(0, *length as i64) as u32;
+                Ok(())
+            }
+            StreamKind::File { file, text } => {
+                let scale = if self.unicode && !*text { 4 } else { 1 };
+                let offset = position as i64 * scale;
+                let seek = match mode {
+                    seekmode::CURRENT => SeekFrom::Current(offset),
+                    seekmode::END => SeekFrom::End(offset),
+                    _ => SeekFrom::Start(offset.max(0) as u64),
+                };
+                file.seek(seek).map(|_| ()).map_err(GlkError::IOError)
+            }
+        }
+    }
+}
+
+pub fn latin1(ch: char) -> u8 {
+    if (ch as u32) < 0x100 { ch as u8 } else { b'?' }
+}
+
+fn read_bytes<const N: usize>(file: &mut File) -> Result<Option<[u8; N]>, GlkError> {
+    let mut bytes = [0u8; N];
+    match file.read_exact(&mut bytes) {
+        Ok(()) => Ok(Some(bytes)),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(GlkError::IOError(err)),
+    }
+}
+
+fn read_utf8(file: &mut File) -> Result<Option<u32>, GlkError> {
+    let Some([first]) = read_bytes::<1>(file)? else { return Ok(None) };
+    let extra = match first {
+        0x00..=0x7F => 0,
+        0xC0..=0xDF => 1,
+        0xE0..=0xEF => 2,
+        0xF0..=0xF7 => 3,
+        _ => return Ok(Some(char::REPLACEMENT_CHARACTER as u32)),
+    };
+
+    let mut bytes = vec![first];
+    for _ in 0..extra {
+        let Some([byte]) = read_bytes::<1>(file)? else { break };
+        bytes.push(byte);
+    }
+    let ch = std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next());
+    Ok(Some(ch.unwrap_or(char::REPLACEMENT_CHARACTER) as u32))
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+pub mod wintype {
+    pub const ALL_TYPES: u32 = 0;
+    pub const PAIR: u32 = 1;
+    pub const BLANK: u32 = 2;
+    pub const TEXT_BUFFER: u32 = 3;
+    pub const TEXT_GRID: u32 = 4;
+    pub const GRAPHICS: u32 = 5;
+}
+
+#[derive(Debug, Clone)]
+pub struct LineRequest {
+    pub buffer: u32,
+    // In characters, which are words for unicode requests.
+    pub capacity: u32,
+    pub unicode: bool,
+    // Whether the line is shown in the window once entered, fixed when requesting.
+    pub echo: bool,
+    // Keys besides Enter that end the line, also fixed when requesting.
+    pub terminators: Vec<u32>,
+}
+
+// How a pair window divides its space. `second` is the window whose opening split
+// `first` and created the pair.
+#[derive(Debug, Clone, Copy)]
+pub struct Arrangement {
+    pub method: u32,
+    pub size: u32,
+    pub key: u32,
+    pub first: u32,
+    pub second: u32,
+}
+
+#[derive(Debug)]
+pub struct Window {
+    pub kind: u32,
+    pub rock: u32,
+    // The pair window holding this one, 0 for the root.
+    pub parent: u32,
+    // Only set for pair windows.
+    pub arrangement: Option<Arrangement>,
+    pub stream: u32,
+    pub line_request: Option<LineRequest>,
+    // Set by glk_set_echo_line_event, applies to the next line requests.
+    pub echo_line: bool,
+    // Set by glk_set_terminators_line_event, like echo_line.
+    pub line_terminators: Vec<u32>,
+    pub hyperlink_request: bool,
+    // Style hints in effect when the window was opened, keyed by (style, hint).
+    pub style_hints: BTreeMap<(u32, u32), u32>,
+}
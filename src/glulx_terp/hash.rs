@@ -0,0 +1,84 @@
+use super::GlulxTerp;
+
+// FNV-1a, spelled out so the value doesn't depend on the standard library's
+// hasher, which may change between Rust releases.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_be_bytes());
+    }
+}
+
+impl GlulxTerp {
+    // A digest of everything that decides what the story does next: RAM, the stack with
+    // its frames, the PC and the heap's blocks. ROM can't change so it's left out, and
+    // the heap is walked by address, so two interpreters in the same state agree however
+    // they got there.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv::new();
+        hasher.write_u32(self.pc);
+
+        let ram = self.memory.ram();
+        hasher.write_u32(ram.len() as u32);
+        hasher.write(ram);
+
+        hasher.write_u32(self.stack.fp());
+        hasher.write_u32(self.stack.sp());
+        hasher.write(self.stack.contents());
+
+        match &self.heap {
+            Some(heap) => {
+                hasher.write_u32(heap.start());
+                for (addr, len) in heap.blocks() {
+                    hasher.write_u32(addr);
+                    hasher.write_u32(len);
+                }
+            }
+            None => hasher.write_u32(0),
+        }
+        hasher.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, GlulxTerp};
+
+    // Two steps into a story writing 7 to l0 and 0x104.
+    fn two_steps() -> GlulxTerp {
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x10, &[Arg::Const(3), Arg::Const(4), Arg::Local(0)]),
+            op(0x10, &[Arg::Const(3), Arg::Const(4), Arg::Addr(0x104)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 0x10])).unwrap();
+        for _ in 0..2 { terp.step().unwrap(); }
+        terp
+    }
+
+    #[test]
+    fn same_state_hashes_the_same() {
+        let (first, mut second) = (two_steps(), two_steps());
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        second.memory.set_u8(0x10F, 1).unwrap();
+        assert_ne!(first.state_hash(), second.state_hash());
+        second.memory.set_u8(0x10F, 0).unwrap();
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        second.step().unwrap();
+        assert_ne!(first.state_hash(), second.state_hash());
+    }
+}
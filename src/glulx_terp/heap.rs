@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+// A live allocation, as reported to debugging tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapBlock {
+    pub addr: u32,
+    pub len: u32,
+}
+
+// Bytes in use and bytes free between the start of the heap and the end of memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapSummary {
+    pub allocated: u32,
+    pub free: u32,
+}
+
+// A MALLOC that returned 0: how much was asked for, how big memory was, and the
+// function asking, when known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationFailure {
+    pub requested: u32,
+    pub memory_size: u32,
+    pub function: Option<u32>,
+}
+
+// Blocks handed out by MALLOC. The heap starts at the end of memory as it was at the
+// first allocation, memory is extended as needed to hold the blocks.
+#[derive(Debug)]
+pub struct Heap {
+    start: u32,
+    // Allocated blocks by address, with their length.
+    blocks: BTreeMap<u32, u32>,
+}
+
+impl Heap {
+    pub fn new(start: u32) -> Self {
+        Self { start, blocks: BTreeMap::new() }
+    }
+
+    pub fn from_blocks(start: u32, blocks: impl IntoIterator<Item = (u32, u32)>) -> Self {
+        Self { start, blocks: blocks.into_iter().collect() }
+    }
+
+    // Allocated blocks as (address, length), by address.
+    pub fn blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.blocks.iter().map(|(&addr, &len)| (addr, len))
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    // Reserves `size` bytes at the start of the lowest gap that fits, or after the
+    // last block. Only allocated blocks are tracked, free space is whatever lies
+    // between them, so freed neighbours merge by themselves and the same sequence of
+    // calls always returns the same addresses, which save replays rely on.
+    // Returns None if the block would end past the 32-bit address space.
+    pub fn allocate(&mut self, size: u32) -> Option<u32> {
+        let mut pos = self.start;
+        for (&addr, &len) in &self.blocks {
+            if addr - pos >= size { break }
+            pos = addr + len;
+        }
+        pos.checked_add(size)?;
+        self.blocks.insert(pos, size);
+        Some(pos)
+    }
+
+    pub fn free(&mut self, addr: u32) -> bool {
+        self.blocks.remove(&addr).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, Errors, GlulxTerp};
+
+    #[test]
+    fn freed_blocks_are_no_longer_listed() {
+        let code = function(&[(4, 2)], 0xC1, &[
+            op(0x178, &[Arg::Const(0x10), Arg::Local(0)]),
+            op(0x178, &[Arg::Const(0x20), Arg::Local(4)]),
+            op(0x179, &[Arg::Local(0)]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        assert!(terp.heap_blocks().is_empty());
+        assert_eq!(terp.heap_summary(), HeapSummary::default());
+
+        for _ in 0..3 {
+            terp.step().unwrap();
+        }
+        assert_eq!(terp.heap_blocks(), [HeapBlock { addr: 0x210, len: 0x20 }]);
+        assert_eq!(terp.heap_summary(), HeapSummary { allocated: 0x20, free: 0xE0 });
+    }
+
+    #[test]
+    fn allocation_takes_the_lowest_gap_that_fits() {
+        let mut heap = Heap::new(0x1000);
+        let blocks = [heap.allocate(0x10), heap.allocate(0x20), heap.allocate(0x10)];
+        assert_eq!(blocks, [Some(0x1000), Some(0x1010), Some(0x1030)]);
+
+        // The first two blocks merge into a 0x30 gap once both are freed.
+        assert!(heap.free(0x1000));
+        assert!(heap.free(0x1010));
+        assert!(!heap.free(0x1010));
+        assert_eq!(heap.allocate(0x28), Some(0x1000));
+        assert_eq!(heap.allocate(0x8), Some(0x1028));
+        assert_eq!(heap.allocate(0x1), Some(0x1040));
+    }
+
+    #[test]
+    fn freed_neighbours_are_reused_together() {
+        // Four blocks of 0x10 in l0-l3, the middle two freed, then one of 0x20. The
+        // second block is written to first, the new one must still come back zeroed.
+        let mut body: Vec<Vec<u8>> = (0..4).map(|local| op(0x178, &[Arg::Const(0x10), Arg::Local(4 * local)])).collect();
+        body.push(op(0x4C, &[Arg::Local(4), Arg::Zero, Arg::Const(-1)]));
+        body.push(op(0x179, &[Arg::Local(4)]));
+        body.push(op(0x179, &[Arg::Local(8)]));
+        body.push(op(0x178, &[Arg::Const(0x20), Arg::Stack]));
+        body.push(op(0x179, &[Arg::Const(0x214)]));
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[(4, 4)], 0xC1, &body), &[])).unwrap();
+        for _ in 0..8 { terp.step().unwrap(); }
+
+        let second = terp.stack.get_local(4, 4).unwrap();
+        assert_eq!(second, 0x210);
+        assert_eq!(terp.stack.pop().unwrap(), second);
+        assert_eq!(terp.memory.get_u32(second).unwrap(), 0);
+        assert_eq!(terp.heap_blocks().iter().map(|block| block.len).collect::<Vec<_>>(), [0x10, 0x20, 0x10]);
+
+        // Only the start of a block can be freed.
+        assert!(matches!(terp.step(), Err(Errors::InvalidFree(0x214))));
+    }
+}
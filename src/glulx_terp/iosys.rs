@@ -0,0 +1,146 @@
+use super::{stack::CallStub, strings::StringPos, Errors, GlulxTerp};
+
+pub mod mode {
+    pub const NULL: u32 = 0;
+    pub const FILTER: u32 = 1;
+    pub const GLK: u32 = 2;
+}
+
+// Call stub types the output opcodes push when printing through a filter function,
+// so that returning from the filter carries on with the rest of the output.
+pub(in crate::glulx_terp) mod stub {
+    // The next bit of a compressed string, PC holds its address and DestAddr the bit.
+    pub const RESUME_COMPRESSED: u32 = 0x10;
+    // Back to the instruction after the one that started printing.
+    pub const RESUME_CODE: u32 = 0x11;
+    // The next digit of a number, PC holds the number and DestAddr the digit's position.
+    pub const RESUME_NUMBER: u32 = 0x12;
+    // The next character of an uncompressed string, at PC.
+    pub const RESUME_LATIN1: u32 = 0x13;
+    pub const RESUME_UNICODE: u32 = 0x14;
+}
+
+// Where STREAMCHAR and the other output opcodes send characters, chosen with
+// SETIOSYS. The filter system calls the function `rock` with each character.
+// Stories start with the null system, which discards everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoSystem {
+    pub mode: u32,
+    pub rock: u32,
+}
+
+impl IoSystem {
+    // Unsupported modes select the null system.
+    pub fn new(mode: u32, rock: u32) -> Self {
+        let mode = if mode <= mode::GLK { mode } else { mode::NULL };
+        Self { mode, rock }
+    }
+}
+
+impl GlulxTerp {
+    pub fn io_system(&self) -> IoSystem {
+        self.iosys
+    }
+
+    pub(in crate::glulx_terp) fn set_io_system(&mut self, iosys: IoSystem) {
+        self.iosys = iosys;
+    }
+
+    // Calls the filter function with `ch`, returning to the given stub.
+    fn call_filter(&mut self, stub: CallStub, ch: u32) -> Result<(), Errors> {
+        self.stack.push_call_stub(stub.dest_type, stub.dest_addr, stub.pc).map_err(Errors::StackError)?;
+        self.enter_function(self.iosys.rock, &[ch])
+    }
+
+    // STREAMCHAR and STREAMUNICHAR. A filter's result is discarded, and execution
+    // goes on after the instruction once it returns.
+    pub(in crate::glulx_terp) fn stream_char(&mut self, ch: u32) -> Result<(), Errors> {
+        match self.iosys.mode {
+            mode::FILTER => self.call_filter(CallStub { dest_type: 0, dest_addr: 0, pc: self.pc, frame_ptr: 0 }, ch),
+            mode::GLK => {
+                let ch = char::from_u32(ch).unwrap_or(char::REPLACEMENT_CHARACTER);
+                self.glk.put_current_char(&mut self.memory, ch).map_err(Errors::Glk)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // STREAMNUM, printing from digit `pos` on. Through a filter only one character is
+    // sent at a time: a RESUME_NUMBER stub brings us back here for the next, and the
+    // RESUME_CODE stub pushed first (`resuming` once it is there) is popped at the end.
+    pub(in crate::glulx_terp) fn stream_num(&mut self, value: u32, pos: u32, resuming: bool) -> Result<(), Errors> {
+        let text = (value as i32).to_string();
+        for (index, ch) in text.chars().enumerate().skip(pos as usize) {
+            if self.iosys.mode == mode::FILTER {
+                if !resuming {
+                    self.stack.push_call_stub(stub::RESUME_CODE, 0, self.pc).map_err(Errors::StackError)?;
+                }
+                let next = CallStub { dest_type: stub::RESUME_NUMBER, dest_addr: index as u32 + 1, pc: value, frame_ptr: 0 };
+                return self.call_filter(next, ch as u32)
+            }
+            self.stream_char(ch as u32)?;
+        }
+
+        if resuming {
+            let stub = self.stack.pop_call_stub().map_err(Errors::StackError)?;
+            self.pc = stub.pc;
+        }
+        Ok(())
+    }
+
+    // Picks up output where a filter function returned to one of the stubs above.
+    pub(in crate::glulx_terp) fn resume_output(&mut self, stub: CallStub) -> Result<(), Errors> {
+        match stub.dest_type {
+            stub::RESUME_CODE => {
+                self.pc = stub.pc;
+                Ok(())
+            }
+            stub::RESUME_NUMBER => self.stream_num(stub.pc, stub.dest_addr, true),
+            other => match StringPos::from_stub(stub) {
+                Some(pos) => self.stream_string(pos, true),
+                None => Err(Errors::InvalidCallStub(other)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glulx_terp::{exec::ExecState, glk::backend::HeadlessBackend, test_support::{function, glk, op, story, Arg}};
+
+    #[test]
+    fn output_follows_the_selected_system() {
+        // Null first, then Glk, then the filter at 0x100 which prints each character
+        // itself. GETIOSYS saves the filter at 0x1F0. Mode 7 doesn't exist and falls
+        // back to null but keeps its rock.
+        let code = function(&[], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Stack),
+            glk(0x2F, vec![Arg::Stack], Arg::Zero),
+            op(0x70, &[Arg::Const('a' as i32)]),
+            op(0x71, &[Arg::Const(5)]),
+            op(0x149, &[Arg::Const(2), Arg::Zero]),
+            op(0x70, &[Arg::Const(0x168)]),
+            op(0x71, &[Arg::Const(-42)]),
+            op(0x73, &[Arg::Const(0x263A)]),
+            op(0x149, &[Arg::Const(1), Arg::Const(0x100)]),
+            op(0x71, &[Arg::Const(17)]),
+            op(0x70, &[Arg::Const('z' as i32)]),
+            op(0x148, &[Arg::Addr(0x1F0), Arg::Addr(0x1F4)]),
+            op(0x149, &[Arg::Const(7), Arg::Const(9)]),
+            op(0x70, &[Arg::Const('q' as i32)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let filter = function(&[(4, 1)], 0xC1, &[
+            glk(0x80, vec![Arg::Local(0)], Arg::Zero),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &filter)).unwrap();
+        assert_eq!(terp.run().unwrap(), ExecState::Halted);
+
+        // STREAMCHAR keeps the low byte, numbers come out signed.
+        assert_eq!(terp.glk_backend::<HeadlessBackend>().unwrap().transcript(), "h-42\u{263A}17z");
+        assert_eq!((terp.memory.get_u32(0x1F0).unwrap(), terp.memory.get_u32(0x1F4).unwrap()), (mode::FILTER, 0x100));
+        assert_eq!(terp.io_system(), IoSystem { mode: mode::NULL, rock: 9 });
+    }
+}
@@ -1,16 +1,23 @@
-use std::{io::Cursor, ops::{Deref, DerefMut}};
+use std::io::Cursor;
 
 use binread::{BinRead, BinReaderExt};
 
+use super::blorb::{Blorb, BlorbError};
 
-#[derive(BinRead, Debug)]
+const HEADER_LEN: u32 = 36;
+const CHECKSUM_POS: usize = 32;
+// Memory is allocated whole, so a corrupt header or save mustn't be able to ask
+// for gigabytes. Real stories are a few megabytes at most.
+pub const MAX_SIZE: u32 = 0x1000_0000;
+
+#[derive(BinRead, Debug, Clone, Copy)]
 pub struct Version {
     pub major: u16,
     pub minor: u8,
     pub patch: u8,
 }
 
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone, Copy)]
 #[br(magic = b"Glul")]
 #[br(big)]
 pub struct Header {
@@ -24,115 +31,484 @@ pub struct Header {
     pub checksum: u32,
 }
 
+// Identification of a story build. Inform stories put a release number and a
+// serial code (usually the compile date) in the "Info" block right after the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameId {
+    pub release: Option<u16>,
+    pub serial: Option<String>,
+    pub checksum: u32,
+}
+
+impl Header {
+    // Layout rules from the spec's "Memory Map" section, only enforced in strict mode.
+    pub fn check_layout(&self, length: u32) -> Result<(), MemoryError> {
+        if !(2..=3).contains(&self.version.major) {
+            return Err(MemoryError::InvalidHeader(format!("Unsupported Glulx version {}.{}.{}", self.version.major, self.version.minor, self.version.patch)))
+        }
+
+        for (name, value) in [("ram_start", self.ram_start), ("ext_start", self.ext_start), ("end_mem", self.end_mem)] {
+            if value % 256 != 0 {
+                return Err(MemoryError::InvalidHeader(format!("{name} ({value:X}) isn't a multiple of 256")))
+            }
+        }
+
+        if self.ram_start < 0x100 || self.ram_start > self.ext_start || self.ext_start > self.end_mem {
+            return Err(MemoryError::InvalidHeader("Expected 256 <= ram_start <= ext_start <= end_mem".to_string()))
+        }
+
+        if self.ext_start != length {
+            return Err(MemoryError::InvalidHeader(format!("ext_start ({:X}) doesn't match the file length ({length:X})", self.ext_start)))
+        }
+
+        Ok(())
+    }
+
+    // Addresses the VM starts from must at least point inside the memory map.
+    pub fn check_addresses(&self) -> Result<(), MemoryError> {
+        if self.end_mem > MAX_SIZE {
+            return Err(MemoryError::InvalidHeader(format!("end_mem ({:X}) is larger than {MAX_SIZE:X}", self.end_mem)))
+        }
+
+        for (name, value) in [("ram_start", self.ram_start), ("ext_start", self.ext_start)] {
+            if value > self.end_mem {
+                return Err(MemoryError::InvalidHeader(format!("{name} ({value:X}) is past end_mem ({:X})", self.end_mem)))
+            }
+        }
+
+        if self.start_func >= self.end_mem {
+            return Err(MemoryError::InvalidHeader(format!("start_func ({:X}) is past end_mem ({:X})", self.start_func, self.end_mem)))
+        }
+
+        if self.decoding_tree != 0 && self.decoding_tree >= self.end_mem {
+            return Err(MemoryError::InvalidHeader(format!("decoding_tree ({:X}) is past end_mem ({:X})", self.decoding_tree, self.end_mem)))
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum MemoryError {
     NotEnoughData(usize),
-    BadChecksum
+    BadChecksum,
+    InvalidHeader(String),
+    OutOfBounds { pos: u32, len: u32 },
+    WriteToRom(u32),
+    Blorb(BlorbError),
+    // Memory can't be resized below end_mem or past MAX_SIZE, and only in multiples of 256 bytes.
+    InvalidSize(u32)
 }
 
-pub struct Memory {
-    raw: Vec<u8>,
-    start_ram_address: u32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Short,
+    Word,
 }
 
-impl Deref for Memory {
-    type Target = Vec<u8>;
-    fn deref(&self) -> &Self::Target {
-        &self.raw
+impl Width {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Short => 2,
+            Width::Word => 4,
+        }
     }
-}
 
-impl DerefMut for Memory {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.raw
+    // The bits a value of this width keeps.
+    pub fn mask(self) -> u32 {
+        match self {
+            Width::Byte => 0xFF,
+            Width::Short => 0xFFFF,
+            Width::Word => 0xFFFF_FFFF,
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+pub struct Memory {
+    raw: Vec<u8>,
+    // Memory as it was loaded, what saves are diffed against.
+    original: Vec<u8>,
+    header: Header,
+    start_ram_address: u32
+}
+
 impl Memory {
-    pub fn new(raw: Vec<u8>) -> Result<Self, MemoryError> {
+    pub fn new(mut raw: Vec<u8>) -> Result<Self, MemoryError> {
         if raw.len() < 36 { return Err(MemoryError::NotEnoughData(raw.len())) }
 
-        let mut memory = Self {
-            raw,
-            start_ram_address: 0
-        };
-        
-        memory.start_ram_address = memory.get_header().expect("Bad file header").ram_start;
+        let header: Header = Cursor::new(&raw).read_be()
+            .map_err(|err| MemoryError::InvalidHeader(err.to_string()))?;
+        header.check_addresses()?;
+        // RAM starts with bytes from the file, so ram_start has to be inside it.
+        if header.ram_start as usize > raw.len() {
+            return Err(MemoryError::InvalidHeader(format!("ram_start ({:X}) is past the end of the file ({:X})", header.ram_start, raw.len())))
+        }
+
+        // Memory between ext_start and end_mem isn't stored in the file and starts zeroed.
+        if (header.end_mem as usize) > raw.len() {
+            raw.resize(header.end_mem as usize, 0);
+        }
 
-        Ok(memory)
+        Ok(Self {
+            original: raw.clone(),
+            raw,
+            header,
+            start_ram_address: header.ram_start
+        })
     } 
 
+    // Stories usually ship wrapped in a Blorb file with their pictures and sounds,
+    // only the Glulx image inside is loaded then.
+    pub fn from_blorb_or_raw(raw: Vec<u8>) -> Result<Self, MemoryError> {
+        Self::new(Self::story_image(raw)?)
+    }
+
+    // The Glulx image inside a Blorb file, or `raw` itself if it's a bare story.
+    pub fn story_image(raw: Vec<u8>) -> Result<Vec<u8>, MemoryError> {
+        if !Blorb::is_blorb(&raw) { return Ok(raw) }
+        let blorb = Blorb::parse(raw).map_err(MemoryError::Blorb)?;
+        Ok(blorb.executable().map_err(MemoryError::Blorb)?.to_vec())
+    }
+
     // Specials
-    pub fn get_header(&self) -> Result<Header, binread::Error> {
-        Cursor::new(&self.raw).read_be()
+    // The header lives in ROM, so it's parsed once when the memory is created.
+    pub fn get_header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn size(&self) -> u32 {
+        self.raw.len() as u32
+    }
+
+    // Grows or shrinks memory, new bytes start zeroed.
+    pub fn set_size(&mut self, size: u32) -> Result<(), MemoryError> {
+        if size < self.header.end_mem || size > MAX_SIZE || !size.is_multiple_of(256) { return Err(MemoryError::InvalidSize(size)) }
+        self.raw.resize(size as usize, 0);
+        Ok(())
+    }
+
+    // RAM from ram_start to the current end of memory, heap included.
+    pub fn ram(&self) -> &[u8] {
+        &self.raw[self.start_ram_address as usize..]
+    }
+
+    // RAM as the story file had it, to diff the current state against.
+    pub fn initial_ram(&self) -> &[u8] {
+        &self.original[self.start_ram_address as usize..]
+    }
+
+    // The first 128 bytes of the story file, which identify it in save files.
+    pub fn identification(&self) -> &[u8] {
+        &self.original[..self.original.len().min(128)]
+    }
+
+    // Replaces all of RAM, resizing memory so it ends right after `ram`. The bytes of
+    // `protected` (address, length) keep their current values, as far as they are in
+    // RAM both before and after.
+    pub fn restore_ram(&mut self, ram: &[u8], protected: Option<(u32, u32)>) {
+        let start = self.start_ram_address as usize;
+        let kept = protected.map(|(addr, len)| {
+            let from = (addr as usize).max(start).min(self.raw.len());
+            let to = (addr as usize + len as usize).min(self.raw.len()).max(from);
+            (from, self.raw[from..to].to_vec())
+        });
+
+        self.raw.resize(start + ram.len(), 0);
+        self.raw[start..].copy_from_slice(ram);
+
+        if let Some((from, bytes)) = kept {
+            let len = bytes.len().min(self.raw.len().saturating_sub(from));
+            self.raw[from..from + len].copy_from_slice(&bytes[..len]);
+        }
+    }
+
+    // Whether `width` bytes at `pos` will be writable once RAM is replaced by `ram_len`
+    // bytes, so a restore can be checked before it's done.
+    pub fn writable_after_restore(&self, pos: u32, width: u32, ram_len: usize) -> bool {
+        let end = self.start_ram_address as usize + ram_len;
+        pos >= self.start_ram_address.max(HEADER_LEN) && pos as usize + width as usize <= end
+    }
+
+    // Sum of every word of the story file except the checksum itself, computed on the
+    // image as loaded so changes made while playing don't matter.
+    pub fn checksum(&self) -> u32 {
+        let len = (self.header.ext_start as usize).min(self.original.len());
+        self.original[..len].chunks_exact(4)
+            .enumerate()
+            .filter(|&(index, _)| index * 4 != CHECKSUM_POS)
+            .fold(0u32, |sum, (_, word)| sum.wrapping_add(u32::from_be_bytes(word.try_into().unwrap())))
+    }
+
+    pub fn game_id(&self) -> GameId {
+        const INFO_POS: usize = 0x24;
+        let info = self.raw.get(INFO_POS..INFO_POS + 0x18).filter(|info| &info[0..4] == b"Info");
+        GameId {
+            release: info.map(|info| u16::from_be_bytes([info[0x10], info[0x11]])),
+            serial: info.map(|info| info[0x12..0x18].iter().map(|&byte| byte as char).collect()),
+            checksum: self.header.checksum,
+        }
     }
 
     fn add_ram_offset(&self, value: u32) -> u32 {
         self.start_ram_address.wrapping_add(value)
     }
 
-    pub fn as_cursor(&self) -> Cursor<&Vec<u8>> {
-        Cursor::new(&self.raw)
+    // All of memory, ROM included, for decoding instructions in place.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    fn check_bounds(&self, pos: u32, width: u32) -> Result<usize, MemoryError> {
+        match pos.checked_add(width) {
+            Some(end) if end as usize <= self.raw.len() => Ok(pos as usize),
+            _ => Err(MemoryError::OutOfBounds { pos, len: self.raw.len() as u32 })
+        }
+    }
+
+    // The header (checksum included) is always read-only, even if a malformed
+    // story puts ram_start inside it.
+    fn check_writable(&self, pos: u32, width: u32) -> Result<usize, MemoryError> {
+        if pos < self.start_ram_address.max(HEADER_LEN) { return Err(MemoryError::WriteToRom(pos)) }
+        self.check_bounds(pos, width)
+    }
+
+    // Validates a whole range up front, so bulk operations either fully happen or touch nothing.
+    pub fn check_range(&self, addr: u32, len: u32, access: Access) -> Result<(), MemoryError> {
+        if len == 0 { return Ok(()) }
+        match access {
+            Access::Read => self.check_bounds(addr, len),
+            Access::Write => self.check_writable(addr, len),
+        }.map(|_| ())
     }
 
     // Getters
-    pub fn get_u8(&self, pos: u32) -> u8 {
-        // TODO: Error handling
-        self[pos as usize]
+    pub fn bytes(&self, pos: u32, len: u32) -> Result<&[u8], MemoryError> {
+        let pos = self.check_bounds(pos, len)?;
+        Ok(&self.raw[pos..pos + len as usize])
+    }
+
+    pub fn get_u8(&self, pos: u32) -> Result<u8, MemoryError> {
+        let pos = self.check_bounds(pos, 1)?;
+        Ok(self.raw[pos])
     }
 
-    pub fn get_u16(&self, pos: u32) -> u16 {
-        // TODO: Error handling
-        let pos = pos as usize;
-        u16::from_be_bytes(self[pos..pos+2].try_into().unwrap())
+    pub fn get_u16(&self, pos: u32) -> Result<u16, MemoryError> {
+        let pos = self.check_bounds(pos, 2)?;
+        Ok(u16::from_be_bytes(self.raw[pos..pos+2].try_into().unwrap()))
     }
 
-    pub fn get_u32(&self, pos: u32) -> u32 {
-        // TODO: Error handling
-        let pos = pos as usize;
-        u32::from_be_bytes(self[pos..pos+4].try_into().unwrap())
+    pub fn get_u32(&self, pos: u32) -> Result<u32, MemoryError> {
+        let pos = self.check_bounds(pos, 4)?;
+        Ok(u32::from_be_bytes(self.raw[pos..pos+4].try_into().unwrap()))
     }
 
-    pub fn get_ram_u8(&self, pos: u32) -> u8 {
+    pub fn get_ram_u8(&self, pos: u32) -> Result<u8, MemoryError> {
         self.get_u8(self.add_ram_offset(pos))
     }
 
-    pub fn get_ram_u16(&self, pos: u32) -> u16 {
+    pub fn get_ram_u16(&self, pos: u32) -> Result<u16, MemoryError> {
         self.get_u16(self.add_ram_offset(pos))
     }
 
-    pub fn get_ram_u32(&self, pos: u32) -> u32 {
+    pub fn get_ram_u32(&self, pos: u32) -> Result<u32, MemoryError> {
         self.get_u32(self.add_ram_offset(pos))
     }
 
+    pub fn get_ram(&self, pos: u32, width: Width) -> Result<u32, MemoryError> {
+        self.get(self.add_ram_offset(pos), width)
+    }
+
     // Setters
-    pub fn set_u8(&mut self, pos: u32, value: u8) {
-        // TODO: Error handling
-        let pos = pos as usize;
-        self[pos] = value ;
+    pub fn set_u8(&mut self, pos: u32, value: u8) -> Result<(), MemoryError> {
+        let pos = self.check_writable(pos, 1)?;
+        self.raw[pos] = value;
+        Ok(())
+    }
+
+    pub fn set_u16(&mut self, pos: u32, value: u16) -> Result<(), MemoryError> {
+        let pos = self.check_writable(pos, 2)?;
+        self.raw[pos..pos+2].copy_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    pub fn set_u32(&mut self, pos: u32, value: u32) -> Result<(), MemoryError> {
+        let pos = self.check_writable(pos, 4)?;
+        self.raw[pos..pos+4].copy_from_slice(&value.to_be_bytes());
+        Ok(())
     }
 
-    pub fn set_u16(&mut self, pos: u32, value: u16) {
-        // TODO: Error handling
-        let pos = pos as usize;
-        self[pos..pos+2].copy_from_slice(&value.to_be_bytes());
+    pub fn get(&self, pos: u32, width: Width) -> Result<u32, MemoryError> {
+        match width {
+            Width::Byte => self.get_u8(pos).map(u32::from),
+            Width::Short => self.get_u16(pos).map(u32::from),
+            Width::Word => self.get_u32(pos),
+        }
     }
 
-    pub fn set_u32(&mut self, pos: u32, value: u32) {
-        // TODO: Error handling
-        let pos = pos as usize;
-        self[pos..pos+4].copy_from_slice(&value.to_be_bytes());
+    // Values are truncated to the width.
+    pub fn set(&mut self, pos: u32, value: u32, width: Width) -> Result<(), MemoryError> {
+        match width {
+            Width::Byte => self.set_u8(pos, value as u8),
+            Width::Short => self.set_u16(pos, value as u16),
+            Width::Word => self.set_u32(pos, value),
+        }
     }
 
-    pub fn set_ram_u8(&mut self, pos: u32, value: u32) {
+    // A zero length is a no-op, whatever the address.
+    pub fn zero(&mut self, addr: u32, len: u32) -> Result<(), MemoryError> {
+        if len == 0 { return Ok(()) }
+        self.check_range(addr, len, Access::Write)?;
+        let addr = addr as usize;
+        self.raw[addr..addr + len as usize].fill(0);
+        Ok(())
+    }
+
+    // Behaves like memmove, overlapping ranges are copied as if through a temporary buffer.
+    // A zero length is a no-op, whatever the addresses.
+    pub fn copy(&mut self, src: u32, dest: u32, len: u32) -> Result<(), MemoryError> {
+        if len == 0 { return Ok(()) }
+        self.check_range(src, len, Access::Read)?;
+        self.check_range(dest, len, Access::Write)?;
+        let src = src as usize;
+        self.raw.copy_within(src..src + len as usize, dest as usize);
+        Ok(())
+    }
+
+    pub fn set_ram_u8(&mut self, pos: u32, value: u32) -> Result<(), MemoryError> {
         self.set_u8(self.add_ram_offset(pos), value as u8)
     }
 
-    pub fn set_ram_u16(&mut self, pos: u32, value: u32) {
+    pub fn set_ram_u16(&mut self, pos: u32, value: u32) -> Result<(), MemoryError> {
         self.set_u16(self.add_ram_offset(pos), value as u16)
     }
 
-    pub fn set_ram_u32(&mut self, pos: u32, value: u32) {
+    pub fn set_ram_u32(&mut self, pos: u32, value: u32) -> Result<(), MemoryError> {
         self.set_u32(self.add_ram_offset(pos), value)
     }
-}
\ No newline at end of file
+
+    pub fn set_ram(&mut self, pos: u32, value: u32, width: Width) -> Result<(), MemoryError> {
+        self.set(self.add_ram_offset(pos), value, width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glulx_terp::test_support::{blorb, story};
+
+    #[test]
+    fn zero_past_the_end_leaves_memory_alone() {
+        let mut memory = Memory::new(story(&[0xC1, 0, 0], &[0xAA; 0x100])).unwrap();
+        assert!(matches!(memory.check_range(0x1F0, 0x20, Access::Write), Err(MemoryError::OutOfBounds { .. })));
+        assert!(matches!(memory.zero(0x1F0, 0x20), Err(MemoryError::OutOfBounds { .. })));
+        assert!(memory.bytes(0x1F0, 0x10).unwrap().iter().all(|&byte| byte == 0xAA));
+
+        assert!(matches!(memory.copy(0x100, 0x1F8, 0x10), Err(MemoryError::OutOfBounds { .. })));
+        assert!(memory.bytes(0x1F0, 0x10).unwrap().iter().all(|&byte| byte == 0xAA));
+        assert!(matches!(memory.zero(0x30, 0x20), Err(MemoryError::WriteToRom(0x30))));
+    }
+
+    #[test]
+    fn game_id_reads_the_inform_info_block() {
+        let memory = Memory::new(std::fs::read("assets/glulxercise.ulx").unwrap()).unwrap();
+        let id = memory.game_id();
+        assert_eq!(id.release, Some(10));
+        assert_eq!(id.serial.as_deref(), Some("220722"));
+        assert_eq!(id.checksum, memory.get_header().checksum);
+
+        let memory = Memory::new(story(&[0xC1, 0, 0], &[])).unwrap();
+        assert_eq!(memory.game_id().release, None);
+    }
+
+    #[test]
+    fn headers_pointing_outside_memory_are_rejected() {
+        let with_header = |pos: usize, value: u32| {
+            let mut raw = story(&[0xC1, 0, 0], &[]);
+            raw[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+            Memory::new(raw)
+        };
+        // ram_start, ext_start and end_mem sit at 8, 12 and 16.
+        for (pos, value) in [(8, 0x300), (12, 0x300), (16, 0xFFFF_FF00)] {
+            assert!(matches!(with_header(pos, value), Err(MemoryError::InvalidHeader(_))), "{pos} {value:X}");
+        }
+
+        // end_mem can be past the file, ram_start can't.
+        let mut raw = story(&[0xC1, 0, 0], &[]);
+        raw[16..20].copy_from_slice(&0x1000u32.to_be_bytes());
+        raw.truncate(0x100);
+        assert!(Memory::new(raw.clone()).is_ok());
+        raw.truncate(0xF0);
+        assert!(matches!(Memory::new(raw), Err(MemoryError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn initial_ram_keeps_the_load_time_bytes() {
+        let mut memory = Memory::new(story(&[0xC1, 0, 0], &[1, 2, 3, 4])).unwrap();
+        memory.set_u8(0x101, 9).unwrap();
+        assert_eq!(memory.initial_ram()[..4], [1, 2, 3, 4]);
+        assert_eq!(memory.ram()[..4], [1, 9, 3, 4]);
+        assert_eq!(memory.initial_ram().len(), memory.ram().len());
+    }
+
+    #[test]
+    fn reads_must_fit_before_the_end() {
+        let memory = Memory::new(story(&[0xC1, 0, 0], &[])).unwrap();
+        let end = memory.size();
+        assert_eq!(end, 0x200);
+        assert!(memory.get_u8(end - 1).is_ok());
+        assert!(memory.get_u16(end - 2).is_ok());
+        assert!(memory.get_u32(end - 4).is_ok());
+        for err in [memory.get_u8(end).map(u32::from), memory.get_u16(end - 1).map(u32::from), memory.get_u32(end - 3), memory.get_u32(u32::MAX)] {
+            assert!(matches!(err, Err(MemoryError::OutOfBounds { len: 0x200, .. })), "{err:?}");
+        }
+        assert!(matches!(memory.get_ram_u32(0xFD), Err(MemoryError::OutOfBounds { pos: 0x1FD, .. })));
+    }
+
+    #[test]
+    fn writes_below_ram_start_are_rejected() {
+        let mut memory = Memory::new(story(&[0xC1, 0, 0], &[])).unwrap();
+        assert!(matches!(memory.set_u8(0x40, 0), Err(MemoryError::WriteToRom(0x40))));
+        assert!(matches!(memory.set_u32(0xFE, 0), Err(MemoryError::WriteToRom(0xFE))));
+        assert_eq!(memory.get_u8(0x40).unwrap(), 0xC1);
+        memory.set_u32(0x100, 0x1234_5678).unwrap();
+        assert!(matches!(memory.set_u16(0x1FF, 0), Err(MemoryError::OutOfBounds { .. })));
+        assert_eq!(memory.get_ram_u32(0).unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn size_stays_within_max_size() {
+        let mut memory = Memory::new(story(&[0xC1, 0, 0], &[])).unwrap();
+        let size = memory.size();
+        assert!(matches!(memory.set_size(MAX_SIZE + 0x100), Err(MemoryError::InvalidSize(_))));
+        assert_eq!(memory.size(), size);
+        memory.set_size(size + 0x100).unwrap();
+        assert_eq!(memory.size(), size + 0x100);
+    }
+
+    #[test]
+    fn story_is_taken_out_of_a_blorb() {
+        use crate::glulx_terp::blorb::{usage, BlorbError};
+        let image = story(&[0xC1, 0, 0], &[]);
+        let wrapped = blorb(&[(usage::PICT, 1, *b"PNG ", vec![1, 2, 3]), (usage::EXEC, 0, *b"GLUL", image.clone())]);
+        let memory = Memory::from_blorb_or_raw(wrapped.clone()).unwrap();
+        assert_eq!(memory.bytes(0, memory.size()).unwrap(), image);
+        assert!(Memory::from_blorb_or_raw(image).is_ok());
+
+        let terp = crate::glulx_terp::GlulxTerp::from_reader(&mut std::io::Cursor::new(wrapped.clone())).unwrap();
+        assert_eq!(terp.memory.get_u32(0).unwrap(), u32::from_be_bytes(*b"Glul"));
+        // Strict mode checks ext_start against the image, not the whole Blorb.
+        let strict = crate::glulx_terp::config::TerpConfig { strict: true, ..Default::default() };
+        assert!(crate::glulx_terp::GlulxTerp::from_bytes_with_config(wrapped, strict).is_ok());
+
+        let pictures_only = blorb(&[(usage::PICT, 1, *b"PNG ", vec![1])]);
+        assert!(matches!(Memory::from_blorb_or_raw(pictures_only), Err(MemoryError::Blorb(BlorbError::MissingExecutable))));
+    }
+}
@@ -1,19 +1,116 @@
+mod accel;
+pub mod blorb;
+pub mod config;
+pub mod exec;
+pub mod gestalt;
+pub mod glk;
+pub mod hash;
+pub mod heap;
+pub mod iosys;
 pub mod memory;
-mod operations;
-use std::io::Read;
-use self::{memory::{Memory, MemoryError}, operations::Operation};
+pub mod operations;
+pub mod rng;
+pub mod quetzal;
+pub mod stack;
+pub mod stats;
+mod strings;
+pub mod trace;
+mod undo;
+#[cfg(any(test, feature = "test-driver"))]
+pub mod test_driver;
+#[cfg(test)]
+mod test_support;
+use std::{any::Any, collections::{BTreeMap, VecDeque}, io::Read, panic::{self, AssertUnwindSafe}};
+use self::{config::TerpConfig, glk::{Glk, backend::{GlkBackend, HeadlessBackend}}, memory::{Memory, MemoryError, Width}, operations::{Operand, OperandAddressingMode, OperandMode, Operation}, exec::{ExecState, PauseReason}, heap::Heap, stack::{CallStub, Stack, StackError}, stats::RunStats};
 
 pub struct GlulxTerp {
     memory: Memory,
-    pc: u32
+    stack: Stack,
+    glk: Glk,
+    pc: u32,
+    config: TerpConfig,
+    stats: RunStats,
+    state: ExecState,
+    heap: Option<Heap>,
+    accel: accel::Accel,
+    // Whether step() fills `record`, for step_n.
+    recording: bool,
+    record: Option<trace::TraceRecord>,
+    tracer: Option<trace::Tracer>,
+    rng: Box<dyn rng::RandomSource>,
+    // SAVEUNDO snapshots, oldest first.
+    undo: VecDeque<quetzal::SaveState>,
+    // Function each frame belongs to, by frame pointer. Only used in diagnostics.
+    frame_functions: BTreeMap<u32, u32>,
+    last_allocation_failure: Option<heap::AllocationFailure>,
+    iosys: iosys::IoSystem,
+    // Decoding table for compressed strings, the header's until SETSTRINGTBL changes it.
+    string_table: u32,
+    // Range set by PROTECT, as (address, length), that restoring leaves alone. It
+    // isn't part of the game state, saves and undo don't include it.
+    protected: Option<(u32, u32)>
+}
+
+// A function's type byte (0xC0 or 0xC1), its locals format and the address of its first instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionHeader {
+    pub kind: u8,
+    pub format: Vec<(u8, u8)>,
+    pub body: u32,
+}
+
+impl FunctionHeader {
+    // Passes `args` to the frame just pushed for this function. A 0xC0 function finds
+    // them on its stack, last one deepest, under their count. A 0xC1 function gets them
+    // in its locals in order, extra ones are dropped and missing ones leave locals at 0.
+    pub fn bind_args(&self, stack: &mut Stack, args: &[u32]) -> Result<(), StackError> {
+        if self.kind == 0xC0 {
+            for &arg in args.iter().rev() {
+                stack.push(arg)?;
+            }
+            return stack.push(args.len() as u32)
+        }
+
+        let mut args = args.iter();
+        let mut offset = 0u32;
+        for &(size, count) in &self.format {
+            let size = size as u32;
+            offset = (offset + size - 1) & !(size - 1);
+            for _ in 0..count {
+                let Some(&arg) = args.next() else { return Ok(()) };
+                stack.set_local(offset, size, arg)?;
+                offset += size;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub enum Errors {
     IOError(std::io::Error),
     MemoryError(memory::MemoryError),
-    BinRead(binread::Error),
-    FetchOperation(String)
+    StackError(stack::StackError),
+    Glk(glk::GlkError),
+    FetchOperation(String),
+    InvalidFunction(u32),
+    StoreToConstant,
+    UnimplementedOPCode(operations::OPCode),
+    InternalPanic { pc: u32 },
+    CycleLimitReached(u64),
+    InvalidCallStub(u32),
+    InvalidCatchToken(u32),
+    InvalidFree(u32),
+    // STREAMSTR was given something that isn't a string, or a decoding table is broken.
+    InvalidString(u32),
+    NoStringTable,
+    // A search key given directly must be 1, 2 or 4 bytes long.
+    InvalidSearchKeySize(u32),
+    DivisionByZero,
+    // The stack ran out with `depth` frames live. `function` is the innermost one,
+    // when known (frames restored from a save aren't).
+    StackOverflow { depth: u32, limit: u32, function: Option<u32> },
+    Quetzal(quetzal::QuetzalError)
 }
 
 impl GlulxTerp {
@@ -21,54 +118,752 @@ impl GlulxTerp {
         let mut raw: Vec<u8> = Vec::new();
 
         source.read_to_end(&mut raw).map_err(Errors::IOError)?;
-        
-        let memory = Memory::new(raw).map_err(Errors::MemoryError)?;
-        let header = memory.get_header().map_err(Errors::BinRead)?;
-
-        { // Check if the header's checksum is valid.
-            const CHECKSUM_POS: u32 = 8*4;
-            let mut index = CHECKSUM_POS;
-            let valid_checksum: u32 = memory.get_u32(index);
-            let mut checksum = 0u32;
-            let length = memory.len() as u32;
-
-            index = 0;
-            while index < CHECKSUM_POS {
-                checksum = checksum.wrapping_add(memory.get_u32(index));
-                index += 4;
-            }
-            index = CHECKSUM_POS+4;
-            while index < length {
-                checksum = checksum.wrapping_add(memory.get_u32(index));
-                index += 4;
-            }
 
-            if checksum != valid_checksum {
-                return Err(Errors::MemoryError(MemoryError::BadChecksum))
-            }
+        // The pictures and sounds of a Blorb file come with the story.
+        if !blorb::Blorb::is_blorb(&raw) { return Self::from_bytes(raw) }
+        let resources = blorb::Blorb::parse(raw).map_err(|err| Errors::MemoryError(MemoryError::Blorb(err)))?;
+        let story = resources.executable().map_err(|err| Errors::MemoryError(MemoryError::Blorb(err)))?.to_vec();
+        let mut terp = Self::from_bytes(story)?;
+        terp.set_resources(resources);
+        Ok(terp)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, Errors> {
+        Self::from_bytes_with_config(data, Default::default())
+    }
+
+    pub fn from_bytes_with_config(data: Vec<u8>, config: TerpConfig) -> Result<Self, Errors> {
+        // The layout rules measure the Glulx image, not the Blorb file around it.
+        let story = Memory::story_image(data).map_err(Errors::MemoryError)?;
+        let file_length = story.len() as u32;
+        let memory = Memory::new(story).map_err(Errors::MemoryError)?;
+        let header = *memory.get_header();
+
+        if config.strict {
+            header.check_layout(file_length).map_err(Errors::MemoryError)?;
+        }
+
+        if config.verify_checksum && memory.checksum() != header.checksum {
+            return Err(Errors::MemoryError(MemoryError::BadChecksum))
         }
-        
-        Ok(Self {
+
+        let rng = Box::new(config.random_seed.map_or_else(rng::Rng::from_entropy, rng::Rng::seeded));
+        let mut terp = Self {
             memory,
-            pc: header.start_func
-        })
+            stack: Stack::new(header.stack_size),
+            glk: Glk::new(Box::new(HeadlessBackend::default())),
+            pc: 0,
+            config,
+            stats: RunStats::default(),
+            state: ExecState::Running,
+            heap: None,
+            accel: Default::default(),
+            recording: false,
+            tracer: None,
+            record: None,
+            rng,
+            undo: VecDeque::new(),
+            frame_functions: BTreeMap::new(),
+            last_allocation_failure: None,
+            iosys: Default::default(),
+            string_table: header.decoding_tree,
+            protected: None
+        };
+        terp.enter_function(header.start_func, &[])?;
+        terp.stats.peak_stack = terp.stack.sp();
+        terp.stats.peak_memory = terp.memory.size();
+
+        Ok(terp)
+    }
+
+    // Resumes a game from a Quetzal save, right after the SAVE that made it.
+    // Fails with Errors::Quetzal when the save belongs to another story.
+    pub fn from_bytes_and_save(story: Vec<u8>, save: &[u8]) -> Result<Self, Errors> {
+        let mut terp = Self::from_bytes(story)?;
+        terp.restore_from_bytes(save)?;
+        Ok(terp)
+    }
+
+    // Reads memory from outside the VM, e.g. for debuggers and cheat tools.
+    pub fn peek(&self, addr: u32, width: memory::Width) -> Result<u32, MemoryError> {
+        self.memory.get(addr, width)
+    }
+
+    // Writes memory from outside the VM. ROM stays read-only, like for the story itself.
+    pub fn poke(&mut self, addr: u32, value: u32, width: memory::Width) -> Result<(), MemoryError> {
+        self.memory.set(addr, value, width)
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn game_id(&self) -> memory::GameId {
+        self.memory.game_id()
+    }
+
+    pub fn set_glk_backend(&mut self, backend: Box<dyn GlkBackend>) {
+        self.glk.set_backend(backend);
+    }
+
+    // Where the story was loaded from, saves the player is asked for go next to it.
+    pub fn set_story_path(&mut self, path: impl Into<std::path::PathBuf>) {
+        self.glk.set_story_path(path.into());
+    }
+
+    // Makes the pictures and sounds of the story's Blorb file available through Glk.
+    pub fn set_resources(&mut self, resources: blorb::Blorb) {
+        self.glk.set_resources(resources);
+    }
+
+    // Replaces the generator behind RANDOM, e.g. with one shared with the embedder.
+    pub fn set_random_source(&mut self, source: Box<dyn rng::RandomSource>) {
+        self.rng = source;
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn glk::clock::Clock>) {
+        self.glk.set_clock(clock);
+    }
+
+    pub fn glk_backend<T: GlkBackend>(&self) -> Option<&T> {
+        (self.glk.backend() as &dyn Any).downcast_ref::<T>()
+    }
+
+    // Whether the story is over, by returning from its outermost function or with QUIT.
+    pub fn has_halted(&self) -> bool {
+        matches!(self.state, ExecState::Halted | ExecState::Terminated)
     }
 
-    pub fn step(&mut self) -> Result<(), Errors> {
-        print!("{:X}: ", self.pc);
-        let operation = Operation::fetch(&mut self.memory.as_cursor(), self.pc)?;
-        dbg!(operation);
-        todo!("Execute the operation");
+    // True while the story is blocked in glk_select with nothing to deliver.
+    pub fn awaiting_input(&self) -> bool {
+        self.glk.is_waiting()
+    }
+
+    pub fn provide_input(&mut self, line: &str) -> Result<(), Errors> {
+        self.glk.provide_line(&mut self.memory, line).map_err(Errors::Glk)?;
+        self.resume_after_input();
+        Ok(())
+    }
+
+    // Ends the line with a terminator key instead of Enter, see glk_set_terminators_line_event.
+    pub fn provide_terminated_input(&mut self, line: &str, terminator: u32) -> Result<(), Errors> {
+        self.glk.provide_terminated_line(&mut self.memory, line, terminator).map_err(Errors::Glk)?;
+        self.resume_after_input();
+        Ok(())
+    }
+
+    pub fn provide_hyperlink(&mut self, window: u32, link: u32) -> Result<(), Errors> {
+        self.glk.provide_hyperlink(&mut self.memory, window, link).map_err(Errors::Glk)?;
+        self.resume_after_input();
         Ok(())
     }
 
-    pub fn run(&mut self) {
+    fn resume_after_input(&mut self) {
+        if self.state == ExecState::Paused(PauseReason::AwaitingInput) && !self.glk.is_waiting() {
+            self.state = ExecState::Running;
+        }
+    }
+
+    // Decodes the header of the function at `addr`.
+    pub fn function_header(&self, addr: u32) -> Result<FunctionHeader, Errors> {
+        let kind = self.memory.get_u8(addr).map_err(Errors::MemoryError)?;
+        if kind != 0xC0 && kind != 0xC1 { return Err(Errors::InvalidFunction(addr)) }
+
+        let mut format: Vec<(u8, u8)> = Vec::new();
+        let mut pos = addr + 1;
         loop {
-            let result = self.step();
-            if let Err(err) = result {
-                eprintln!("{:?}", err);
-                break;
+            let size = self.memory.get_u8(pos).map_err(Errors::MemoryError)?;
+            let count = self.memory.get_u8(pos + 1).map_err(Errors::MemoryError)?;
+            pos += 2;
+            if size == 0 { break }
+            if !matches!(size, 1 | 2 | 4) { return Err(Errors::InvalidFunction(addr)) }
+            format.push((size, count));
+        }
+        Ok(FunctionHeader { kind, format, body: pos })
+    }
+
+    // Pushes a new frame for the function at `addr` and moves the pc to its first instruction.
+    fn enter_function(&mut self, addr: u32, args: &[u32]) -> Result<(), Errors> {
+        let header = self.function_header(addr)?;
+        self.stack.push_frame(&header.format).map_err(Errors::StackError)?;
+        self.frame_functions.insert(self.stack.fp(), addr);
+        header.bind_args(&mut self.stack, args).map_err(Errors::StackError)?;
+        self.pc = header.body;
+        Ok(())
+    }
+
+    // The DestType/DestAddr pair of a call stub is derived from the store operand receiving the result.
+    fn push_call_stub(&mut self, store: &Operand) -> Result<(), Errors> {
+        use OperandAddressingMode::*;
+        let (dest_type, dest_addr) = match store.addressing_mode {
+            ConstantZero => (0, 0),
+            ContentOfAddress1Byte(addr) |
+            ContentOfAddress2Bytes(addr) |
+            ContentOfAddress4Bytes(addr) => (1, addr),
+            ContentOfRAMAddress1Byte(addr) |
+            ContentOfRAMAddress2Bytes(addr) |
+            ContentOfRAMAddress4Bytes(addr) => (1, self.memory.get_header().ram_start.wrapping_add(addr)),
+            CallFrameLocalAtAddress1Byte(offset) |
+            CallFrameLocalAtAddress2Bytes(offset) |
+            CallFrameLocalAtAddress4Bytes(offset) => (2, offset),
+            Stack => (3, 0),
+            Constant1Byte(_) |
+            Constant2Bytes(_) |
+            Constant4Bytes(_) => return Err(Errors::StoreToConstant),
+            __Unused1 | __Unused2 => return Err(Errors::FetchOperation(format!("Invalid addressing mode {:?}", store.addressing_mode))),
+        };
+        self.stack.push_call_stub(dest_type, dest_addr, self.pc).map_err(Errors::StackError)
+    }
+
+    fn call_function(&mut self, addr: u32, args: &[u32], store: &Operand) -> Result<(), Errors> {
+        self.push_call_stub(store)?;
+        // Accelerated functions have no frame, but their result takes the same way
+        // back as a RETURN so it lands exactly where the bytecode's would.
+        if let Some(value) = self.call_accelerated(addr, args)? {
+            let stub = self.stack.pop_call_stub().map_err(Errors::StackError)?;
+            return self.resume_from_stub(stub, value)
+        }
+        self.enter_function(addr, args)
+    }
+
+    // The current frame is replaced, so the callee returns straight to its caller's stub.
+    fn tail_call(&mut self, addr: u32, args: &[u32]) -> Result<(), Errors> {
+        if let Some(value) = self.call_accelerated(addr, args)? {
+            return self.return_from_function(value)
+        }
+        self.stack.discard_frame();
+        self.enter_function(addr, args)
+    }
+
+    // Resumes at the stub's PC and stores `value` where the stub says.
+    fn resume_from_stub(&mut self, stub: CallStub, value: u32) -> Result<(), Errors> {
+        // Stubs pushed by the output opcodes, their PC isn't always an address.
+        if stub.dest_type >= 0x10 { return self.resume_output(stub) }
+        self.pc = stub.pc;
+        match stub.dest_type {
+            0 => Ok(()),
+            1 => self.memory.set_u32(stub.dest_addr, value).map_err(Errors::MemoryError),
+            2 => self.stack.set_local(stub.dest_addr, 4, value).map_err(Errors::StackError),
+            3 => self.stack.push(value).map_err(Errors::StackError),
+            other => Err(Errors::InvalidCallStub(other)),
+        }
+    }
+
+    fn return_from_function(&mut self, value: u32) -> Result<(), Errors> {
+        match self.stack.leave_frame().map_err(Errors::StackError)? {
+            Some(stub) => self.resume_from_stub(stub, value),
+            None => {
+                self.state = ExecState::Halted;
+                Ok(())
             }
         }
     }
-}
\ No newline at end of file
+
+    // RESTART: memory goes back to the story file's image and size, apart from the
+    // protected range, and start_func is entered again on an empty stack. The heap,
+    // I/O system and string table are reset too, undo states and Glk objects are kept.
+    fn restart(&mut self) -> Result<(), Errors> {
+        let header = *self.memory.get_header();
+        let mut ram = self.memory.initial_ram().to_vec();
+        ram.resize(header.end_mem.saturating_sub(header.ram_start) as usize, 0);
+        self.memory.restore_ram(&ram, self.protected);
+
+        self.heap = None;
+        self.iosys = Default::default();
+        self.string_table = header.decoding_tree;
+        self.stack.clear();
+        self.frame_functions.clear();
+        self.enter_function(header.start_func, &[])
+    }
+
+    // Returns the address of a new zeroed block, or 0 if it can't be allocated.
+    // A zero-sized (or negative) request allocates nothing and returns 0.
+    fn malloc(&mut self, size: u32) -> Result<u32, Errors> {
+        if size == 0 || (size as i32) < 0 { return Ok(0) }
+
+        let allocated: u64 = self.heap.iter().flat_map(Heap::blocks).map(|(_, len)| len as u64).sum();
+        if self.config.heap_limit.is_some_and(|limit| allocated + size as u64 > limit as u64) {
+            return Ok(self.allocation_failed(size))
+        }
+
+        let memory_size = self.memory.size();
+        let heap = self.heap.get_or_insert_with(|| Heap::new(memory_size));
+        let Some(addr) = heap.allocate(size) else { return Ok(self.allocation_failed(size)) };
+
+        // Memory only ever grows in multiples of 256 bytes.
+        let end = addr as u64 + size as u64;
+        let new_size = (end + 0xFF) & !0xFF;
+        if new_size > memory::MAX_SIZE as u64 {
+            heap.free(addr);
+            return Ok(self.allocation_failed(size))
+        }
+        if end > memory_size as u64 {
+            self.memory.set_size(new_size as u32).map_err(Errors::MemoryError)?;
+        }
+        // Freed blocks may be reused, their old contents must not leak.
+        self.memory.zero(addr, size).map_err(Errors::MemoryError)?;
+
+        self.stats.allocations += 1;
+        Ok(addr)
+    }
+
+    fn allocation_failed(&mut self, requested: u32) -> u32 {
+        // A heap whose first block couldn't be placed was never there, SETMEMSIZE
+        // and GESTALT must not see it.
+        if self.heap.as_ref().is_some_and(Heap::is_empty) {
+            self.heap = None;
+        }
+        self.last_allocation_failure = Some(heap::AllocationFailure {
+            requested,
+            memory_size: self.memory.size(),
+            function: self.frame_functions.get(&self.stack.fp()).copied(),
+        });
+        0
+    }
+
+    fn mfree(&mut self, addr: u32) -> Result<(), Errors> {
+        let heap = self.heap.as_mut().ok_or(Errors::InvalidFree(addr))?;
+        if !heap.free(addr) { return Err(Errors::InvalidFree(addr)) }
+
+        // Once the last block is gone the heap disappears and memory shrinks back.
+        if heap.is_empty() {
+            self.memory.set_size(heap.start()).map_err(Errors::MemoryError)?;
+            self.heap = None;
+        }
+        Ok(())
+    }
+
+    // Blocks allocated by MALLOC and not freed yet, by address. Whatever is still
+    // listed when a story is done with it is a leak.
+    pub fn heap_blocks(&self) -> Vec<heap::HeapBlock> {
+        self.heap.iter()
+            .flat_map(Heap::blocks)
+            .map(|(addr, len)| heap::HeapBlock { addr, len })
+            .collect()
+    }
+
+    pub fn heap_summary(&self) -> heap::HeapSummary {
+        let Some(heap) = &self.heap else { return heap::HeapSummary::default() };
+        let allocated = heap.blocks().map(|(_, len)| len).sum();
+        heap::HeapSummary { allocated, free: self.memory.size() - heap.start() - allocated }
+    }
+
+    // COPYS and COPYB read 16 or 8 bits from memory and locals, constants and popped
+    // values are truncated to that width.
+    fn load_operand_as(&mut self, operand: &Operand, width: Width) -> Result<u32, Errors> {
+        use OperandAddressingMode::*;
+        let value = match operand.addressing_mode {
+            ConstantZero => 0,
+            Constant1Byte(value) => value as u8 as i8 as u32,
+            Constant2Bytes(value) => value as u16 as i16 as u32,
+            Constant4Bytes(value) => value,
+            ContentOfAddress1Byte(addr) |
+            ContentOfAddress2Bytes(addr) |
+            ContentOfAddress4Bytes(addr) => return self.memory.get(addr, width).map_err(Errors::MemoryError),
+            Stack => self.stack.pop().map_err(Errors::StackError)?,
+            CallFrameLocalAtAddress1Byte(offset) |
+            CallFrameLocalAtAddress2Bytes(offset) |
+            CallFrameLocalAtAddress4Bytes(offset) => return self.stack.get_local(offset, width.bytes()).map_err(Errors::StackError),
+            ContentOfRAMAddress1Byte(addr) |
+            ContentOfRAMAddress2Bytes(addr) |
+            ContentOfRAMAddress4Bytes(addr) => return self.memory.get_ram(addr, width).map_err(Errors::MemoryError),
+            __Unused1 | __Unused2 => return Err(Errors::FetchOperation(format!("Invalid addressing mode {:?}", operand.addressing_mode))),
+        };
+        Ok(value & width.mask())
+    }
+
+    fn store_operand(&mut self, operand: &Operand, value: u32) -> Result<(), Errors> {
+        self.store_operand_as(operand, value, Width::Word)
+    }
+
+    // Memory and locals get the low 8 or 16 bits of `value`, the stack always gets a whole word.
+    fn store_operand_as(&mut self, operand: &Operand, value: u32, width: Width) -> Result<(), Errors> {
+        use OperandAddressingMode::*;
+        if let Some(record) = &mut self.record {
+            record.stores.push(value);
+        }
+        match operand.addressing_mode {
+            ConstantZero => Ok(()),
+            Constant1Byte(_) |
+            Constant2Bytes(_) |
+            Constant4Bytes(_) => Err(Errors::StoreToConstant),
+            ContentOfAddress1Byte(addr) |
+            ContentOfAddress2Bytes(addr) |
+            ContentOfAddress4Bytes(addr) => self.memory.set(addr, value, width).map_err(Errors::MemoryError),
+            Stack => self.stack.push(value & width.mask()).map_err(Errors::StackError),
+            CallFrameLocalAtAddress1Byte(offset) |
+            CallFrameLocalAtAddress2Bytes(offset) |
+            CallFrameLocalAtAddress4Bytes(offset) => self.stack.set_local(offset, width.bytes(), value).map_err(Errors::StackError),
+            ContentOfRAMAddress1Byte(addr) |
+            ContentOfRAMAddress2Bytes(addr) |
+            ContentOfRAMAddress4Bytes(addr) => self.memory.set_ram(addr, value, width).map_err(Errors::MemoryError),
+            __Unused1 | __Unused2 => Err(Errors::FetchOperation(format!("Invalid addressing mode {:?}", operand.addressing_mode))),
+        }
+    }
+
+    // Executes one instruction and tells where that left the story: still running,
+    // waiting for input, paused by DEBUGTRAP or over.
+    pub fn step(&mut self) -> Result<ExecState, Errors> {
+        // Once the story is over there's nothing left to execute.
+        if self.has_halted() { return Ok(self.state) }
+        // Stepping on is how a story stopped by DEBUGTRAP resumes.
+        if matches!(self.state, ExecState::Paused(PauseReason::DebugTrap(_))) {
+            self.state = ExecState::Running;
+        }
+        if self.config.catch_panics {
+            // The VM may be left half-updated by the panic, so it shouldn't be resumed afterwards.
+            let pc = self.pc;
+            panic::catch_unwind(AssertUnwindSafe(|| self.execute_step()))
+                .unwrap_or(Err(Errors::InternalPanic { pc }))?;
+        } else {
+            self.execute_step()?;
+        }
+
+        if self.state == ExecState::Running && self.glk.is_waiting() {
+            self.state = ExecState::Paused(PauseReason::AwaitingInput);
+        }
+        Ok(self.state)
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    fn execute_step(&mut self) -> Result<(), Errors> {
+        let pc = self.pc;
+        let (operation, next) = Operation::fetch(self.memory.as_bytes(), pc)?;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(pc, opcode = ?operation.code, "execute");
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer(pc, &operation);
+        }
+        self.pc = next;
+
+        let width = operation.code.data_width();
+        let mut loads = Vec::with_capacity(operation.operands.len());
+        for operand in operation.operands.iter().filter(|operand| operand.operand_mode == OperandMode::Load) {
+            loads.push(self.load_operand_as(operand, width)?);
+        }
+        let stores: Vec<&Operand> = operation.operands.iter()
+            .filter(|operand| operand.operand_mode == OperandMode::Store)
+            .collect();
+        if self.recording {
+            self.record = Some(trace::TraceRecord { pc, opcode: operation.code, loads: loads.clone(), stores: Vec::new() });
+        }
+
+        let result = self.execute(operation.code, &loads, &stores).map_err(|err| match err {
+            Errors::StackError(StackError::Overflow) => self.stack_overflow(),
+            err => err,
+        });
+
+        self.stats.instructions += 1;
+        self.stats.peak_stack = self.stats.peak_stack.max(self.stack.sp());
+        self.stats.peak_memory = self.stats.peak_memory.max(self.memory.size());
+        result
+    }
+
+    fn stack_overflow(&self) -> Errors {
+        Errors::StackOverflow {
+            depth: self.stack.depth(),
+            limit: self.stack.capacity(),
+            function: self.frame_functions.get(&self.stack.fp()).copied(),
+        }
+    }
+
+    // Why the last MALLOC that returned 0 failed.
+    pub fn last_allocation_failure(&self) -> Option<heap::AllocationFailure> {
+        self.last_allocation_failure
+    }
+
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*, test_support::{function, glk, op, push, story, Arg}};
+
+    #[test]
+    fn config_options_take_effect() {
+        let code = function(&[], 0xC1, &[op(0x20, &[Arg::Const(-1)])]);
+        let mut image = story(&code, &[]);
+        image[4..8].copy_from_slice(&0x0004_0000u32.to_be_bytes());
+        let config = TerpConfig { strict: true, cycle_limit: Some(3), ..Default::default() };
+
+        assert!(matches!(GlulxTerp::from_bytes_with_config(image.clone(), config.clone()), Err(Errors::MemoryError(MemoryError::InvalidHeader(_)))));
+        assert!(GlulxTerp::from_bytes(image).is_ok());
+
+        let mut terp = GlulxTerp::from_bytes_with_config(story(&code, &[]), config).unwrap();
+        assert!(matches!(terp.run(), Err(Errors::CycleLimitReached(3))));
+        assert_eq!(terp.stats().instructions, 3);
+    }
+
+    #[test]
+    fn start_func_past_end_mem_fails_to_load() {
+        let mut image = story(&[0xC1, 0, 0], &[]);
+        image[24..28].copy_from_slice(&0x5000u32.to_be_bytes());
+        assert!(matches!(GlulxTerp::from_bytes(image), Err(Errors::MemoryError(MemoryError::InvalidHeader(_)))));
+
+        let mut image = story(&[0xC1, 0, 0], &[]);
+        image[28..32].copy_from_slice(&0x200u32.to_be_bytes());
+        assert!(matches!(GlulxTerp::from_bytes(image), Err(Errors::MemoryError(MemoryError::InvalidHeader(_)))));
+    }
+
+    struct PanickingBackend;
+
+    impl GlkBackend for PanickingBackend {
+        fn put_char(&mut self, _window: u32, _ch: char) {
+            panic!("put_char")
+        }
+    }
+
+    #[test]
+    fn panics_are_reported_with_the_pc() {
+        let setup = [
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Stack),
+            glk(0x2F, vec![Arg::Stack], Arg::Zero),
+            push(Arg::Const('A' as i32)),
+        ].concat();
+        let code = function(&[], 0xC1, &[setup.clone(), op(0x130, &[Arg::Const(0x80), Arg::Const(1), Arg::Zero])]);
+        let config = TerpConfig { catch_panics: true, ..Default::default() };
+        let mut terp = GlulxTerp::from_bytes_with_config(story(&code, &[]), config).unwrap();
+        terp.set_glk_backend(Box::new(PanickingBackend));
+
+        let err = loop {
+            if let Err(err) = terp.step() { break err }
+        };
+        assert!(matches!(err, Errors::InternalPanic { pc } if pc == 0x43 + setup.len() as u32), "{err:?}");
+    }
+
+    #[test]
+    fn stats_count_a_small_loop() {
+        // l0 = 3, then decrement it until it reaches 0, the jnz going back over the sub.
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x10, &[Arg::Const(3), Arg::Zero, Arg::Local(0)]),
+            op(0x11, &[Arg::Local(0), Arg::Const(1), Arg::Local(0)]),
+            op(0x23, &[Arg::Local(0), Arg::Const(-8)]),
+            push(Arg::Const(1)),
+            push(Arg::Const(2)),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let start = *terp.stats();
+        assert_eq!(terp.run().unwrap(), ExecState::Halted);
+
+        let stats = terp.stats();
+        assert_eq!(stats.instructions, 10);
+        assert_eq!(stats.peak_stack, start.peak_stack + 8);
+        assert_eq!(stats.peak_memory, 0x200);
+        assert_eq!(stats.allocations, 0);
+    }
+
+    #[test]
+    fn poke_then_peek_a_global() {
+        use memory::Width;
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[0; 0x10])).unwrap();
+        terp.poke(0x104, 0x1234_5678, Width::Word).unwrap();
+        assert_eq!(terp.peek(0x104, Width::Word).unwrap(), 0x1234_5678);
+        assert_eq!(terp.peek(0x104, Width::Short).unwrap(), 0x1234);
+        terp.poke(0x107, 0xAB, Width::Byte).unwrap();
+        assert_eq!(terp.peek(0x104, Width::Word).unwrap(), 0x1234_56AB);
+    }
+
+    #[test]
+    fn poke_into_rom_is_rejected() {
+        use memory::Width;
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[])).unwrap();
+        assert!(matches!(terp.poke(0x40, 1, Width::Byte), Err(MemoryError::WriteToRom(0x40))));
+        assert_eq!(terp.peek(0x40, Width::Byte).unwrap(), 0xC1);
+        assert!(matches!(terp.peek(0x1FE, Width::Word), Err(MemoryError::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn save_resumes_in_a_fresh_terp() {
+        // Saves into a memory stream at 0x300, between two writes to 0x104.
+        let code = function(&[(4, 2)], 0xC1, &[
+            glk(0x43, vec![Arg::Const(0x300), Arg::Const(0x400), Arg::Const(1), Arg::Zero], Arg::Local(0)),
+            op(0x4C, &[Arg::Const(0x104), Arg::Zero, Arg::Const(7)]),
+            op(0x123, &[Arg::Local(0), Arg::Local(4)]),
+            op(0x4C, &[Arg::Const(0x104), Arg::Zero, Arg::Const(99)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let image = story(&code, &[0; 0x700]);
+        let mut terp = GlulxTerp::from_bytes(image.clone()).unwrap();
+        while terp.memory.get_u32(0x300).unwrap() == 0 {
+            terp.step().unwrap();
+        }
+        assert_eq!(terp.stack.get_local(4, 4).unwrap(), 0);
+        let len = terp.memory.get_u32(0x304).unwrap() + 8;
+        let save: Vec<u8> = (0x300..0x300 + len).map(|addr| terp.memory.get_u8(addr).unwrap()).collect();
+
+        let mut resumed = GlulxTerp::from_bytes_and_save(image.clone(), &save).unwrap();
+        assert_eq!(resumed.stack.get_local(4, 4).unwrap(), u32::MAX);
+        assert_eq!(resumed.memory.get_u32(0x104).unwrap(), 7);
+        assert_eq!(resumed.run().unwrap(), ExecState::Halted);
+        assert_eq!(resumed.memory.get_u32(0x104).unwrap(), 99);
+
+        assert!(matches!(GlulxTerp::from_bytes_and_save(image, b"FORM\0\0\0\x04IFRS"), Err(Errors::Quetzal(quetzal::QuetzalError::NotQuetzal))));
+    }
+
+    #[test]
+    fn stack_overflow_tells_where_it_happened() {
+        // main calls itself forever.
+        let code = function(&[(4, 1)], 0xC1, &[op(0x160, &[Arg::Const(0x40), Arg::Zero])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let err = loop {
+            if let Err(err) = terp.step() { break err }
+        };
+        let Errors::StackOverflow { depth, limit, function } = err else { panic!("{err:?}") };
+        assert_eq!((limit, function), (0x1000, Some(0x40)));
+        assert!(depth > 100, "{depth}");
+    }
+
+    #[test]
+    fn failed_malloc_is_recorded() {
+        let code = function(&[], 0xC1, &[op(0x178, &[Arg::Const(0x200), Arg::Stack])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        assert_eq!(terp.last_allocation_failure(), None);
+
+        // A heap right below 4GiB has no room for the block.
+        let mut heap = Heap::new(0xFFFF_FF00);
+        heap.allocate(0x10);
+        terp.heap = Some(heap);
+        terp.step().unwrap();
+        assert_eq!(terp.stack.pop().unwrap(), 0);
+        assert_eq!(terp.last_allocation_failure(), Some(heap::AllocationFailure { requested: 0x200, memory_size: 0x200, function: Some(0x40) }));
+    }
+
+    #[test]
+    fn every_addressing_mode_loads_and_stores() {
+        use OperandAddressingMode::*;
+        let operand = |addressing_mode| Operand { operand_mode: OperandMode::Load, addressing_mode };
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[(4, 2)], 0xC1, &[]), &[0; 0x10])).unwrap();
+
+        for (mode, expected) in [
+            (ConstantZero, 0),
+            (Constant1Byte(0xFF), u32::MAX),
+            (Constant1Byte(0x7F), 0x7F),
+            (Constant2Bytes(0x8000), 0xFFFF_8000),
+            (Constant4Bytes(0x8000_0000), 0x8000_0000),
+        ] {
+            assert_eq!(terp.load_operand_as(&operand(mode), Width::Word).unwrap(), expected, "{mode:?}");
+        }
+        assert!(matches!(terp.store_operand(&operand(Constant1Byte(1)), 1), Err(Errors::StoreToConstant)));
+        terp.store_operand(&operand(ConstantZero), 1).unwrap();
+
+        // Each memory or local mode reads back what it stored, RAM modes count from ram_start.
+        for (mode, value) in [
+            (ContentOfAddress1Byte(0x104), 1),
+            (ContentOfAddress4Bytes(0x108), 2),
+            (ContentOfRAMAddress2Bytes(0x0C), 3),
+            (CallFrameLocalAtAddress1Byte(4), 4),
+        ] {
+            terp.store_operand(&operand(mode), value).unwrap();
+            assert_eq!(terp.load_operand_as(&operand(mode), Width::Word).unwrap(), value, "{mode:?}");
+        }
+        assert_eq!(terp.memory.get_u32(0x10C).unwrap(), 3);
+        assert_eq!(terp.stack.get_local(4, 4).unwrap(), 4);
+
+        // The stack pops what was pushed, last in first out.
+        let sp = terp.stack.sp();
+        for value in [5, 6] {
+            terp.store_operand(&operand(Stack), value).unwrap();
+        }
+        assert_eq!(terp.load_operand_as(&operand(Stack), Width::Word).unwrap(), 6);
+        assert_eq!(terp.load_operand_as(&operand(Stack), Width::Word).unwrap(), 5);
+        assert_eq!(terp.stack.sp(), sp);
+        assert!(matches!(terp.load_operand_as(&operand(Stack), Width::Word), Err(Errors::StackError(_))));
+        assert!(matches!(terp.load_operand_as(&operand(__Unused1), Width::Word), Err(Errors::FetchOperation(_))));
+    }
+
+    #[test]
+    fn call_passes_arguments_and_stores_the_result() {
+        // main pushes 5 and 7 and calls the C0 function at 0x80 with them. That one
+        // tail calls the C1 function at 0xA0, whose result lands in main's l0.
+        let mut code = function(&[(4, 1)], 0xC1, &[
+            push(Arg::Const(7)),
+            push(Arg::Const(5)),
+            op(0x30, &[Arg::Const(0x80), Arg::Const(2), Arg::Local(0)]),
+            op(0x31, &[Arg::Local(0)]),
+        ]);
+        code.resize(0x40, 0);
+        code.extend(function(&[], 0xC0, &[op(0x34, &[Arg::Const(0xA0), Arg::Stack])]));
+        code.resize(0x60, 0);
+        code.extend(function(&[(4, 2)], 0xC1, &[
+            op(0x11, &[Arg::Local(0), Arg::Local(4), Arg::Stack]),
+            op(0x31, &[Arg::Stack]),
+        ]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        for _ in 0..3 { terp.step().unwrap(); }
+        assert_eq!((terp.stack.depth(), terp.pc), (2, 0x83));
+
+        // The tail call replaces the C0 frame, the C1 one gets the arguments as locals.
+        terp.step().unwrap();
+        assert_eq!((terp.stack.depth(), terp.pc), (2, 0xA5));
+        assert_eq!((terp.stack.get_local(0, 4).unwrap(), terp.stack.get_local(4, 4).unwrap()), (5, 7));
+
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.depth(), 1);
+        assert_eq!(terp.stack.get_local(0, 4).unwrap() as i32, -2);
+        assert_eq!(terp.run().unwrap(), ExecState::Halted);
+    }
+
+    #[test]
+    fn header_binds_arguments_by_function_type() {
+        // Two byte locals, then a word local aligned to offset 4.
+        let code = function(&[(1, 2), (4, 1)], 0xC1, &[op(0x31, &[Arg::Zero])]);
+        let terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let header = terp.function_header(0x40).unwrap();
+        assert_eq!(header, FunctionHeader { kind: 0xC1, format: vec![(1, 2), (4, 1)], body: 0x47 });
+
+        let mut stack = Stack::new(0x100);
+        stack.push_frame(&header.format).unwrap();
+        header.bind_args(&mut stack, &[0x101, 2, 3, 4]).unwrap();
+        assert_eq!([0, 1, 4].map(|offset| stack.get_local(offset, if offset == 4 { 4 } else { 1 }).unwrap()), [1, 2, 3]);
+        assert_eq!(stack.count(), 0);
+
+        let stack_args = FunctionHeader { kind: 0xC0, format: Vec::new(), body: 0 };
+        let mut stack = Stack::new(0x100);
+        stack.push_frame(&[]).unwrap();
+        stack_args.bind_args(&mut stack, &[5, 7]).unwrap();
+        assert_eq!([0, 1, 2].map(|depth| stack.peek(depth).unwrap()), [2, 5, 7]);
+    }
+
+    #[test]
+    fn restart_reloads_ram_but_keeps_the_protected_range() {
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x40, &[Arg::Const(5), Arg::Addr(0x100)]),
+            op(0x40, &[Arg::Const(6), Arg::Addr(0x184)]),
+            op(0x127, &[Arg::Const(0x184), Arg::Const(4)]),
+            op(0x178, &[Arg::Const(16), Arg::Local(0)]),
+            op(0x149, &[Arg::Const(1), Arg::Const(0x100)]),
+            push(Arg::Const(9)),
+            op(0x122, &[]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[1, 2, 3, 4])).unwrap();
+        let sp = terp.stack.sp();
+        for _ in 0..6 { terp.step().unwrap(); }
+        assert_eq!(terp.memory.size(), 0x300);
+
+        terp.step().unwrap();
+        assert_eq!(terp.memory.bytes(0x100, 4).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(terp.memory.get_u32(0x184).unwrap(), 6);
+        assert!(terp.heap.is_none());
+        assert_eq!(terp.memory.size(), 0x200);
+        assert_eq!(terp.io_system(), Default::default());
+        assert_eq!((terp.stack.sp(), terp.pc), (sp, 0x45));
+    }
+
+    #[test]
+    fn bad_checksum_loads_unless_verification_is_asked_for() {
+        let mut image = story(&function(&[(4, 1)], 0xC1, &[op(0x121, &[Arg::Local(0)])]), &[]);
+        image[0x150] ^= 1;
+        let mut terp = GlulxTerp::from_bytes(image.clone()).unwrap();
+        terp.step().unwrap();
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 1);
+
+        let config = TerpConfig { verify_checksum: true, ..Default::default() };
+        assert!(matches!(GlulxTerp::from_bytes_with_config(image, config), Err(Errors::MemoryError(MemoryError::BadChecksum))));
+    }
+}
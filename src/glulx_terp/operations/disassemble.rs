@@ -0,0 +1,258 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt};
+
+use crate::glulx_terp::{Errors, GlulxTerp};
+
+use super::{OPCode, OperandAddressingMode, OperandMode, Operation};
+
+// Value of a constant operand, sign-extended like load_operand does.
+// Anything else is only known at runtime.
+fn constant(mode: OperandAddressingMode) -> Option<u32> {
+    use OperandAddressingMode::*;
+    match mode {
+        ConstantZero => Some(0),
+        Constant1Byte(value) => Some(value as u8 as i8 as u32),
+        Constant2Bytes(value) => Some(value as u16 as i16 as u32),
+        Constant4Bytes(value) => Some(value),
+        _ => None
+    }
+}
+
+impl OPCode {
+    // Opcodes whose last load operand is a branch offset.
+    fn is_branch(self) -> bool {
+        use OPCode::*;
+        matches!(self,
+            JUMP | JZ | JNZ | JEQ | JNE | JLT | JGE | JGT | JLE | JLTU | JGEU | JGTU | JLEU |
+            JFEQ | JFNE | JFLT | JFLE | JFGT | JFGE | JISNAN | JISINF |
+            JDEQ | JDNE | JDLT | JDLE | JDGT | JDGE | JDISNAN | JDISINF |
+            CATCH)
+    }
+
+    // Opcodes whose first load operand is the address of a function.
+    fn is_call(self) -> bool {
+        use OPCode::*;
+        matches!(self, CALL | TAILCALL | CALLF | CALLFI | CALLFII | CALLFIII)
+    }
+
+    // Execution never falls through to the next instruction.
+    fn ends_block(self) -> bool {
+        use OPCode::*;
+        matches!(self, JUMP | JUMPABS | RETURN | TAILCALL | THROW | QUIT | RESTART)
+    }
+}
+
+// An entry of a linear listing, see GlulxTerp::linear_listing.
+#[derive(Debug)]
+pub enum Listing {
+    Function { addr: u32, kind: u8 },
+    Instruction { addr: u32, operation: Operation },
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Function { addr, kind } => write!(f, "{addr:08x}: function {kind:02x}"),
+            Self::Instruction { addr, operation } => write!(f, "{addr:08x}:     {operation}"),
+        }
+    }
+}
+
+// Walks one function in address order, see GlulxTerp::function_instructions.
+struct FunctionInstructions<'a> {
+    terp: &'a GlulxTerp,
+    // None once the walk is over.
+    pc: Option<u32>,
+    // Furthest address a branch seen so far jumps to.
+    furthest_target: u32,
+    // Error that stops the walk before the first instruction.
+    header_error: Option<Errors>,
+}
+
+impl Iterator for FunctionInstructions<'_> {
+    type Item = Result<(u32, Operation), Errors>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.header_error.take() {
+            return Some(Err(err))
+        }
+        let pc = self.pc.take()?;
+
+        let (operation, next) = match Operation::fetch(self.terp.memory.as_bytes(), pc) {
+            Ok(fetched) => fetched,
+            Err(err) => return Some(Err(err)),
+        };
+
+        if operation.code.is_branch() {
+            let offset = operation.operands.iter()
+                .rfind(|operand| operand.operand_mode == OperandMode::Load)
+                .and_then(|operand| constant(operand.addressing_mode));
+            if let Some(offset) = offset.filter(|offset| *offset > 1) {
+                self.furthest_target = self.furthest_target.max(next.wrapping_add(offset).wrapping_sub(2));
+            }
+        }
+        // The function ends at a terminal instruction no branch jumps past.
+        if !operation.code.ends_block() || self.furthest_target >= next {
+            self.pc = Some(next);
+        }
+        Some(Ok((pc, operation)))
+    }
+}
+
+impl GlulxTerp {
+    // Decodes the function at `addr` one instruction at a time, without building the
+    // whole listing. Glulx doesn't record where a function ends, so the walk stops at
+    // the first RETURN (or other terminal instruction) that no branch seen so far
+    // jumps past. A decode error is yielded and ends the walk.
+    pub fn function_instructions(&self, addr: u32) -> impl Iterator<Item = Result<(u32, Operation), Errors>> + '_ {
+        let (pc, header_error) = match self.function_header(addr) {
+            Ok(header) => (Some(header.body), None),
+            Err(err) => (None, Some(err)),
+        };
+        FunctionInstructions { terp: self, pc, furthest_target: 0, header_error }
+    }
+
+    // Decodes memory from `start` on as one function after another, the way compilers
+    // lay out code. There's no telling code from data this way, so the listing ends at
+    // the first byte that doesn't decode, returned with its address and the error.
+    pub fn linear_listing(&self, start: u32) -> (Vec<Listing>, u32, Errors) {
+        let mut listing = Vec::new();
+        let mut pc = start;
+        loop {
+            match self.memory.get_u8(pc) {
+                Ok(kind @ (0xC0 | 0xC1)) => match self.function_header(pc) {
+                    Ok(header) => {
+                        listing.push(Listing::Function { addr: pc, kind });
+                        pc = header.body;
+                        continue
+                    }
+                    Err(err) => return (listing, pc, err),
+                },
+                Ok(_) => {}
+                Err(err) => return (listing, pc, Errors::MemoryError(err)),
+            }
+
+            match Operation::fetch(self.memory.as_bytes(), pc) {
+                Ok((operation, next)) => {
+                    listing.push(Listing::Instruction { addr: pc, operation });
+                    pc = next;
+                }
+                Err(err) => return (listing, pc, err),
+            }
+        }
+    }
+
+    // Decodes every instruction reachable from start_func by following constant call
+    // targets and branch destinations. Calls and jumps through a computed address
+    // can't be resolved statically, so the code behind them is only found if it's
+    // reachable some other way. The addresses of such calls come second, in order.
+    pub fn disassemble_reachable(&self) -> (Vec<(u32, Operation)>, Vec<u32>) {
+        let mut code: BTreeMap<u32, Operation> = BTreeMap::new();
+        let mut indirect_calls: BTreeSet<u32> = BTreeSet::new();
+        let mut visited: BTreeSet<u32> = BTreeSet::new();
+        let mut functions = vec![self.memory.get_header().start_func];
+
+        while let Some(function) = functions.pop() {
+            if !visited.insert(function) { continue }
+            let Ok(header) = self.function_header(function) else { continue };
+
+            let mut blocks = vec![header.body];
+            while let Some(mut pc) = blocks.pop() {
+                while !code.contains_key(&pc) {
+                    let Ok((operation, next)) = Operation::fetch(self.memory.as_bytes(), pc) else { break };
+
+                    let loads: Vec<Option<u32>> = operation.operands.iter()
+                        .filter(|operand| operand.operand_mode == OperandMode::Load)
+                        .map(|operand| constant(operand.addressing_mode))
+                        .collect();
+
+                    if operation.code.is_call() {
+                        match loads.first().copied().flatten() {
+                            Some(target) => functions.push(target),
+                            None => { indirect_calls.insert(pc); }
+                        }
+                    }
+                    if operation.code == OPCode::JUMPABS {
+                        blocks.extend(loads.first().copied().flatten());
+                    }
+                    // Offsets 0 and 1 return from the function instead of jumping.
+                    if operation.code.is_branch() {
+                        if let Some(offset) = loads.last().copied().flatten().filter(|offset| *offset > 1) {
+                            blocks.push(next.wrapping_add(offset).wrapping_sub(2));
+                        }
+                    }
+
+                    let ends_block = operation.code.ends_block();
+                    code.insert(pc, operation);
+                    if ends_block { break }
+                    pc = next;
+                }
+            }
+        }
+
+        (code.into_iter().collect(), indirect_calls.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listing;
+    use crate::glulx_terp::{operations::OPCode, test_support::{function, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn reachable_code_includes_called_functions() {
+        // main calls the function at 0x60 and the one in l0, then branches over `return 1`.
+        let mut code = function(&[(4, 1)], 0xC1, &[
+            op(0x160, &[Arg::Const(0x60), Arg::Zero]),
+            op(0x160, &[Arg::Local(0), Arg::Zero]),
+            op(0x22, &[Arg::Local(0), Arg::Const(5)]),
+            op(0x31, &[Arg::Const(1)]),
+            op(0x31, &[Arg::Const(0)]),
+        ]);
+        code.resize(0x20, 0);
+        code.extend(function(&[], 0xC0, &[op(0x31, &[Arg::Const(7)])]));
+        let terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+
+        let (reachable, indirect_calls) = terp.disassemble_reachable();
+        let addrs: Vec<u32> = reachable.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(addrs, [0x45, 0x49, 0x4D, 0x51, 0x54, 0x63]);
+        let codes: Vec<OPCode> = reachable.iter().map(|(_, operation)| operation.code).collect();
+        assert_eq!(codes, [OPCode::CALLF, OPCode::CALLF, OPCode::JZ, OPCode::RETURN, OPCode::RETURN, OPCode::RETURN]);
+        assert_eq!(indirect_calls, [0x49]);
+    }
+
+    #[test]
+    fn function_instructions_run_past_a_skipped_return() {
+        // The jz branches over the first return, so only the second one ends the
+        // function, not the next function's header after it.
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x22, &[Arg::Local(0), Arg::Const(5)]),
+            op(0x31, &[Arg::Const(1)]),
+            op(0x10, &[Arg::Const(2), Arg::Const(3), Arg::Local(0)]),
+            op(0x31, &[Arg::Const(0)]),
+            function(&[], 0xC1, &[op(0x31, &[Arg::Zero])]),
+        ]);
+        let terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let instructions: Vec<_> = terp.function_instructions(0x40).collect::<Result<_, _>>().unwrap();
+        let codes: Vec<OPCode> = instructions.iter().map(|(_, operation)| operation.code).collect();
+        assert_eq!(codes, [OPCode::JZ, OPCode::RETURN, OPCode::ADD, OPCode::RETURN]);
+        assert_eq!(instructions[0].0, 0x45);
+
+        let mut not_a_function = terp.function_instructions(0x41);
+        assert!(not_a_function.next().unwrap().is_err());
+        assert!(not_a_function.next().is_none());
+    }
+
+    #[test]
+    fn listing_renders_each_operand_mode() {
+        let code = function(&[(4, 3)], 0xC1, &[
+            op(0x10, &[Arg::Const(0x1A), Arg::Local(8), Arg::Addr(0x1000)]),
+            op(0x22, &[Arg::Const(-3), Arg::Stack]),
+            vec![0x05],
+        ]);
+        let terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let (listing, stop, _) = terp.linear_listing(0x40);
+        let text: Vec<String> = listing.iter().map(Listing::to_string).collect();
+        assert_eq!(text, ["00000040: function c1", "00000045:     add #1a Fr:8 ->*1000", "0000004e:     jz #-3 sp"]);
+        assert_eq!(stop, 0x51);
+    }
+}
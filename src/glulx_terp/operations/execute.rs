@@ -0,0 +1,977 @@
+use crate::glulx_terp::{exec::{ExecState, PauseReason}, iosys::IoSystem, memory::Width, Errors, GlulxTerp};
+
+use super::{search::Search, OPCode, Operand};
+
+// Floats are single precision, their bits passed as is in a word.
+fn decode_float(value: u32) -> f32 {
+    f32::from_bits(value)
+}
+
+fn encode_float(value: f32) -> u32 {
+    value.to_bits()
+}
+
+// FTONUMZ, FTONUMN and their double versions: out of range values clamp to the nearest
+// end of the i32 range, which `as` does for us, and NaN goes to the end matching its sign bit.
+// Every float is exactly representable as a double, so both go through here.
+fn clamp_to_int(value: f64) -> u32 {
+    if value.is_nan() {
+        return if value.is_sign_negative() { 0x8000_0000 } else { 0x7FFF_FFFF }
+    }
+    value as i32 as u32
+}
+
+// JFEQ: within `tolerance` of each other. A NaN tolerance never matches, and two
+// infinities are only equal if they have the same sign, whatever the tolerance.
+fn floats_equal(x: f32, y: f32, tolerance: f32) -> bool {
+    if tolerance.is_nan() { return false }
+    if x.is_infinite() && y.is_infinite() { return x == y }
+    (x - y).abs() <= tolerance.abs()
+}
+
+// JDEQ, with the same rules as floats_equal.
+fn doubles_equal(x: f64, y: f64, tolerance: f64) -> bool {
+    if tolerance.is_nan() { return false }
+    if x.is_infinite() && y.is_infinite() { return x == y }
+    (x - y).abs() <= tolerance.abs()
+}
+
+// ALOADBIT and ASTOREBIT: bit 0 is the lowest bit of the byte at `base`, and a
+// negative index counts back from there, bit -1 being the top bit of the byte before.
+fn bit_address(base: u32, index: u32) -> (u32, u32) {
+    let index = index as i32;
+    (base.wrapping_add(index.div_euclid(8) as u32), index.rem_euclid(8) as u32)
+}
+
+// Doubles are passed as two words, high word first.
+fn decode_double(hi: u32, lo: u32) -> f64 {
+    f64::from_bits(((hi as u64) << 32) | lo as u64)
+}
+
+fn encode_double(value: f64) -> (u32, u32) {
+    let bits = value.to_bits();
+    ((bits >> 32) as u32, bits as u32)
+}
+
+impl GlulxTerp {
+    fn store_float(&mut self, store: &Operand, value: f32) -> Result<(), Errors> {
+        self.store_operand(store, encode_float(value))
+    }
+
+    // Results go to S1 (low word) then S2 (high word), so pushing both leaves the
+    // high word on top, ready to be popped back as the first operand of a pair.
+    fn store_double(&mut self, stores: &[&Operand], value: f64) -> Result<(), Errors> {
+        let (hi, lo) = encode_double(value);
+        self.store_operand(stores[0], lo)?;
+        self.store_operand(stores[1], hi)
+    }
+
+    // Branch offsets 0 and 1 return that value from the current function instead of jumping.
+    fn branch(&mut self, offset: u32) -> Result<(), Errors> {
+        match offset {
+            0 | 1 => self.return_from_function(offset),
+            _ => {
+                self.pc = self.pc.wrapping_add(offset).wrapping_sub(2);
+                Ok(())
+            }
+        }
+    }
+
+    fn branch_if(&mut self, condition: bool, offset: u32) -> Result<(), Errors> {
+        if condition { self.branch(offset) } else { Ok(()) }
+    }
+
+    pub(in crate::glulx_terp) fn execute(&mut self, code: OPCode, loads: &[u32], stores: &[&Operand]) -> Result<(), Errors> {
+        match code {
+            // 2.1. Integer Math
+            // Results are truncated to 32 bits, which is the same for signed and unsigned
+            // values. Wrapping ops keep debug builds from panicking on overflow.
+            OPCode::ADD => self.store_operand(stores[0], loads[0].wrapping_add(loads[1])),
+            OPCode::SUB => self.store_operand(stores[0], loads[0].wrapping_sub(loads[1])),
+            OPCode::MUL => self.store_operand(stores[0], loads[0].wrapping_mul(loads[1])),
+            OPCode::NEG => self.store_operand(stores[0], loads[0].wrapping_neg()),
+            // Signed, rounding toward zero: -7/2 is -3 and -7%2 is -1, the remainder
+            // takes the sign of the dividend.
+            OPCode::DIV |
+            OPCode::MOD => {
+                let (x, y) = (loads[0] as i32, loads[1] as i32);
+                if y == 0 { return Err(Errors::DivisionByZero) }
+                let value = if code == OPCode::DIV { x.wrapping_div(y) } else { x.wrapping_rem(y) };
+                self.store_operand(stores[0], value as u32)
+            }
+            OPCode::BITAND => self.store_operand(stores[0], loads[0] & loads[1]),
+            OPCode::BITOR => self.store_operand(stores[0], loads[0] | loads[1]),
+            OPCode::BITXOR => self.store_operand(stores[0], loads[0] ^ loads[1]),
+            OPCode::BITNOT => self.store_operand(stores[0], !loads[0]),
+            // Shift counts are unsigned and anything from 32 up shifts every bit out,
+            // which Rust's shift operators don't allow.
+            OPCode::SHIFTL => {
+                let value = if loads[1] >= 32 { 0 } else { loads[0] << loads[1] };
+                self.store_operand(stores[0], value)
+            }
+            OPCode::USHIFTR => {
+                let value = if loads[1] >= 32 { 0 } else { loads[0] >> loads[1] };
+                self.store_operand(stores[0], value)
+            }
+            OPCode::SSHIFTR => {
+                let value = (loads[0] as i32) >> loads[1].min(31);
+                self.store_operand(stores[0], value as u32)
+            }
+
+            // 2.2. Branches
+            // The offset is always the last operand. Signed comparisons see the operands
+            // as i32, the U variants as u32.
+            OPCode::JUMP => self.branch(loads[0]),
+            OPCode::JZ => self.branch_if(loads[0] == 0, loads[1]),
+            OPCode::JNZ => self.branch_if(loads[0] != 0, loads[1]),
+            OPCode::JEQ => self.branch_if(loads[0] == loads[1], loads[2]),
+            OPCode::JNE => self.branch_if(loads[0] != loads[1], loads[2]),
+            OPCode::JLT => self.branch_if((loads[0] as i32) < loads[1] as i32, loads[2]),
+            OPCode::JGE => self.branch_if(loads[0] as i32 >= loads[1] as i32, loads[2]),
+            OPCode::JGT => self.branch_if(loads[0] as i32 > loads[1] as i32, loads[2]),
+            OPCode::JLE => self.branch_if(loads[0] as i32 <= loads[1] as i32, loads[2]),
+            OPCode::JLTU => self.branch_if(loads[0] < loads[1], loads[2]),
+            OPCode::JGEU => self.branch_if(loads[0] >= loads[1], loads[2]),
+            OPCode::JGTU => self.branch_if(loads[0] > loads[1], loads[2]),
+            OPCode::JLEU => self.branch_if(loads[0] <= loads[1], loads[2]),
+            // An absolute address, 0 and 1 aren't special here.
+            OPCode::JUMPABS => {
+                self.pc = loads[0];
+                Ok(())
+            }
+
+            // 2.3. Moving Data
+            // COPYS and COPYB got their value at their width, and store it the same way.
+            OPCode::COPY => self.store_operand(stores[0], loads[0]),
+            OPCode::COPYS => self.store_operand_as(stores[0], loads[0], Width::Short),
+            OPCode::COPYB => self.store_operand_as(stores[0], loads[0], Width::Byte),
+            OPCode::SEXS => self.store_operand(stores[0], loads[0] as u16 as i16 as u32),
+            OPCode::SEXB => self.store_operand(stores[0], loads[0] as u8 as i8 as u32),
+
+            // 2.4. Array Data
+            // Indices are signed, so wrapping arithmetic lets negative ones address before the array.
+            OPCode::ALOAD => {
+                let addr = loads[0].wrapping_add(loads[1].wrapping_mul(4));
+                let value = self.memory.get_u32(addr).map_err(Errors::MemoryError)?;
+                self.store_operand(stores[0], value)
+            }
+            OPCode::ALOADS => {
+                let addr = loads[0].wrapping_add(loads[1].wrapping_mul(2));
+                let value = self.memory.get_u16(addr).map_err(Errors::MemoryError)?;
+                self.store_operand(stores[0], value as u32)
+            }
+            OPCode::ALOADB => {
+                let value = self.memory.get_u8(loads[0].wrapping_add(loads[1])).map_err(Errors::MemoryError)?;
+                self.store_operand(stores[0], value as u32)
+            }
+            OPCode::ALOADBIT => {
+                let (addr, bit) = bit_address(loads[0], loads[1]);
+                let value = self.memory.get_u8(addr).map_err(Errors::MemoryError)?;
+                self.store_operand(stores[0], (value >> bit & 1) as u32)
+            }
+            OPCode::ASTORE => {
+                let addr = loads[0].wrapping_add(loads[1].wrapping_mul(4));
+                self.memory.set_u32(addr, loads[2]).map_err(Errors::MemoryError)
+            }
+            OPCode::ASTORES => {
+                let addr = loads[0].wrapping_add(loads[1].wrapping_mul(2));
+                self.memory.set_u16(addr, loads[2] as u16).map_err(Errors::MemoryError)
+            }
+            OPCode::ASTOREB => self.memory.set_u8(loads[0].wrapping_add(loads[1]), loads[2] as u8).map_err(Errors::MemoryError),
+            OPCode::ASTOREBIT => {
+                let (addr, bit) = bit_address(loads[0], loads[1]);
+                let byte = self.memory.get_u8(addr).map_err(Errors::MemoryError)?;
+                let byte = if loads[2] != 0 { byte | 1 << bit } else { byte & !(1 << bit) };
+                self.memory.set_u8(addr, byte).map_err(Errors::MemoryError)
+            }
+
+            // 2.5. The Stack
+            // Operands have already been popped, so the count reflects the stack after them.
+            OPCode::STKCOUNT => self.store_operand(stores[0], self.stack.count()),
+            OPCode::STKPEEK => {
+                let value = self.stack.peek(loads[0]).map_err(Errors::StackError)?;
+                self.store_operand(stores[0], value)
+            }
+            OPCode::STKSWAP => self.stack.swap().map_err(Errors::StackError),
+            OPCode::STKROLL => self.stack.roll(loads[0], loads[1] as i32).map_err(Errors::StackError),
+            OPCode::STKCOPY => self.stack.copy(loads[0]).map_err(Errors::StackError),
+
+            // 2.6. Functions
+            // CALL passes its arguments on the stack, first argument on top.
+            OPCode::CALL => {
+                let mut args = Vec::with_capacity(loads[1] as usize);
+                for _ in 0..loads[1] {
+                    args.push(self.stack.pop().map_err(Errors::StackError)?);
+                }
+                self.call_function(loads[0], &args, stores[0])
+            }
+            OPCode::CALLF |
+            OPCode::CALLFI |
+            OPCode::CALLFII |
+            OPCode::CALLFIII => self.call_function(loads[0], &loads[1..], stores[0]),
+            OPCode::RETURN => self.return_from_function(loads[0]),
+            OPCode::TAILCALL => {
+                let mut args = Vec::with_capacity(loads[1] as usize);
+                for _ in 0..loads[1] {
+                    args.push(self.stack.pop().map_err(Errors::StackError)?);
+                }
+                self.tail_call(loads[0], &args)
+            }
+
+            // 2.7. Continuations
+            // The stub stays on the stack, THROW pops it and resumes right after the CATCH.
+            OPCode::CATCH => {
+                self.push_call_stub(stores[0])?;
+                let token = self.stack.sp();
+                self.store_operand(stores[0], token)?;
+                self.branch(loads[0])
+            }
+            OPCode::THROW => {
+                let token = loads[1];
+                if !self.stack.is_catch_token(token) { return Err(Errors::InvalidCatchToken(token)) }
+                let stub = self.stack.unwind_to(token).map_err(Errors::StackError)?;
+                self.resume_from_stub(stub, loads[0])
+            }
+
+            // 2.8. Memory Map
+            OPCode::GETMEMSIZE => self.store_operand(stores[0], self.memory.size()),
+            // Memory can't be resized while the heap is in use.
+            OPCode::SETMEMSIZE => {
+                let resized = self.heap.is_none() && self.memory.set_size(loads[0]).is_ok();
+                self.store_operand(stores[0], !resized as u32)
+            }
+
+            // 2.9. Memory Allocation Heap
+            OPCode::MALLOC => {
+                let addr = self.malloc(loads[0])?;
+                self.store_operand(stores[0], addr)
+            }
+            OPCode::MFREE => self.mfree(loads[0]),
+
+            // 2.10. Game State
+            OPCode::QUIT => {
+                self.state = ExecState::Terminated;
+                Ok(())
+            }
+            // The stub pushed for S1 is saved along with the stack, it's what RESTORE resumes from.
+            OPCode::SAVE => {
+                self.push_call_stub(stores[0])?;
+                let state = self.save_state();
+                self.stack.pop_call_stub().map_err(Errors::StackError)?;
+
+                let data = self.save_to_bytes(&state);
+                let saved = self.glk.write_bytes(&mut self.memory, loads[0], &data).is_ok();
+                self.store_operand(stores[0], !saved as u32)
+            }
+            // On success execution continues after the SAVE, S1 only gets 1 on failure.
+            OPCode::RESTORE => {
+                let restored = match self.glk.read_bytes(&self.memory, loads[0]) {
+                    Ok(data) => self.restore_from_bytes(&data).is_ok(),
+                    Err(_) => false,
+                };
+                if restored { Ok(()) } else { self.store_operand(stores[0], 1) }
+            }
+
+            // Same as SAVE and RESTORE, with the states kept in memory.
+            OPCode::SAVEUNDO => {
+                self.push_call_stub(stores[0])?;
+                let saved = self.save_undo();
+                self.stack.pop_call_stub().map_err(Errors::StackError)?;
+                self.store_operand(stores[0], !saved as u32)
+            }
+            OPCode::RESTOREUNDO => {
+                if self.restore_undo()? { Ok(()) } else { self.store_operand(stores[0], 1) }
+            }
+            OPCode::HASUNDO => self.store_operand(stores[0], !self.has_undo() as u32),
+            OPCode::DISCARDUNDO => {
+                self.discard_undo();
+                Ok(())
+            }
+            OPCode::RESTART => self.restart(),
+            // Only one range is protected at a time, a length of 0 removes it.
+            OPCode::PROTECT => {
+                self.protected = (loads[1] != 0).then_some((loads[0], loads[1]));
+                Ok(())
+            }
+            // Checks the image as loaded, so it passes however RAM changed since.
+            OPCode::VERIFY => {
+                let valid = self.memory.checksum() == self.memory.get_header().checksum;
+                self.store_operand(stores[0], !valid as u32)
+            }
+
+            // 2.11. Output
+            OPCode::STREAMCHAR => self.stream_char(loads[0] & 0xFF),
+            OPCode::STREAMUNICHAR => self.stream_char(loads[0]),
+            OPCode::STREAMNUM => self.stream_num(loads[0], 0, false),
+            OPCode::STREAMSTR => self.stream_str(loads[0]),
+            OPCode::GETIOSYS => {
+                let iosys = self.io_system();
+                self.store_operand(stores[0], iosys.mode)?;
+                self.store_operand(stores[1], iosys.rock)
+            }
+            OPCode::SETIOSYS => {
+                self.set_io_system(IoSystem::new(loads[0], loads[1]));
+                Ok(())
+            }
+            OPCode::GETSTRINGTBL => self.store_operand(stores[0], self.string_table),
+            OPCode::SETSTRINGTBL => {
+                self.string_table = loads[0];
+                Ok(())
+            }
+
+            // 2.12. Floating-Point Math
+            OPCode::NUMTOF => self.store_float(stores[0], loads[0] as i32 as f32),
+            OPCode::FTONUMZ => self.store_operand(stores[0], clamp_to_int(decode_float(loads[0]).trunc() as f64)),
+            // `round` goes away from zero on halves, as the spec wants.
+            OPCode::FTONUMN => self.store_operand(stores[0], clamp_to_int(decode_float(loads[0]).round() as f64)),
+            OPCode::CEIL => self.store_float(stores[0], decode_float(loads[0]).ceil()),
+            OPCode::FLOOR => self.store_float(stores[0], decode_float(loads[0]).floor()),
+            OPCode::FADD => self.store_float(stores[0], decode_float(loads[0]) + decode_float(loads[1])),
+            OPCode::FSUB => self.store_float(stores[0], decode_float(loads[0]) - decode_float(loads[1])),
+            OPCode::FMUL => self.store_float(stores[0], decode_float(loads[0]) * decode_float(loads[1])),
+            OPCode::FDIV => self.store_float(stores[0], decode_float(loads[0]) / decode_float(loads[1])),
+            // Same as DMODR and DMODQ at once, remainder to S1 and quotient to S2.
+            OPCode::FMOD => {
+                let (x, y) = (decode_float(loads[0]), decode_float(loads[1]));
+                let remainder = x % y;
+                let quotient = (x - remainder) / y;
+                let negative = x.is_sign_negative() ^ y.is_sign_negative();
+                self.store_float(stores[0], remainder)?;
+                self.store_float(stores[1], quotient.abs().copysign(if negative { -1.0 } else { 1.0 }))
+            }
+            OPCode::SQRT => self.store_float(stores[0], decode_float(loads[0]).sqrt()),
+            OPCode::EXP => self.store_float(stores[0], decode_float(loads[0]).exp()),
+            OPCode::LOG => self.store_float(stores[0], decode_float(loads[0]).ln()),
+            OPCode::POW => self.store_float(stores[0], decode_float(loads[0]).powf(decode_float(loads[1]))),
+            OPCode::SIN => self.store_float(stores[0], decode_float(loads[0]).sin()),
+            OPCode::COS => self.store_float(stores[0], decode_float(loads[0]).cos()),
+            OPCode::TAN => self.store_float(stores[0], decode_float(loads[0]).tan()),
+            OPCode::ASIN => self.store_float(stores[0], decode_float(loads[0]).asin()),
+            OPCode::ACOS => self.store_float(stores[0], decode_float(loads[0]).acos()),
+            OPCode::ATAN => self.store_float(stores[0], decode_float(loads[0]).atan()),
+            OPCode::ATAN2 => self.store_float(stores[0], decode_float(loads[0]).atan2(decode_float(loads[1]))),
+
+            // 2.13. Double-Precision Math
+            // Doubles are loaded as pairs of operands, high word first.
+            OPCode::NUMTOD => self.store_double(stores, loads[0] as i32 as f64),
+            OPCode::DTONUMZ => self.store_operand(stores[0], clamp_to_int(decode_double(loads[0], loads[1]).trunc())),
+            OPCode::DTONUMN => self.store_operand(stores[0], clamp_to_int(decode_double(loads[0], loads[1]).round())),
+            OPCode::FTOD => self.store_double(stores, decode_float(loads[0]) as f64),
+            OPCode::DTOF => self.store_float(stores[0], decode_double(loads[0], loads[1]) as f32),
+            OPCode::DCEIL => self.store_double(stores, decode_double(loads[0], loads[1]).ceil()),
+            OPCode::DFLOOR => self.store_double(stores, decode_double(loads[0], loads[1]).floor()),
+            OPCode::DADD => self.store_double(stores, decode_double(loads[0], loads[1]) + decode_double(loads[2], loads[3])),
+            OPCode::DSUB => self.store_double(stores, decode_double(loads[0], loads[1]) - decode_double(loads[2], loads[3])),
+            OPCode::DMUL => self.store_double(stores, decode_double(loads[0], loads[1]) * decode_double(loads[2], loads[3])),
+            OPCode::DDIV => self.store_double(stores, decode_double(loads[0], loads[1]) / decode_double(loads[2], loads[3])),
+            OPCode::DMODR |
+            OPCode::DMODQ => {
+                let x = decode_double(loads[0], loads[1]);
+                let y = decode_double(loads[2], loads[3]);
+                // Rust's `%` truncates the quotient like C's fmod, so the remainder keeps the sign of X.
+                let remainder = x % y;
+                let result = if code == OPCode::DMODR {
+                    remainder
+                } else {
+                    // The quotient is exact, its sign (even for zero) is the sign of X/Y.
+                    let quotient = (x - remainder) / y;
+                    let negative = x.is_sign_negative() ^ y.is_sign_negative();
+                    quotient.abs().copysign(if negative { -1.0 } else { 1.0 })
+                };
+                self.store_double(stores, result)
+            }
+            OPCode::DSQRT => self.store_double(stores, decode_double(loads[0], loads[1]).sqrt()),
+            OPCode::DEXP => self.store_double(stores, decode_double(loads[0], loads[1]).exp()),
+            OPCode::DLOG => self.store_double(stores, decode_double(loads[0], loads[1]).ln()),
+            OPCode::DPOW => self.store_double(stores, decode_double(loads[0], loads[1]).powf(decode_double(loads[2], loads[3]))),
+            OPCode::DSIN => self.store_double(stores, decode_double(loads[0], loads[1]).sin()),
+            OPCode::DCOS => self.store_double(stores, decode_double(loads[0], loads[1]).cos()),
+            OPCode::DTAN => self.store_double(stores, decode_double(loads[0], loads[1]).tan()),
+            OPCode::DASIN => self.store_double(stores, decode_double(loads[0], loads[1]).asin()),
+            OPCode::DACOS => self.store_double(stores, decode_double(loads[0], loads[1]).acos()),
+            OPCode::DATAN => self.store_double(stores, decode_double(loads[0], loads[1]).atan()),
+            OPCode::DATAN2 => self.store_double(stores, decode_double(loads[0], loads[1]).atan2(decode_double(loads[2], loads[3]))),
+
+            // 2.14. Floating-Point Comparisons
+            // Comparisons with NaN are all false, so only JFNE branches on it.
+            OPCode::JFEQ => {
+                let equal = floats_equal(decode_float(loads[0]), decode_float(loads[1]), decode_float(loads[2]));
+                self.branch_if(equal, loads[3])
+            }
+            OPCode::JFNE => {
+                let equal = floats_equal(decode_float(loads[0]), decode_float(loads[1]), decode_float(loads[2]));
+                self.branch_if(!equal, loads[3])
+            }
+            OPCode::JFLT => self.branch_if(decode_float(loads[0]) < decode_float(loads[1]), loads[2]),
+            OPCode::JFLE => self.branch_if(decode_float(loads[0]) <= decode_float(loads[1]), loads[2]),
+            OPCode::JFGT => self.branch_if(decode_float(loads[0]) > decode_float(loads[1]), loads[2]),
+            OPCode::JFGE => self.branch_if(decode_float(loads[0]) >= decode_float(loads[1]), loads[2]),
+            OPCode::JISNAN => self.branch_if(decode_float(loads[0]).is_nan(), loads[1]),
+            OPCode::JISINF => self.branch_if(decode_float(loads[0]).is_infinite(), loads[1]),
+
+            // 2.15. Double-Precision Comparisons
+            OPCode::JDEQ => {
+                let equal = doubles_equal(decode_double(loads[0], loads[1]), decode_double(loads[2], loads[3]), decode_double(loads[4], loads[5]));
+                self.branch_if(equal, loads[6])
+            }
+            OPCode::JDNE => {
+                let equal = doubles_equal(decode_double(loads[0], loads[1]), decode_double(loads[2], loads[3]), decode_double(loads[4], loads[5]));
+                self.branch_if(!equal, loads[6])
+            }
+            OPCode::JDLT => self.branch_if(decode_double(loads[0], loads[1]) < decode_double(loads[2], loads[3]), loads[4]),
+            OPCode::JDLE => self.branch_if(decode_double(loads[0], loads[1]) <= decode_double(loads[2], loads[3]), loads[4]),
+            OPCode::JDGT => self.branch_if(decode_double(loads[0], loads[1]) > decode_double(loads[2], loads[3]), loads[4]),
+            OPCode::JDGE => self.branch_if(decode_double(loads[0], loads[1]) >= decode_double(loads[2], loads[3]), loads[4]),
+            OPCode::JDISNAN => self.branch_if(decode_double(loads[0], loads[1]).is_nan(), loads[2]),
+            OPCode::JDISINF => self.branch_if(decode_double(loads[0], loads[1]).is_infinite(), loads[2]),
+
+            // 2.16. Random Number Generator
+            OPCode::RANDOM => {
+                let value = self.rng.random(loads[0]);
+                self.store_operand(stores[0], value)
+            }
+            OPCode::SETRANDOM => {
+                self.rng.seed(loads[0]);
+                Ok(())
+            }
+
+            // 2.17. Block Copy and Clear
+            OPCode::MZERO => self.memory.zero(loads[1], loads[0]).map_err(Errors::MemoryError),
+            OPCode::MCOPY => self.memory.copy(loads[1], loads[2], loads[0]).map_err(Errors::MemoryError),
+
+            // 2.18. Searching
+            OPCode::LINEARSEARCH => {
+                let search = Search { key: loads[0], key_size: loads[1], key_offset: loads[5], options: loads[6] };
+                let result = self.linear_search(&search, loads[2], loads[3], loads[4])?;
+                self.store_operand(stores[0], result)
+            }
+            OPCode::BINARYSEARCH => {
+                let search = Search { key: loads[0], key_size: loads[1], key_offset: loads[5], options: loads[6] };
+                let result = self.binary_search(&search, loads[2], loads[3], loads[4])?;
+                self.store_operand(stores[0], result)
+            }
+            OPCode::LINKEDSEARCH => {
+                let search = Search { key: loads[0], key_size: loads[1], key_offset: loads[3], options: loads[5] };
+                let result = self.linked_search(&search, loads[2], loads[4])?;
+                self.store_operand(stores[0], result)
+            }
+
+            // 2.19. Accelerated Functions
+            OPCode::ACCELFUNC => {
+                self.accel.set_function(loads[0], loads[1]);
+                Ok(())
+            }
+            OPCode::ACCELPARAM => {
+                self.accel.set_param(loads[0], loads[1]);
+                Ok(())
+            }
+
+            // 2.20. Miscellaneous
+            OPCode::NOP => Ok(()),
+            OPCode::GESTALT => self.store_operand(stores[0], self.gestalt(loads[0], loads[1])),
+            // Execution stops right after the trap, and goes on from there when run again.
+            OPCode::DEBUGTRAP => {
+                self.state = ExecState::Paused(PauseReason::DebugTrap(loads[0]));
+                Ok(())
+            }
+            // Glk arguments are passed on the stack, first argument on top.
+            OPCode::GLK => {
+                let mut args = Vec::with_capacity(loads[1] as usize);
+                for _ in 0..loads[1] {
+                    args.push(self.stack.pop().map_err(Errors::StackError)?);
+                }
+                let result = self.glk.dispatch(&mut self.memory, loads[0], &args).map_err(Errors::Glk)?;
+                self.store_operand(stores[0], result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glulx_terp::{config::TerpConfig, exec::ExecState, memory::Width, test_support::{function, op, push, story, Arg}, Errors, GlulxTerp, MemoryError};
+
+    fn aload(array: i32, index: i32, dest: Arg) -> Vec<u8> {
+        op(0x48, &[Arg::Const(array), Arg::Const(index), dest])
+    }
+
+    // Words 10, 20, 30 and 40 from the start of RAM.
+    fn array_story(body: &[Vec<u8>]) -> GlulxTerp {
+        let ram: Vec<u8> = [10u32, 20, 30, 40].iter().flat_map(|word| word.to_be_bytes()).collect();
+        GlulxTerp::from_bytes(story(&function(&[], 0xC1, body), &ram)).unwrap()
+    }
+
+    fn aload_at(index: i32) -> u32 {
+        let mut terp = array_story(&[aload(0x104, index, Arg::Stack)]);
+        terp.step().unwrap();
+        terp.stack.pop().unwrap()
+    }
+
+    #[test]
+    fn aload_positive_index() {
+        assert_eq!(aload_at(2), 40);
+    }
+
+    #[test]
+    fn aload_zero_index() {
+        assert_eq!(aload_at(0), 20);
+    }
+
+    #[test]
+    fn aload_negative_index_reads_before_the_array() {
+        assert_eq!(aload_at(-1), 10);
+    }
+
+    #[test]
+    fn astore_then_aload_round_trips() {
+        let mut terp = array_story(&[
+            op(0x4C, &[Arg::Const(0x108), Arg::Const(-2), Arg::Const(0x1234_5678)]),
+            aload(0x108, -2, Arg::Stack),
+        ]);
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.memory.get_u32(0x100).unwrap(), 0x1234_5678);
+        assert_eq!(terp.stack.pop().unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn copys_and_copyb_move_only_their_width() {
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x40, &[Arg::Const(-1), Arg::Local(0)]),
+            op(0x41, &[Arg::Addr(0x100), Arg::Stack]),
+            op(0x42, &[Arg::Addr(0x101), Arg::Addr(0x108)]),
+            op(0x41, &[Arg::Const(-2), Arg::Addr(0x10C)]),
+            op(0x42, &[Arg::Stack, Arg::Local(3)]),
+            op(0x00, &[]),
+            op(0x42, &[Arg::Const(0x1FF), Arg::Stack]),
+        ]);
+        let mut ram = vec![0xAA; 0x10];
+        ram[..4].copy_from_slice(&0x1234_5678_u32.to_be_bytes());
+        let mut terp = GlulxTerp::from_bytes(story(&code, &ram)).unwrap();
+
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), u32::MAX);
+        assert_eq!(terp.stack.peek(0).unwrap(), 0x1234);
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.memory.get_u32(0x108).unwrap(), 0x34AA_AAAA);
+        assert_eq!(terp.memory.get_u32(0x10C).unwrap(), 0xFFFE_AAAA);
+        // The popped 0x1234 only changes the last byte of the local.
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 0xFFFF_FF34);
+        assert_eq!(terp.stack.count(), 0);
+        terp.step().unwrap();
+        assert_eq!(terp.stack.pop().unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn sexs_and_sexb_sign_extend_the_low_bits() {
+        assert_eq!(unary_op(0x44, 0x8001), 0xFFFF_8001);
+        assert_eq!(unary_op(0x44, 0xABCD_7FFF), 0x7FFF);
+        assert_eq!(unary_op(0x45, 0x80), 0xFFFF_FF80);
+        assert_eq!(unary_op(0x45, 0x1234_567F), 0x7F);
+    }
+
+    // The two halves of a double as constants, high word first like the opcodes take them.
+    fn double(value: f64) -> [Arg; 2] {
+        let bits = value.to_bits();
+        [Arg::Const((bits >> 32) as i32), Arg::Const(bits as i32)]
+    }
+
+    // Runs a two-operand double opcode and reads back its result, stored low word first.
+    fn double_op(code: u32, a: f64, b: f64) -> f64 {
+        let ([a_hi, a_lo], [b_hi, b_lo]) = (double(a), double(b));
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(code, &[a_hi, a_lo, b_hi, b_lo, Arg::Stack, Arg::Stack])]), &[])).unwrap();
+        terp.step().unwrap();
+        let hi = terp.stack.pop().unwrap() as u64;
+        let lo = terp.stack.pop().unwrap() as u64;
+        f64::from_bits(hi << 32 | lo)
+    }
+
+    #[test]
+    fn dmodr_and_dmodq_recombine() {
+        for (a, b) in [(7.5, 2.0), (-7.5, 2.0), (7.5, -2.0), (1e10, 3.3), (-0.5, 3.0)] {
+            let remainder = double_op(0x214, a, b);
+            let quotient = double_op(0x215, a, b);
+            assert_eq!(quotient, quotient.trunc(), "{a} / {b}");
+            assert!((quotient * b + remainder - a).abs() < 1e-6, "{a} / {b}");
+        }
+        assert_eq!(double_op(0x214, 7.5, 2.0), 1.5);
+        assert_eq!(double_op(0x215, -7.5, 2.0), -3.0);
+    }
+
+    #[test]
+    fn double_arithmetic_and_conversions() {
+        assert_eq!(double_op(0x210, 1.25, 2.5), 3.75);
+        assert_eq!(double_op(0x213, 1.0, 0.0), f64::INFINITY);
+
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(0x200, &[Arg::Const(-3), Arg::Stack, Arg::Stack])]), &[])).unwrap();
+        terp.step().unwrap();
+        let hi = terp.stack.pop().unwrap() as u64;
+        let lo = terp.stack.pop().unwrap() as u64;
+        assert_eq!(f64::from_bits(hi << 32 | lo), -3.0);
+    }
+
+    // The function at 0x80 returns 1 if JDEQ takes its branch, 5 otherwise.
+    fn jdeq(a: f64, b: f64, tolerance: f64) -> u32 {
+        let mut code = function(&[(4, 1)], 0xC1, &[op(0x160, &[Arg::Const(0x80), Arg::Local(0)]), op(0x31, &[Arg::Zero])]);
+        code.resize(0x40, 0);
+        let ([a_hi, a_lo], [b_hi, b_lo], [t_hi, t_lo]) = (double(a), double(b), double(tolerance));
+        code.extend(function(&[], 0xC1, &[
+            op(0x230, &[a_hi, a_lo, b_hi, b_lo, t_hi, t_lo, Arg::Const(1)]),
+            op(0x31, &[Arg::Const(5)]),
+        ]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        for _ in 0..3 { terp.step().unwrap(); }
+        terp.stack.get_local(0, 4).unwrap()
+    }
+
+    #[test]
+    fn jdeq_branches_within_the_tolerance() {
+        assert_eq!(jdeq(1.0, 1.05, 0.1), 1);
+        assert_eq!(jdeq(1.0, 1.05, -0.1), 1);
+        assert_eq!(jdeq(1.0, 1.05, 0.01), 5);
+        assert_eq!(jdeq(1.0, 1.0, f64::NAN), 5);
+        assert_eq!(jdeq(f64::INFINITY, f64::INFINITY, 0.0), 1);
+        assert_eq!(jdeq(f64::INFINITY, f64::NEG_INFINITY, f64::INFINITY), 5);
+    }
+
+    // Runs `code a b -> sp` and returns the result.
+    fn binary_op(code: u32, a: u32, b: u32) -> u32 {
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(code, &[Arg::Const(a as i32), Arg::Const(b as i32), Arg::Stack])]), &[])).unwrap();
+        terp.step().unwrap();
+        terp.stack.pop().unwrap()
+    }
+
+    #[test]
+    fn arithmetic_wraps_on_overflow() {
+        assert_eq!(binary_op(0x12, 0x10000, 0x10000), 0);
+        assert_eq!(binary_op(0x12, i32::MAX as u32, 2), 0xFFFF_FFFE);
+        assert_eq!(binary_op(0x12, u32::MAX, u32::MAX), 1);
+        assert_eq!(binary_op(0x12, i32::MIN as u32, u32::MAX), 0x8000_0000);
+        assert_eq!(binary_op(0x10, u32::MAX, 2), 1);
+        assert_eq!(binary_op(0x11, i32::MIN as u32, 1), 0x7FFF_FFFF);
+        assert_eq!(unary_op(0x15, 0x8000_0000), 0x8000_0000);
+    }
+
+    #[test]
+    fn div_and_mod_truncate_toward_zero() {
+        let word = |value: i32| value as u32;
+        assert_eq!(binary_op(0x13, word(-7), 2), word(-3));
+        assert_eq!(binary_op(0x14, word(-7), 2), word(-1));
+        assert_eq!(binary_op(0x13, 7, word(-2)), word(-3));
+        assert_eq!(binary_op(0x14, 7, word(-2)), 1);
+        assert_eq!(binary_op(0x13, word(i32::MIN), word(-1)), word(i32::MIN));
+        assert_eq!(binary_op(0x14, word(i32::MIN), word(-1)), 0);
+        assert_eq!(binary_op(0x18, 0x0F0F, 0x00FF), 0x0F);
+        assert_eq!(binary_op(0x1A, 0x0F0F, 0x00FF), 0x0FF0);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        for code in [0x13, 0x14] {
+            let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(code, &[Arg::Const(1), Arg::Zero, Arg::Stack])]), &[])).unwrap();
+            assert!(matches!(terp.step(), Err(Errors::DivisionByZero)));
+        }
+    }
+
+    fn unary_op(code: u32, a: u32) -> u32 {
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[op(code, &[Arg::Const(a as i32), Arg::Stack])]), &[])).unwrap();
+        terp.step().unwrap();
+        terp.stack.pop().unwrap()
+    }
+
+    #[test]
+    fn float_arithmetic_and_conversions() {
+        let float = |result: u32| f32::from_bits(result);
+        assert_eq!(float(binary_op(0x1A0, 1.5f32.to_bits(), 2.25f32.to_bits())), 3.75);
+
+        // FTONUMZ truncates and clamps, NaN goes to the positive maximum.
+        for (value, expected) in [(-2.7f32, -2), (1e10, i32::MAX), (f32::NEG_INFINITY, i32::MIN), (f32::NAN, i32::MAX)] {
+            assert_eq!(unary_op(0x191, value.to_bits()) as i32, expected, "{value}");
+        }
+        assert_eq!(unary_op(0x192, (-2.5f32).to_bits()) as i32, -3);
+
+        // ATAN2 takes y first and follows the quadrant of (x, y).
+        for (y, x) in [(1.0f32, 1.0f32), (1.0, -1.0), (-1.0, -1.0), (-1.0, 1.0)] {
+            let angle = float(binary_op(0x1B6, y.to_bits(), x.to_bits()));
+            assert!((angle - y.atan2(x)).abs() < 1e-6, "atan2({y}, {x}) = {angle}");
+        }
+    }
+
+    #[test]
+    fn fmod_stores_the_remainder_and_the_quotient() {
+        let code = function(&[], 0xC1, &[op(0x1A4, &[Arg::Const((-7.0f32).to_bits() as i32), Arg::Const(2.0f32.to_bits() as i32), Arg::Stack, Arg::Stack])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.step().unwrap();
+        assert_eq!(f32::from_bits(terp.stack.pop().unwrap()), -3.0);
+        assert_eq!(f32::from_bits(terp.stack.pop().unwrap()), -1.0);
+    }
+
+    #[test]
+    fn shifts_of_32_or_more_clear_the_value() {
+        for (count, expected) in [(31, 0x8000_0000), (32, 0), (33, 0), (u32::MAX, 0)] {
+            assert_eq!(binary_op(0x1C, 1, count), expected, "shiftl by {count}");
+        }
+        assert_eq!(binary_op(0x1E, 0x8000_0000, 31), 1);
+        assert_eq!(binary_op(0x1E, 0x8000_0000, 32), 0);
+        assert_eq!(binary_op(0x1D, 0x8000_0000, 1), 0xC000_0000);
+        assert_eq!(binary_op(0x1D, 0x8000_0000, 40), u32::MAX);
+        assert_eq!(binary_op(0x1D, 0x4000_0000, 40), 0);
+    }
+
+    #[test]
+    fn branches_compare_signed_or_unsigned() {
+        // -1 < 1 signed skips the first add, unsigned it doesn't skip the second.
+        let code = function(&[(4, 2)], 0xC1, &[
+            op(0x26, &[Arg::Const(-1), Arg::Const(1), Arg::Const(7)]),
+            op(0x10, &[Arg::Const(1), Arg::Zero, Arg::Local(0)]),
+            op(0x2A, &[Arg::Const(-1), Arg::Const(1), Arg::Const(7)]),
+            op(0x10, &[Arg::Const(2), Arg::Zero, Arg::Local(4)]),
+            op(0x22, &[Arg::Zero, Arg::Zero]),
+            op(0x31, &[Arg::Const(1)]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.step().unwrap();
+        assert_eq!(terp.pc, 0x50);
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 0);
+        assert_eq!(terp.stack.get_local(4, 4).unwrap(), 2);
+
+        // An offset of 0 returns 0 from main instead of jumping.
+        assert_eq!(terp.step().unwrap(), ExecState::Halted);
+    }
+
+    #[test]
+    fn throw_to_a_returned_frame_is_rejected() {
+        // The function at 0x80 stores a catch token at 0x100 and returns normally,
+        // main then throws to it.
+        let mut code = function(&[(4, 1)], 0xC1, &[
+            op(0x160, &[Arg::Const(0x80), Arg::Local(0)]),
+            op(0x33, &[Arg::Const(9), Arg::Addr(0x100)]),
+        ]);
+        code.resize(0x40, 0);
+        code.extend(function(&[], 0xC1, &[
+            op(0x32, &[Arg::Addr(0x100), Arg::Const(2)]),
+            op(0x31, &[Arg::Const(5)]),
+        ]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 4])).unwrap();
+        for _ in 0..3 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 5);
+
+        let token = terp.memory.get_u32(0x100).unwrap();
+        assert!(matches!(terp.step(), Err(Errors::InvalidCatchToken(stale)) if stale == token));
+    }
+
+    #[test]
+    fn throw_resumes_after_the_catch_with_the_value() {
+        // Main catches into 0x104 and passes the token to the function at 0x80, which throws 9.
+        let mut code = function(&[], 0xC1, &[
+            op(0x32, &[Arg::Addr(0x104), Arg::Const(2)]),
+            op(0x161, &[Arg::Const(0x80), Arg::Addr(0x104), Arg::Stack]),
+        ]);
+        code.resize(0x40, 0);
+        code.extend(function(&[(4, 1)], 0xC1, &[op(0x33, &[Arg::Const(9), Arg::Local(0)])]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 8])).unwrap();
+        let sp = terp.stack.sp();
+
+        terp.step().unwrap();
+        assert_eq!(terp.memory.get_u32(0x104).unwrap(), terp.stack.sp());
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.memory.get_u32(0x104).unwrap(), 9);
+        assert_eq!(terp.pc, 0x4A);
+        assert_eq!(terp.stack.sp(), sp);
+    }
+
+    // The values above the current frame, bottom first.
+    fn stack_values(terp: &GlulxTerp) -> Vec<u32> {
+        (0..terp.stack.count()).rev().map(|depth| terp.stack.peek(depth).unwrap()).collect()
+    }
+
+    #[test]
+    fn stack_opcodes_roll_copy_and_peek() {
+        let mut body: Vec<Vec<u8>> = (1..=5).map(|value| push(Arg::Const(value))).collect();
+        body.extend([
+            op(0x53, &[Arg::Const(3), Arg::Const(1)]),
+            op(0x53, &[Arg::Const(3), Arg::Const(-1)]),
+            op(0x53, &[Arg::Const(4), Arg::Const(6)]),
+            op(0x54, &[Arg::Const(2)]),
+            op(0x52, &[]),
+            op(0x51, &[Arg::Const(4), Arg::Stack]),
+            op(0x50, &[Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &body), &[])).unwrap();
+        for _ in 0..5 { terp.step().unwrap(); }
+
+        let expect = |terp: &mut GlulxTerp, values: &[u32]| {
+            terp.step().unwrap();
+            assert_eq!(stack_values(terp), values);
+        };
+        expect(&mut terp, &[1, 2, 5, 3, 4]);
+        expect(&mut terp, &[1, 2, 3, 4, 5]);
+        expect(&mut terp, &[1, 4, 5, 2, 3]);
+        expect(&mut terp, &[1, 4, 5, 2, 3, 2, 3]);
+        expect(&mut terp, &[1, 4, 5, 2, 3, 3, 2]);
+        expect(&mut terp, &[1, 4, 5, 2, 3, 3, 2, 5]);
+        expect(&mut terp, &[1, 4, 5, 2, 3, 3, 2, 5, 8]);
+    }
+
+    #[test]
+    fn stack_opcodes_stay_inside_the_frame() {
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[push(Arg::Const(1)), op(0x54, &[Arg::Const(2)])]), &[])).unwrap();
+        terp.step().unwrap();
+        assert!(terp.step().is_err());
+    }
+
+    #[test]
+    fn narrow_loads_zero_extend_and_bits_count_back_from_the_base() {
+        let mut terp = array_story(&[
+            op(0x4C, &[Arg::Const(0x100), Arg::Zero, Arg::Const(0xFFFE_8001_u32 as i32)]),
+            op(0x49, &[Arg::Const(0x100), Arg::Const(1), Arg::Stack]),
+            op(0x4A, &[Arg::Const(0x100), Arg::Zero, Arg::Stack]),
+            op(0x4F, &[Arg::Const(0x108), Arg::Const(-1), Arg::Const(1)]),
+            op(0x4B, &[Arg::Const(0x108), Arg::Const(-1), Arg::Stack]),
+            op(0x4B, &[Arg::Const(0x108), Arg::Const(-9), Arg::Stack]),
+        ]);
+        for _ in 0..6 { terp.step().unwrap(); }
+        assert_eq!(stack_values(&terp), [0x8001, 0xFF, 1, 0]);
+        // Word 20 at 0x104 got the top bit of its last byte set.
+        assert_eq!(terp.memory.get_u32(0x104).unwrap(), 0x94);
+    }
+
+    #[test]
+    fn array_stores_respect_rom() {
+        let mut terp = array_story(&[op(0x4E, &[Arg::Const(0x10), Arg::Zero, Arg::Const(1)])]);
+        assert!(matches!(terp.step(), Err(Errors::MemoryError(MemoryError::WriteToRom(0x10)))));
+    }
+
+    // Executes one instruction, which must leave the pc right after it.
+    fn run_one(instruction: Vec<u8>) -> GlulxTerp {
+        let next = 0x43 + instruction.len() as u32;
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &[instruction]), &[])).unwrap();
+        terp.step().unwrap();
+        assert_eq!(terp.pc, next);
+        terp
+    }
+
+    #[test]
+    fn malloc_of_zero_returns_null() {
+        let mut terp = run_one(op(0x178, &[Arg::Zero, Arg::Stack]));
+        assert_eq!(terp.stack.pop().unwrap(), 0);
+        assert_eq!(terp.stats().allocations, 0);
+        assert_eq!(terp.memory.size(), 0x200);
+    }
+
+    #[test]
+    fn failed_first_malloc_leaves_memory_resizable() {
+        // Too big for memory, so the heap that would hold it is never set up.
+        let code = function(&[], 0xC1, &[
+            op(0x178, &[Arg::Const(0x7FFF_FFF0), Arg::Stack]),
+            op(0x103, &[Arg::Const(0x300), Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.step().unwrap();
+        terp.step().unwrap();
+        assert_eq!(terp.stack.pop().unwrap(), 0);
+        assert_eq!(terp.stack.pop().unwrap(), 0);
+        assert_eq!(terp.memory.size(), 0x300);
+        assert!(terp.heap.is_none());
+    }
+
+    #[test]
+    fn malloc_stops_at_the_heap_limit() {
+        let code = function(&[], 0xC1, &[
+            op(0x178, &[Arg::Const(0x20), Arg::Stack]),
+            op(0x178, &[Arg::Const(0x10), Arg::Stack]),
+            op(0x178, &[Arg::Const(0x9), Arg::Stack]),
+            op(0x178, &[Arg::Const(0x8), Arg::Stack]),
+        ]);
+        let config = TerpConfig { heap_limit: Some(0x18), ..Default::default() };
+        let mut terp = GlulxTerp::from_bytes_with_config(story(&code, &[]), config).unwrap();
+        for _ in 0..4 { terp.step().unwrap(); }
+        assert_eq!([(); 4].map(|_| terp.stack.pop().unwrap()), [0x210, 0, 0x200, 0]);
+    }
+
+    #[test]
+    fn mzero_of_zero_bytes_ignores_the_address() {
+        run_one(op(0x170, &[Arg::Zero, Arg::Const(0x7FFF_FFF0)]));
+    }
+
+    #[test]
+    fn mcopy_of_zero_bytes_ignores_the_addresses() {
+        let terp = run_one(op(0x171, &[Arg::Zero, Arg::Const(0x7FFF_FFF0), Arg::Const(0x40)]));
+        assert_eq!(terp.memory.get_u8(0x40).unwrap(), 0xC1);
+    }
+
+    #[test]
+    fn checksum_is_read_only_and_verify_still_passes() {
+        let code = function(&[], 0xC1, &[
+            op(0x4C, &[Arg::Const(0x100), Arg::Zero, Arg::Const(1234)]),
+            op(0x121, &[Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 4])).unwrap();
+        let checksum = terp.memory.get_u32(32).unwrap();
+        assert!(matches!(terp.poke(32, 0, Width::Word), Err(MemoryError::WriteToRom(32))));
+        assert!(matches!(terp.poke(35, 0, Width::Byte), Err(MemoryError::WriteToRom(35))));
+        assert_eq!(terp.memory.get_u32(32).unwrap(), checksum);
+
+        // RAM has changed by the time VERIFY runs, it checks the image as loaded.
+        for _ in 0..2 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.pop().unwrap(), 0);
+    }
+
+    #[test]
+    fn setmemsize_grows_shrinks_and_rejects() {
+        // Grows to 0x400 and back to 0x300, then asks for an unaligned size, one below
+        // end_mem, and one below the end of the heap, which has grown memory to 0x500.
+        let body = [
+            op(0x103, &[Arg::Const(0x400), Arg::Local(0)]),
+            op(0x102, &[Arg::Local(4)]),
+            op(0x103, &[Arg::Const(0x300), Arg::Local(8)]),
+            op(0x103, &[Arg::Const(0x310), Arg::Local(12)]),
+            op(0x103, &[Arg::Const(0x100), Arg::Local(16)]),
+            op(0x178, &[Arg::Const(0x180), Arg::Stack]),
+            op(0x103, &[Arg::Const(0x300), Arg::Local(20)]),
+            op(0x102, &[Arg::Local(24)]),
+        ];
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[(4, 7)], 0xC1, &body), &[0; 0x10])).unwrap();
+        for _ in 0..body.len() { terp.step().unwrap(); }
+        let locals: Vec<u32> = (0..7).map(|index| terp.stack.get_local(4 * index, 4).unwrap()).collect();
+        assert_eq!(locals, [0, 0x400, 0, 1, 1, 1, 0x500]);
+        assert_eq!(terp.heap_blocks()[0].addr, 0x300);
+    }
+
+    #[test]
+    fn searches_find_keys_by_value_and_through_pointers() {
+        // Four 6-byte structs at 0x100 with keys 10-40 at offset 2, a list of 16-byte
+        // nodes at 0x140 with 3-byte keys at offset 4, and the key "abc" at 0x180.
+        let mut ram = vec![0u8; 0x100];
+        for (index, key) in [10u16, 20, 30, 40].into_iter().enumerate() {
+            ram[index * 6 + 2..index * 6 + 4].copy_from_slice(&key.to_be_bytes());
+        }
+        for (index, (next, key)) in [(0x150u32, b"abd"), (0x160, b"abc"), (0, b"xyz")].into_iter().enumerate() {
+            ram[0x40 + index * 16..0x44 + index * 16].copy_from_slice(&next.to_be_bytes());
+            ram[0x44 + index * 16..0x47 + index * 16].copy_from_slice(key);
+        }
+        ram[0x80..0x83].copy_from_slice(b"abc");
+
+        let search = |code, args: &[i32], local| {
+            let mut operands: Vec<Arg> = args.iter().copied().map(Arg::Const).collect();
+            operands.push(Arg::Local(local));
+            op(code, &operands)
+        };
+        let body = [
+            search(0x150, &[30, 2, 0x100, 6, 4, 2, 0], 0),
+            // ReturnIndex, a hit and a miss.
+            search(0x151, &[40, 2, 0x100, 6, 4, 2, 4], 4),
+            search(0x151, &[25, 2, 0x100, 6, 4, 2, 4], 8),
+            // KeyIndirect over several bytes, in a list and in an array.
+            search(0x152, &[0x180, 3, 0x140, 4, 0, 1], 12),
+            search(0x150, &[0x180, 3, 0x140, 16, 3, 4, 5], 16),
+            // ZeroKeyTerminates with no struct count stops at the zero key after 40.
+            search(0x150, &[99, 2, 0x100, 6, -1, 2, 6], 20),
+        ];
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[(4, 6)], 0xC1, &body), &ram)).unwrap();
+        for _ in 0..body.len() { terp.step().unwrap(); }
+        let locals: Vec<u32> = (0..6).map(|index| terp.stack.get_local(4 * index, 4).unwrap()).collect();
+        assert_eq!(locals, [0x10C, 3, u32::MAX, 0x150, 1, u32::MAX]);
+    }
+}
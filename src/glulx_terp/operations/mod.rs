@@ -1,9 +1,39 @@
-use std::{fmt, io::{Read, Seek}};
+pub mod disassemble;
+mod execute;
+mod search;
+use std::fmt;
 
-use byteorder::{BigEndian, ReadBytesExt};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use super::Errors;
+use super::{memory::{MemoryError, Width}, Errors};
+
+// Reads big endian values from memory, advancing past them.
+struct Decoder<'a> {
+    memory: &'a [u8],
+    pos: u32,
+}
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Errors> {
+        let out_of_bounds = || Errors::MemoryError(MemoryError::OutOfBounds { pos: self.pos, len: N as u32 });
+        let start = self.pos as usize;
+        let bytes = self.memory.get(start..start + N).ok_or_else(out_of_bounds)?;
+        self.pos += N as u32;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Errors> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Errors> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Errors> {
+        self.take().map(u32::from_be_bytes)
+    }
+}
 
 #[derive(Debug)]
 pub struct Operation {
@@ -12,25 +42,24 @@ pub struct Operation {
 }
 
 impl Operation {
-    pub fn fetch<R: Read + Seek>(reader: &mut R, pos: u32) -> Result<Operation, Errors> {
-        reader.seek(std::io::SeekFrom::Start(pos as u64)).map_err(Errors::IOError)?;
-        
-        let mut value = reader.read_u8().map_err(Errors::IOError)? as u32;
+    // Decodes the instruction at `pos`, returning it with the address right after it.
+    pub fn fetch(memory: &[u8], pos: u32) -> Result<(Operation, u32), Errors> {
+        let mut decoder = Decoder { memory, pos };
+
+        let mut value = decoder.read_u8()? as u32;
         if (value & 0x80) != 0 {
-            value = (value << 8) + reader.read_u8().map_err(Errors::IOError)? as u32;
+            value = (value << 8) + decoder.read_u8()? as u32;
             if (value & 0xC000) == 0xC000 {
-                value = (value << 16) + (reader.read_u16::<BigEndian>().map_err(Errors::IOError)? as u32);
-                value -= 0xC100_0000;
+                value = (value << 16) + (decoder.read_u16()? as u32);
+                value -= 0xC000_0000;
             } else {
                 value -= 0x8000;
             }
         }
 
         let operation = OPCode::try_from(value).map_err(|_| Errors::FetchOperation(format!("Couldn't convert '{value:X?}' into OPCode")))?;
-        Ok(Operation {
-            code: operation,
-            operands: Operand::fetch_for_opcode(reader, operation)?
-        })
+        let operands = Operand::fetch_for_opcode(&mut decoder, operation)?;
+        Ok((Operation { code: operation, operands }, decoder.pos))
     }
 }
 
@@ -42,7 +71,7 @@ pub struct Operand {
 }
 
 impl Operand {
-    pub fn fetch_for_opcode<R: Read>(reader: &mut R, operation: OPCode) -> Result<Vec<Operand>, Errors> {
+    fn fetch_for_opcode(decoder: &mut Decoder, operation: OPCode) -> Result<Vec<Operand>, Errors> {
         let operand_types = operation.get_operand_types();
         let nb_operands = (operand_types.0 + operand_types.1) as usize;
     
@@ -64,7 +93,7 @@ impl Operand {
         let mut operands: Vec<Operand> = Vec::with_capacity(nb_operands);
         
         for _ in 0..((nb_operands as f32 / 2.0).ceil() as u32) {
-            let modes = reader.read_u8().map_err(Errors::IOError)?;
+            let modes = decoder.read_u8()?;
             operands_raw.push(modes & 0x0F);
             operands_raw.push((modes & 0xF0) >> 4)
         }
@@ -72,7 +101,7 @@ impl Operand {
         for (raw_mode, operand_type) in operands_raw.iter().take(nb_operands).zip(types) {
             operands.push(Operand { 
                 operand_mode: operand_type, 
-                addressing_mode: OperandAddressingMode::try_fetch(reader, *raw_mode).map_err(Errors::BinRead)? 
+                addressing_mode: OperandAddressingMode::try_fetch(decoder, *raw_mode)?
             });
         } 
 
@@ -80,9 +109,52 @@ impl Operand {
     }
 }
 
+// Mnemonic, then operands in encoding order, stores marked with an arrow:
+// `add #1a Fr:8 ->*1000`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self.code).to_lowercase())?;
+        for operand in &self.operands {
+            write!(f, " {operand}")?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        if self.operand_mode == OperandMode::Store {
+            write!(f, "->")?;
+        }
+        write!(f, "{}", self.addressing_mode)
+    }
+}
+
+// Constants in hex, sign-extended like they are when loaded, `*` for memory
+// (`*R:` relative to ram_start), `Fr:` for locals by byte offset and `sp` for the stack.
+impl fmt::Display for OperandAddressingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use OperandAddressingMode::*;
+        let constant = |f: &mut fmt::Formatter, value: i32| {
+            if value < 0 { write!(f, "#-{:x}", value.unsigned_abs()) } else { write!(f, "#{value:x}") }
+        };
+        match *self {
+            ConstantZero => write!(f, "#0"),
+            Constant1Byte(value) => constant(f, value as u8 as i8 as i32),
+            Constant2Bytes(value) => constant(f, value as u16 as i16 as i32),
+            Constant4Bytes(value) => constant(f, value as i32),
+            ContentOfAddress1Byte(addr) |
+            ContentOfAddress2Bytes(addr) |
+            ContentOfAddress4Bytes(addr) => write!(f, "*{addr:x}"),
+            ContentOfRAMAddress1Byte(addr) |
+            ContentOfRAMAddress2Bytes(addr) |
+            ContentOfRAMAddress4Bytes(addr) => write!(f, "*R:{addr:x}"),
+            CallFrameLocalAtAddress1Byte(offset) |
+            CallFrameLocalAtAddress2Bytes(offset) |
+            CallFrameLocalAtAddress4Bytes(offset) => write!(f, "Fr:{offset:x}"),
+            Stack => write!(f, "sp"),
+            __Unused1 | __Unused2 => write!(f, "?"),
+        }
     }
 }
 
@@ -121,32 +193,32 @@ pub enum OperandAddressingMode {
 }
 
 impl OperandAddressingMode {
-    fn try_fetch<R: Read>(reader: &mut R, mode: u8) -> Result<OperandAddressingMode, binread::Error> {
+    fn try_fetch(decoder: &mut Decoder, mode: u8) -> Result<OperandAddressingMode, Errors> {
         match mode {
             0 => Ok(Self::ConstantZero),
-            1 => Ok(Self::Constant1Byte(reader.read_u8()? as u32)),
-            2 => Ok(Self::Constant2Bytes(reader.read_u16::<BigEndian>()? as u32)),
-            3 => Ok(Self::Constant4Bytes(reader.read_u32::<BigEndian>()?)),
+            1 => Ok(Self::Constant1Byte(decoder.read_u8()? as u32)),
+            2 => Ok(Self::Constant2Bytes(decoder.read_u16()? as u32)),
+            3 => Ok(Self::Constant4Bytes(decoder.read_u32()?)),
 
             4 => Ok(Self::__Unused1),
 
-            5 => Ok(Self::ContentOfAddress1Byte(reader.read_u8()? as u32)),
-            6 => Ok(Self::ContentOfAddress2Bytes(reader.read_u16::<BigEndian>()? as u32)),
-            7 => Ok(Self::ContentOfAddress4Bytes(reader.read_u32::<BigEndian>()?)),
+            5 => Ok(Self::ContentOfAddress1Byte(decoder.read_u8()? as u32)),
+            6 => Ok(Self::ContentOfAddress2Bytes(decoder.read_u16()? as u32)),
+            7 => Ok(Self::ContentOfAddress4Bytes(decoder.read_u32()?)),
 
             8 => Ok(Self::Stack),
 
-            9 => Ok(Self::CallFrameLocalAtAddress1Byte(reader.read_u8()? as u32)),
-            0xA => Ok(Self::CallFrameLocalAtAddress2Bytes(reader.read_u16::<BigEndian>()? as u32)),
-            0xB => Ok(Self::CallFrameLocalAtAddress4Bytes(reader.read_u32::<BigEndian>()?)),
+            9 => Ok(Self::CallFrameLocalAtAddress1Byte(decoder.read_u8()? as u32)),
+            0xA => Ok(Self::CallFrameLocalAtAddress2Bytes(decoder.read_u16()? as u32)),
+            0xB => Ok(Self::CallFrameLocalAtAddress4Bytes(decoder.read_u32()?)),
 
             0xC => Ok(Self::__Unused2),
 
-            0xD => Ok(Self::ContentOfRAMAddress1Byte(reader.read_u8()? as u32)),
-            0xE => Ok(Self::ContentOfRAMAddress2Bytes(reader.read_u16::<BigEndian>()? as u32)),
-            0xF => Ok(Self::ContentOfRAMAddress4Bytes(reader.read_u32::<BigEndian>()?)),
+            0xD => Ok(Self::ContentOfRAMAddress1Byte(decoder.read_u8()? as u32)),
+            0xE => Ok(Self::ContentOfRAMAddress2Bytes(decoder.read_u16()? as u32)),
+            0xF => Ok(Self::ContentOfRAMAddress4Bytes(decoder.read_u32()?)),
 
-            _ => Err(binread::error::Error::NoVariantMatch { pos: mode as u64 })
+            _ => Err(Errors::FetchOperation(format!("Invalid addressing mode {mode:X}")))
         }
     }
 }
@@ -350,6 +422,15 @@ pub enum OPCode {
 
 
 impl OPCode {
+    // How much COPYS and COPYB move, every other opcode works on words.
+    pub fn data_width(self) -> Width {
+        match self {
+            Self::COPYS => Width::Short,
+            Self::COPYB => Width::Byte,
+            _ => Width::Word,
+        }
+    }
+
     pub fn get_operand_types(self) -> (u8, u8) {
         match self {
             Self::STKSWAP |
@@ -522,3 +603,62 @@ impl OPCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Operation, OPCode::{self, *}};
+
+    // Load and store operand counts from the opcode list of the Glulx spec, section 2.
+    const SPEC: &[(&[OPCode], (u8, u8))] = &[
+        (&[NOP, STKSWAP, QUIT, RESTART, DISCARDUNDO], (0, 0)),
+        (&[STKCOUNT, GETMEMSIZE, VERIFY, SAVEUNDO, RESTOREUNDO, HASUNDO, GETSTRINGTBL], (0, 1)),
+        (&[GETIOSYS], (0, 2)),
+        (&[JUMP, JUMPABS, STKCOPY, RETURN, MFREE, STREAMCHAR, STREAMNUM, STREAMSTR, STREAMUNICHAR, SETSTRINGTBL, SETRANDOM, DEBUGTRAP], (1, 0)),
+        (&[NEG, BITNOT, COPY, COPYS, COPYB, SEXS, SEXB, STKPEEK, CALLF, CATCH, SETMEMSIZE, MALLOC, SAVE, RESTORE, RANDOM,
+           NUMTOF, FTONUMZ, FTONUMN, CEIL, FLOOR, SQRT, EXP, LOG, SIN, COS, TAN, ASIN, ACOS, ATAN], (1, 1)),
+        (&[NUMTOD, FTOD], (1, 2)),
+        (&[JZ, JNZ, STKROLL, TAILCALL, THROW, PROTECT, SETIOSYS, MZERO, ACCELFUNC, ACCELPARAM, JISNAN, JISINF], (2, 0)),
+        (&[ADD, SUB, MUL, DIV, MOD, BITAND, BITOR, BITXOR, SHIFTL, SSHIFTR, USHIFTR, ALOAD, ALOADS, ALOADB, ALOADBIT,
+           CALL, CALLFI, GESTALT, GLK, FADD, FSUB, FMUL, FDIV, POW, ATAN2, DTONUMZ, DTONUMN, DTOF], (2, 1)),
+        (&[FMOD, DCEIL, DFLOOR, DSQRT, DEXP, DLOG, DSIN, DCOS, DTAN, DASIN, DACOS, DATAN], (2, 2)),
+        (&[JEQ, JNE, JLT, JGE, JGT, JLE, JLTU, JGEU, JGTU, JLEU, ASTORE, ASTORES, ASTOREB, ASTOREBIT, MCOPY,
+           JFLT, JFLE, JFGT, JFGE, JDISNAN, JDISINF], (3, 0)),
+        (&[CALLFII], (3, 1)),
+        (&[JFEQ, JFNE], (4, 0)),
+        (&[CALLFIII], (4, 1)),
+        (&[DADD, DSUB, DMUL, DDIV, DMODR, DMODQ, DPOW, DATAN2], (4, 2)),
+        (&[JDLT, JDLE, JDGT, JDGE], (5, 0)),
+        (&[LINKEDSEARCH], (6, 1)),
+        (&[JDEQ, JDNE], (7, 0)),
+        (&[LINEARSEARCH, BINARYSEARCH], (7, 1)),
+    ];
+
+    #[test]
+    fn operand_types_match_the_spec() {
+        for (codes, counts) in SPEC {
+            for code in *codes {
+                assert_eq!(code.get_operand_types(), *counts, "{code:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn spec_table_lists_every_opcode() {
+        let listed: Vec<OPCode> = SPEC.iter().flat_map(|(codes, _)| codes.iter().copied()).collect();
+        let defined: Vec<OPCode> = (0..0x1000).filter_map(|code| OPCode::try_from(code).ok()).collect();
+        for code in &defined {
+            assert_eq!(listed.iter().filter(|listed| *listed == code).count(), 1, "{code:?}");
+        }
+        assert_eq!(listed.len(), defined.len());
+    }
+
+    #[test]
+    fn every_opcode_length_decodes() {
+        // GETMEMSIZE (0x102) in its two-byte and four-byte forms, NOP in all three.
+        for (bytes, code) in [(&[0x00][..], NOP), (&[0x80, 0x00], NOP), (&[0xC0, 0, 0, 0], NOP),
+                              (&[0x81, 0x02, 0x00], GETMEMSIZE), (&[0xC0, 0x00, 0x01, 0x02, 0x00], GETMEMSIZE)] {
+            let (operation, next) = Operation::fetch(bytes, 0).unwrap();
+            assert_eq!((operation.code, next), (code, bytes.len() as u32), "{bytes:X?}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+
+use crate::glulx_terp::{Errors, GlulxTerp};
+
+// Bits of the options operand.
+mod options {
+    // The key operand is the address of the key, not the key itself.
+    pub const KEY_INDIRECT: u32 = 1;
+    // A struct whose key is all zeros ends the search, unless it's the one searched for.
+    pub const ZERO_KEY_TERMINATES: u32 = 2;
+    // Result is the index of the struct instead of its address, -1 when not found.
+    pub const RETURN_INDEX: u32 = 4;
+}
+
+// The operands the three search opcodes share.
+pub(super) struct Search {
+    pub key: u32,
+    pub key_size: u32,
+    pub key_offset: u32,
+    pub options: u32,
+}
+
+impl GlulxTerp {
+    // Keys are compared as big endian byte strings. A direct key must fit a word and is
+    // taken from its low bytes.
+    fn search_key(&self, search: &Search) -> Result<Vec<u8>, Errors> {
+        if search.options & options::KEY_INDIRECT != 0 {
+            return self.memory.bytes(search.key, search.key_size).map(<[u8]>::to_vec).map_err(Errors::MemoryError)
+        }
+        match search.key_size {
+            1 | 2 | 4 => Ok(search.key.to_be_bytes()[4 - search.key_size as usize..].to_vec()),
+            size => Err(Errors::InvalidSearchKeySize(size)),
+        }
+    }
+
+    fn compare_key(&self, key: &[u8], addr: u32) -> Result<Ordering, Errors> {
+        let entry = self.memory.bytes(addr, key.len() as u32).map_err(Errors::MemoryError)?;
+        Ok(entry.cmp(key))
+    }
+
+    fn is_zero_key(&self, addr: u32, len: u32) -> Result<bool, Errors> {
+        Ok(self.memory.bytes(addr, len).map_err(Errors::MemoryError)?.iter().all(|&byte| byte == 0))
+    }
+
+    fn search_result(options: u32, found: Option<(u32, u32)>) -> u32 {
+        let return_index = options & options::RETURN_INDEX != 0;
+        match found {
+            Some((addr, index)) => if return_index { index } else { addr },
+            None => if return_index { u32::MAX } else { 0 },
+        }
+    }
+
+    // LINEARSEARCH. A count of -1 searches until a zero key ends it.
+    pub(super) fn linear_search(&self, search: &Search, start: u32, struct_size: u32, count: u32) -> Result<u32, Errors> {
+        let key = self.search_key(search)?;
+        let zero_terminates = search.options & options::ZERO_KEY_TERMINATES != 0;
+
+        let mut index = 0u32;
+        while count == u32::MAX || index < count {
+            let addr = start.wrapping_add(index.wrapping_mul(struct_size));
+            let key_addr = addr.wrapping_add(search.key_offset);
+            if self.compare_key(&key, key_addr)? == Ordering::Equal {
+                return Ok(Self::search_result(search.options, Some((addr, index))))
+            }
+            if zero_terminates && self.is_zero_key(key_addr, search.key_size)? { break }
+            index += 1;
+        }
+        Ok(Self::search_result(search.options, None))
+    }
+
+    // BINARYSEARCH. The structs must be sorted by key, ZeroKeyTerminates doesn't apply.
+    pub(super) fn binary_search(&self, search: &Search, start: u32, struct_size: u32, count: u32) -> Result<u32, Errors> {
+        let key = self.search_key(search)?;
+
+        let (mut low, mut high) = (0u32, count);
+        while low < high {
+            let index = low + (high - low) / 2;
+            let addr = start.wrapping_add(index.wrapping_mul(struct_size));
+            match self.compare_key(&key, addr.wrapping_add(search.key_offset))? {
+                Ordering::Equal => return Ok(Self::search_result(search.options, Some((addr, index)))),
+                Ordering::Less => low = index + 1,
+                Ordering::Greater => high = index,
+            }
+        }
+        Ok(Self::search_result(search.options, None))
+    }
+
+    // LINKEDSEARCH, following the pointer at `next_offset` of each struct until a null
+    // one. Results are always addresses.
+    pub(super) fn linked_search(&self, search: &Search, start: u32, next_offset: u32) -> Result<u32, Errors> {
+        let key = self.search_key(search)?;
+        let zero_terminates = search.options & options::ZERO_KEY_TERMINATES != 0;
+
+        let mut addr = start;
+        while addr != 0 {
+            let key_addr = addr.wrapping_add(search.key_offset);
+            if self.compare_key(&key, key_addr)? == Ordering::Equal { return Ok(addr) }
+            if zero_terminates && self.is_zero_key(key_addr, search.key_size)? { break }
+            addr = self.memory.get_u32(addr.wrapping_add(next_offset)).map_err(Errors::MemoryError)?;
+        }
+        Ok(0)
+    }
+}
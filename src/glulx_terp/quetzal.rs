@@ -0,0 +1,361 @@
+use super::{heap::Heap, memory::MAX_SIZE, stack::Stack, Errors, GlulxTerp};
+
+#[derive(Debug)]
+pub enum QuetzalError {
+    NotQuetzal,
+    MissingChunk([u8; 4]),
+    Malformed,
+    // The save was made by another story, or another release of it.
+    WrongGame,
+}
+
+// What a Quetzal save holds for Glulx. There's no pc: the stack ends with the call
+// stub pushed by SAVE, which says where to resume and where RESTORE's result goes.
+#[derive(Debug, Clone)]
+pub struct SaveState {
+    // The first 128 bytes of the story file.
+    pub identification: Vec<u8>,
+    // From ram_start to the end of memory.
+    pub ram: Vec<u8>,
+    pub stack: Vec<u8>,
+    // Heap start and allocated blocks as (address, length).
+    pub heap: Option<(u32, Vec<(u32, u32)>)>,
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, QuetzalError> {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(QuetzalError::Malformed)
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+// CMem: RAM XORed with the original, runs of zeroes stored as a zero and the run length minus one.
+fn compress(ram: &[u8], original: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut zeroes = 0usize;
+    for (index, &byte) in ram.iter().enumerate() {
+        let diff = byte ^ original.get(index).copied().unwrap_or(0);
+        if diff == 0 {
+            zeroes += 1;
+            continue
+        }
+        while zeroes > 0 {
+            let run = zeroes.min(256);
+            out.extend([0, (run - 1) as u8]);
+            zeroes -= run;
+        }
+        out.push(diff);
+    }
+    // Trailing zeroes are implied.
+    out
+}
+
+fn decompress(data: &[u8], original: &[u8], len: usize) -> Result<Vec<u8>, QuetzalError> {
+    let mut diff = Vec::with_capacity(len);
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == 0 {
+            let run = *bytes.next().ok_or(QuetzalError::Malformed)? as usize + 1;
+            diff.extend(std::iter::repeat_n(0, run));
+        } else {
+            diff.push(byte);
+        }
+    }
+    if diff.len() > len { return Err(QuetzalError::Malformed) }
+    diff.resize(len, 0);
+
+    Ok(diff.iter().enumerate()
+        .map(|(index, byte)| byte ^ original.get(index).copied().unwrap_or(0))
+        .collect())
+}
+
+// MAll blocks must lie in order, apart, between the heap start and the end of the
+// restored memory, or the allocator would walk off its gaps.
+fn valid_heap(start: u32, blocks: &[(u32, u32)], ram_start: u32, memory_size: u64) -> bool {
+    if start < ram_start || start as u64 > memory_size { return false }
+    let mut pos = start as u64;
+    for &(addr, len) in blocks {
+        if (addr as u64) < pos { return false }
+        pos = addr as u64 + len as u64;
+    }
+    pos <= memory_size
+}
+
+impl SaveState {
+    pub fn to_bytes(&self, ram_start: u32, initial_ram: &[u8]) -> Vec<u8> {
+        let mut body = b"IFZS".to_vec();
+        write_chunk(&mut body, b"IFhd", &self.identification);
+
+        let mut memory = (ram_start + self.ram.len() as u32).to_be_bytes().to_vec();
+        memory.extend(compress(&self.ram, initial_ram));
+        write_chunk(&mut body, b"CMem", &memory);
+
+        write_chunk(&mut body, b"Stks", &self.stack);
+
+        if let Some((start, blocks)) = &self.heap {
+            let mut heap = start.to_be_bytes().to_vec();
+            heap.extend((blocks.len() as u32).to_be_bytes());
+            for (addr, len) in blocks {
+                heap.extend(addr.to_be_bytes());
+                heap.extend(len.to_be_bytes());
+            }
+            write_chunk(&mut body, b"MAll", &heap);
+        }
+
+        let mut out = b"FORM".to_vec();
+        out.extend((body.len() as u32).to_be_bytes());
+        out.extend(body);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8], ram_start: u32, initial_ram: &[u8]) -> Result<Self, QuetzalError> {
+        if data.len() < 12 || &data[0..4] != b"FORM" || &data[8..12] != b"IFZS" { return Err(QuetzalError::NotQuetzal) }
+        let end = (read_u32(data, 4)? as usize + 8).min(data.len());
+
+        let (mut identification, mut ram, mut stack, mut heap) = (None, None, None, None);
+        let mut pos = 12;
+        while pos + 8 <= end {
+            let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+            let len = read_u32(data, pos + 4)? as usize;
+            let chunk = data.get(pos + 8..pos + 8 + len).ok_or(QuetzalError::Malformed)?;
+            match &id {
+                b"IFhd" => identification = Some(chunk.to_vec()),
+                b"CMem" | b"UMem" => {
+                    // Same rule as SETMEMSIZE, checked before anything is allocated for it.
+                    let size = read_u32(chunk, 0)?;
+                    if size > MAX_SIZE || !size.is_multiple_of(256) { return Err(QuetzalError::Malformed) }
+                    let len = size.checked_sub(ram_start).ok_or(QuetzalError::Malformed)? as usize;
+                    ram = Some(if &id == b"CMem" {
+                        decompress(&chunk[4..], initial_ram, len)?
+                    } else {
+                        chunk.get(4..4 + len).ok_or(QuetzalError::Malformed)?.to_vec()
+                    });
+                }
+                b"Stks" => stack = Some(chunk.to_vec()),
+                b"MAll" => {
+                    let start = read_u32(chunk, 0)?;
+                    let count = read_u32(chunk, 4)? as usize;
+                    let blocks = (0..count)
+                        .map(|index| Ok((read_u32(chunk, 8 + index * 8)?, read_u32(chunk, 12 + index * 8)?)))
+                        .collect::<Result<Vec<_>, QuetzalError>>()?;
+                    heap = Some((start, blocks));
+                }
+                _ => {}
+            }
+            pos += 8 + len + len % 2;
+        }
+
+        Ok(Self {
+            identification: identification.ok_or(QuetzalError::MissingChunk(*b"IFhd"))?,
+            ram: ram.ok_or(QuetzalError::MissingChunk(*b"CMem"))?,
+            stack: stack.ok_or(QuetzalError::MissingChunk(*b"Stks"))?,
+            heap,
+        })
+    }
+}
+
+impl GlulxTerp {
+    // Snapshot of the current state. Meant to be taken with the call stub of the
+    // saving instruction on top of the stack.
+    pub(in crate::glulx_terp) fn save_state(&self) -> SaveState {
+        SaveState {
+            identification: self.memory.identification().to_vec(),
+            ram: self.memory.ram().to_vec(),
+            stack: self.stack.contents().to_vec(),
+            heap: self.heap.as_ref().map(|heap| (heap.start(), heap.blocks().collect())),
+        }
+    }
+
+    pub(in crate::glulx_terp) fn save_to_bytes(&self, state: &SaveState) -> Vec<u8> {
+        state.to_bytes(self.memory.get_header().ram_start, self.memory.initial_ram())
+    }
+
+    // Checks everything before touching anything, so a rejected save leaves the
+    // interpreter as it was. Resumes after the saving instruction, which gets -1.
+    pub(in crate::glulx_terp) fn restore_state(&mut self, state: SaveState) -> Result<(), Errors> {
+        if state.identification != self.memory.identification() {
+            return Err(Errors::Quetzal(QuetzalError::WrongGame))
+        }
+        let ram_start = self.memory.get_header().ram_start as usize;
+        // Memory never shrinks below end_mem, SETMEMSIZE refuses it too.
+        let end_mem = self.memory.get_header().end_mem as usize;
+        let valid_size = (ram_start + state.ram.len()).is_multiple_of(256)
+            && state.ram.len() <= u32::MAX as usize - ram_start
+            && ram_start + state.ram.len() >= end_mem;
+        if !valid_size || state.stack.len() < 16 || state.stack.len() > self.stack.capacity() as usize {
+            return Err(Errors::Quetzal(QuetzalError::Malformed))
+        }
+
+        // The stack is rebuilt on the side and the stub checked against the restored RAM,
+        // so nothing can fail once RAM is replaced.
+        let mut stack = Stack::new(self.stack.capacity());
+        let stub = stack.restore(&state.stack).map_err(Errors::StackError)?;
+        match stub.dest_type {
+            0 => {}
+            1 if self.memory.writable_after_restore(stub.dest_addr, 4, state.ram.len()) => {}
+            1 => return Err(Errors::Quetzal(QuetzalError::Malformed)),
+            2 => stack.set_local(stub.dest_addr, 4, u32::MAX).map_err(Errors::StackError)?,
+            3 => stack.push(u32::MAX).map_err(Errors::StackError)?,
+            // Only SAVE and SAVEUNDO stubs end a saved stack.
+            other => return Err(Errors::InvalidCallStub(other)),
+        }
+        if let Some((start, blocks)) = &state.heap {
+            if !valid_heap(*start, blocks, ram_start as u32, (ram_start + state.ram.len()) as u64) {
+                return Err(Errors::Quetzal(QuetzalError::Malformed))
+            }
+        }
+
+        self.memory.restore_ram(&state.ram, self.protected);
+        self.heap = state.heap.map(|(start, blocks)| Heap::from_blocks(start, blocks));
+        self.stack = stack;
+        self.frame_functions.clear();
+        self.pc = stub.pc;
+        if stub.dest_type == 1 {
+            self.memory.set_u32(stub.dest_addr, u32::MAX).map_err(Errors::MemoryError)?;
+        }
+        Ok(())
+    }
+
+    pub(in crate::glulx_terp) fn restore_from_bytes(&mut self, data: &[u8]) -> Result<(), Errors> {
+        let ram_start = self.memory.get_header().ram_start;
+        let state = SaveState::from_bytes(data, ram_start, self.memory.initial_ram()).map_err(Errors::Quetzal)?;
+        self.restore_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuetzalError, SaveState};
+    use crate::glulx_terp::{exec::ExecState, test_support::{function, glk, op, story, Arg}, Errors, GlulxTerp};
+
+    #[test]
+    fn save_from_another_release_is_rejected() {
+        let original = story(&[0xC1, 0, 0], &[0; 0x10]);
+        let terp = GlulxTerp::from_bytes(original.clone()).unwrap();
+        let save = terp.save_to_bytes(&terp.save_state());
+
+        let mut release = original;
+        release[0x3C] = 1;
+        let mut other = GlulxTerp::from_bytes(release).unwrap();
+        assert!(matches!(other.restore_from_bytes(&save), Err(Errors::Quetzal(QuetzalError::WrongGame))));
+        assert_eq!(other.pc(), terp.pc());
+    }
+
+    #[test]
+    fn memory_sizes_are_checked_before_decompressing() {
+        let terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[0; 0x10])).unwrap();
+        let save = terp.save_to_bytes(&terp.save_state());
+        // CMem comes right after the 128 bytes of IFhd, its first word is the memory size.
+        let size_pos = 12 + 8 + 128 + 8;
+        assert_eq!(save[size_pos - 8..size_pos - 4], *b"CMem");
+        for size in [0xFFFF_FF00u32, 0x280] {
+            let mut bad = save.clone();
+            bad[size_pos..size_pos + 4].copy_from_slice(&size.to_be_bytes());
+            assert!(matches!(SaveState::from_bytes(&bad, 0x100, terp.memory.initial_ram()), Err(QuetzalError::Malformed)), "{size:X}");
+        }
+    }
+
+    #[test]
+    fn memory_below_end_mem_is_rejected() {
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[1, 2, 3, 4])).unwrap();
+        let mut state = terp.save_state();
+        state.ram.clear();
+        assert!(matches!(terp.restore_state(state), Err(Errors::Quetzal(QuetzalError::Malformed))));
+        assert_eq!(terp.memory.size(), 0x200);
+        assert_eq!(terp.memory.ram()[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn bad_stack_leaves_memory_as_it_was() {
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[1, 2, 3, 4])).unwrap();
+        // A stub on its own, going back to the frame at `frame_ptr`.
+        let stub = |frame_ptr: u32| [vec![0; 12], frame_ptr.to_be_bytes().to_vec()].concat();
+        // An empty frame, as the function at the bottom of a chain would leave it.
+        let frame = [12u32, 12, 0].iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<_>>();
+        // Truncated in the middle of a word, a misaligned frame pointer, one past the stub,
+        // and a frame whose caller's stub points back at that same frame.
+        let looped = [frame.clone(), stub(28), frame, stub(28)].concat();
+        for stack in [vec![0; 18], stub(2), stub(16), looped] {
+            let mut state = terp.save_state();
+            state.ram[0] = 9;
+            state.heap = Some((0x200, vec![(0x200, 0x10)]));
+            state.stack = stack;
+            assert!(matches!(terp.restore_state(state), Err(Errors::StackError(_))));
+            assert_eq!(terp.memory.ram()[..4], [1, 2, 3, 4]);
+            assert!(terp.heap.is_none());
+        }
+    }
+
+    #[test]
+    fn bad_heap_leaves_memory_as_it_was() {
+        let mut terp = GlulxTerp::from_bytes(story(&[0xC1, 0, 0], &[1, 2, 3, 4])).unwrap();
+        // RAM runs from 0x100 to 0x300 once restored, the stack is an empty frame and a SAVE stub discarding its result.
+        let stack: Vec<u8> = [12u32, 12, 0, 0, 0, 0x40, 0].iter().flat_map(|word| word.to_be_bytes()).collect();
+        let heaps = [
+            (0x200, vec![(0x200, 0x100), (0x210, 0x10)]),
+            (0x200, vec![(0x210, 0x10), (0x200, 0x8)]),
+            (0x200, vec![(0x1F0, 0x10)]),
+            (0x200, vec![(0x2F8, 0x10)]),
+            (0x200, vec![(0xFFFF_FFF0, 0x20)]),
+            (0x80, vec![]),
+            (0x400, vec![]),
+        ];
+        for heap in heaps {
+            let mut state = terp.save_state();
+            state.ram = vec![9; 0x200];
+            state.stack = stack.clone();
+            state.heap = Some(heap.clone());
+            assert!(matches!(terp.restore_state(state), Err(Errors::Quetzal(QuetzalError::Malformed))), "{heap:X?}");
+            assert_eq!(terp.memory.ram()[..4], [1, 2, 3, 4]);
+            assert!(terp.heap.is_none());
+        }
+
+        // Blocks ending right at the end of memory are fine.
+        let mut state = terp.save_state();
+        state.ram = vec![9; 0x200];
+        state.stack = stack;
+        state.heap = Some((0x200, vec![(0x200, 0x10), (0x2F0, 0x10)]));
+        terp.restore_state(state).unwrap();
+        assert_eq!(terp.heap_summary().free, 0xE0);
+    }
+
+    #[test]
+    fn save_then_restore_through_memory_streams() {
+        // SAVE to a stream over 0x300, change 0x104, then RESTORE from the same bytes.
+        // Once restored the SAVE answers -1 and the jnz returns.
+        let code = function(&[(4, 2)], 0xC1, &[
+            glk(0x43, vec![Arg::Const(0x300), Arg::Const(0x400), Arg::Const(1), Arg::Zero], Arg::Local(0)),
+            op(0x4C, &[Arg::Const(0x104), Arg::Zero, Arg::Const(7)]),
+            op(0x123, &[Arg::Local(0), Arg::Local(4)]),
+            op(0x23, &[Arg::Local(4), Arg::Const(1)]),
+            op(0x4C, &[Arg::Const(0x104), Arg::Zero, Arg::Const(99)]),
+            glk(0x44, vec![Arg::Local(0), Arg::Zero], Arg::Zero),
+            glk(0x43, vec![Arg::Const(0x300), Arg::Const(0x400), Arg::Const(2), Arg::Zero], Arg::Local(0)),
+            op(0x124, &[Arg::Local(0), Arg::Local(4)]),
+            op(0x4C, &[Arg::Const(0x108), Arg::Zero, Arg::Local(4)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let image = story(&code, &[0; 0x700]);
+        let mut terp = GlulxTerp::from_bytes(image.clone()).unwrap();
+        terp.run_until(|terp| terp.memory.get_u32(0x104).unwrap() == 99).unwrap();
+        let len = terp.memory.get_u32(0x304).unwrap() + 8;
+        let save = terp.memory.bytes(0x300, len).unwrap().to_vec();
+        assert_eq!(terp.run().unwrap(), ExecState::Halted);
+        assert_eq!(terp.memory.get_u32(0x104).unwrap(), 7);
+        assert_eq!(terp.memory.get_u32(0x108).unwrap(), 0);
+
+        // The same bytes don't go into a story whose IFhd differs.
+        let mut patched = image;
+        patched[0x7F] ^= 1;
+        let mut other = GlulxTerp::from_bytes(patched).unwrap();
+        assert!(matches!(other.restore_from_bytes(&save), Err(Errors::Quetzal(QuetzalError::WrongGame))));
+    }
+}
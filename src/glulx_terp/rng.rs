@@ -0,0 +1,143 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, time::SystemTime};
+
+// Where RANDOM gets its numbers, swappable so embedders can bring their own generator.
+pub trait RandomSource {
+    fn next_u32(&mut self) -> u32;
+
+    // SETRANDOM: a non-zero seed must always restart the same sequence, 0 asks for
+    // an unpredictable one.
+    fn seed(&mut self, seed: u32);
+
+    // Uniform in [0, bound), bound must not be 0.
+    fn below(&mut self, bound: u32) -> u32 {
+        ((self.next_u32() as u64 * bound as u64) >> 32) as u32
+    }
+
+    // RANDOM: any word for 0, [0, range) when positive, (range, 0] when negative.
+    fn random(&mut self, range: u32) -> u32 {
+        match range as i32 {
+            0 => self.next_u32(),
+            1.. => self.below(range),
+            negative => self.below(negative.unsigned_abs()).wrapping_neg(),
+        }
+    }
+}
+
+// xorshift64*, small and good enough for games. The same seed always gives
+// the same sequence, so runs can be reproduced.
+#[derive(Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn seeded(seed: u32) -> Self {
+        // Spread the seed over the whole state, which must never be zero.
+        let mut state = (seed as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Self { state: (state ^ (state >> 31)).max(1) }
+    }
+
+    pub fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(time.as_nanos());
+        }
+        Self::seeded(hasher.finish() as u32 ^ (hasher.finish() >> 32) as u32)
+    }
+
+    fn xorshift(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+    }
+
+}
+
+impl RandomSource for Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.xorshift()
+    }
+
+    fn seed(&mut self, seed: u32) {
+        *self = if seed == 0 { Self::from_entropy() } else { Self::seeded(seed) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn setrandom_with_the_same_seed_repeats_the_sequence() {
+        // SETRANDOM 42, three RANDOM 1000 onto the stack, and the same again.
+        let mut body = Vec::new();
+        for _ in 0..2 {
+            body.push(op(0x111, &[Arg::Const(42)]));
+            body.extend((0..3).map(|_| op(0x110, &[Arg::Const(1000), Arg::Stack])));
+        }
+        let mut terp = GlulxTerp::from_bytes(story(&function(&[], 0xC1, &body), &[])).unwrap();
+        for _ in 0..body.len() { terp.step().unwrap(); }
+        let values: Vec<u32> = (0..6).map(|_| terp.stack.pop().unwrap()).collect();
+        assert_eq!(values[..3], values[3..]);
+        assert!(values.iter().all(|&value| value < 1000), "{values:?}");
+        assert!(values[0] != values[1] || values[1] != values[2]);
+
+        let (mut first, mut second) = (Rng::seeded(5), Rng::seeded(5));
+        for _ in 0..10 {
+            assert_eq!(first.random(0), second.random(0));
+        }
+    }
+
+    #[test]
+    fn positive_range_stays_below_it() {
+        let mut rng = Rng::seeded(9);
+        assert!((0..1000).all(|_| rng.random(7) < 7));
+        assert!((0..100).all(|_| rng.random(1) == 0));
+    }
+
+    #[test]
+    fn negative_range_counts_down_from_zero() {
+        let mut rng = Rng::seeded(42);
+        let values: Vec<i32> = (0..1000).map(|_| rng.random(-10i32 as u32) as i32).collect();
+        assert!(values.iter().all(|value| (-9..=0).contains(value)), "{values:?}");
+        assert!(values.contains(&0) && values.contains(&-9));
+
+        for _ in 0..100 {
+            let value = rng.random(i32::MIN as u32) as i32;
+            assert!(value <= 0 && value > i32::MIN, "{value}");
+        }
+    }
+
+    // Always hands out the seed it was given last.
+    struct Fixed(u32);
+
+    impl RandomSource for Fixed {
+        fn next_u32(&mut self) -> u32 {
+            self.0
+        }
+
+        fn seed(&mut self, seed: u32) {
+            self.0 = seed;
+        }
+    }
+
+    #[test]
+    fn random_and_setrandom_go_through_the_installed_source() {
+        let code = function(&[(4, 3)], 0xC1, &[
+            op(0x110, &[Arg::Zero, Arg::Local(0)]),
+            op(0x111, &[Arg::Const(i32::MIN)]),
+            op(0x110, &[Arg::Zero, Arg::Local(4)]),
+            op(0x110, &[Arg::Const(-100), Arg::Local(8)]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        terp.set_random_source(Box::new(Fixed(3)));
+        for _ in 0..4 { terp.step().unwrap(); }
+        let locals: Vec<i32> = (0..3).map(|index| terp.stack.get_local(4 * index, 4).unwrap() as i32).collect();
+        // Half of u32::MAX scaled down to (-100, 0] by the default `random`.
+        assert_eq!(locals, [3, i32::MIN, -50]);
+    }
+}
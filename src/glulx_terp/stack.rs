@@ -0,0 +1,354 @@
+#[derive(Debug)]
+pub enum StackError {
+    Overflow,
+    Underflow,
+    LocalOutOfBounds(u32),
+    // The stack pointer left word alignment, which only a bug in the interpreter can cause.
+    Misaligned(u32),
+}
+
+// The four words pushed before entering a function or by CATCH, telling where
+// execution resumes and where the result goes.
+#[derive(Debug, Clone, Copy)]
+pub struct CallStub {
+    pub dest_type: u32,
+    pub dest_addr: u32,
+    pub pc: u32,
+    pub frame_ptr: u32,
+}
+
+// Byte-addressed stack laid out exactly as the spec describes, so frames and
+// call stubs can later be serialized as-is.
+pub struct Stack {
+    raw: Vec<u8>,
+    sp: u32,
+    fp: u32,
+    locals_pos: u32,
+    values_pos: u32,
+}
+
+impl Stack {
+    pub fn new(size: u32) -> Self {
+        Self {
+            raw: vec![0; size as usize],
+            sp: 0,
+            fp: 0,
+            locals_pos: 0,
+            values_pos: 0,
+        }
+    }
+
+    pub fn sp(&self) -> u32 {
+        self.sp
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.raw.len() as u32
+    }
+
+    pub fn fp(&self) -> u32 {
+        self.fp
+    }
+
+    // Number of live frames, following the frame pointers saved in each call stub.
+    // Stops at a pointer that doesn't go down, so a bad chain can't loop.
+    pub fn depth(&self) -> u32 {
+        if self.sp == 0 { return 0 }
+        let (mut fp, mut depth) = (self.fp, 1);
+        while fp >= 16 {
+            let caller = self.read_u32(fp - 4);
+            if caller >= fp { break }
+            fp = caller;
+            depth += 1;
+        }
+        depth
+    }
+
+    fn read_u32(&self, pos: u32) -> u32 {
+        let pos = pos as usize;
+        u32::from_be_bytes(self.raw[pos..pos+4].try_into().unwrap())
+    }
+
+    fn write_u32(&mut self, pos: u32, value: u32) {
+        let pos = pos as usize;
+        self.raw[pos..pos+4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    // Values, frames and call stubs are all whole words, so sp never leaves word alignment.
+    fn check_aligned(&self) -> Result<(), StackError> {
+        if !self.sp.is_multiple_of(4) { return Err(StackError::Misaligned(self.sp)) }
+        Ok(())
+    }
+
+    // Value stack
+    pub fn push(&mut self, value: u32) -> Result<(), StackError> {
+        self.check_aligned()?;
+        if self.sp as usize + 4 > self.raw.len() { return Err(StackError::Overflow) }
+        self.write_u32(self.sp, value);
+        self.sp += 4;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<u32, StackError> {
+        self.check_aligned()?;
+        if self.sp < self.values_pos + 4 { return Err(StackError::Underflow) }
+        self.sp -= 4;
+        Ok(self.read_u32(self.sp))
+    }
+
+    // Number of values above the current frame.
+    pub fn count(&self) -> u32 {
+        (self.sp - self.values_pos) / 4
+    }
+
+    // Start of the top `count` values, which must all belong to the current frame.
+    fn top_values(&self, count: u32) -> Result<u32, StackError> {
+        self.check_aligned()?;
+        if count > self.count() { return Err(StackError::Underflow) }
+        Ok(self.sp - count * 4)
+    }
+
+    // The value `depth` entries below the top, 0 being the top one.
+    pub fn peek(&self, depth: u32) -> Result<u32, StackError> {
+        let pos = self.top_values(depth.saturating_add(1))?;
+        Ok(self.read_u32(pos))
+    }
+
+    pub fn swap(&mut self) -> Result<(), StackError> {
+        let pos = self.top_values(2)? as usize;
+        self.raw[pos..pos+8].rotate_left(4);
+        Ok(())
+    }
+
+    // Pushes the top `count` values again, keeping their order.
+    pub fn copy(&mut self, count: u32) -> Result<(), StackError> {
+        let pos = self.top_values(count)? as usize;
+        let len = count as usize * 4;
+        if self.sp as usize + len > self.raw.len() { return Err(StackError::Overflow) }
+        self.raw.copy_within(pos..pos+len, self.sp as usize);
+        self.sp += len as u32;
+        Ok(())
+    }
+
+    // Rotates the top `count` values, positive places moving them towards the top.
+    pub fn roll(&mut self, count: u32, places: i32) -> Result<(), StackError> {
+        let pos = self.top_values(count)? as usize;
+        if count == 0 { return Ok(()) }
+        let places = places.rem_euclid(count as i32) as usize;
+        self.raw[pos..self.sp as usize].rotate_right(places * 4);
+        Ok(())
+    }
+
+    // Frames
+    pub fn push_frame(&mut self, format: &[(u8, u8)]) -> Result<(), StackError> {
+        self.check_aligned()?;
+        let fp = self.sp;
+
+        // Format of locals, terminated by a zero pair and padded to 4 bytes.
+        let mut locals_pos = 8 + 2 * (format.len() as u32 + 1);
+        locals_pos = (locals_pos + 3) & !3;
+
+        let mut locals_len = 0u32;
+        for &(size, count) in format {
+            let size = size as u32;
+            locals_len = (locals_len + size - 1) & !(size - 1);
+            locals_len += size * count as u32;
+        }
+        locals_len = (locals_len + 3) & !3;
+
+        let frame_len = locals_pos + locals_len;
+        if fp as usize + frame_len as usize > self.raw.len() { return Err(StackError::Overflow) }
+
+        self.raw[fp as usize..(fp + frame_len) as usize].fill(0);
+        self.write_u32(fp, frame_len);
+        self.write_u32(fp + 4, locals_pos);
+        for (index, &(size, count)) in format.iter().enumerate() {
+            let pos = (fp + 8) as usize + index * 2;
+            self.raw[pos] = size;
+            self.raw[pos + 1] = count;
+        }
+
+        self.fp = fp;
+        self.locals_pos = fp + locals_pos;
+        self.values_pos = fp + frame_len;
+        self.sp = self.values_pos;
+        Ok(())
+    }
+
+    // Locals are addressed by byte offset from the start of the locals segment.
+    fn local_pos(&self, offset: u32, width: u32) -> Result<usize, StackError> {
+        let pos = self.locals_pos as u64 + offset as u64;
+        if pos + width as u64 > self.values_pos as u64 { return Err(StackError::LocalOutOfBounds(offset)) }
+        Ok(pos as usize)
+    }
+
+    pub fn get_local(&self, offset: u32, width: u32) -> Result<u32, StackError> {
+        let pos = self.local_pos(offset, width)?;
+        Ok(match width {
+            1 => self.raw[pos] as u32,
+            2 => u16::from_be_bytes(self.raw[pos..pos+2].try_into().unwrap()) as u32,
+            _ => u32::from_be_bytes(self.raw[pos..pos+4].try_into().unwrap()),
+        })
+    }
+
+    pub fn set_local(&mut self, offset: u32, width: u32, value: u32) -> Result<(), StackError> {
+        let pos = self.local_pos(offset, width)?;
+        match width {
+            1 => self.raw[pos] = value as u8,
+            2 => self.raw[pos..pos+2].copy_from_slice(&(value as u16).to_be_bytes()),
+            _ => self.raw[pos..pos+4].copy_from_slice(&value.to_be_bytes()),
+        }
+        Ok(())
+    }
+
+    // Call stubs
+    pub fn push_call_stub(&mut self, dest_type: u32, dest_addr: u32, pc: u32) -> Result<(), StackError> {
+        self.push(dest_type)?;
+        self.push(dest_addr)?;
+        self.push(pc)?;
+        self.push(self.fp)
+    }
+
+    // Pops the stub right below sp and makes the frame it recorded current again.
+    pub fn pop_call_stub(&mut self) -> Result<CallStub, StackError> {
+        self.check_aligned()?;
+        if self.sp < 16 { return Err(StackError::Underflow) }
+        self.sp -= 16;
+        let stub = CallStub {
+            dest_type: self.read_u32(self.sp),
+            dest_addr: self.read_u32(self.sp + 4),
+            pc: self.read_u32(self.sp + 8),
+            frame_ptr: self.read_u32(self.sp + 12),
+        };
+        let (frame_len, locals_pos) = self.frame_below(stub.frame_ptr, self.sp)?;
+
+        self.fp = stub.frame_ptr;
+        self.values_pos = self.fp + frame_len;
+        self.locals_pos = self.fp + locals_pos;
+        Ok(stub)
+    }
+
+    // Length and locals position of the frame at `fp`, which must lie whole below
+    // `top`. A saved stack could say otherwise.
+    fn frame_below(&self, fp: u32, top: u32) -> Result<(u32, u32), StackError> {
+        if !fp.is_multiple_of(4) { return Err(StackError::Misaligned(fp)) }
+        if fp as u64 + 8 > top as u64 { return Err(StackError::Underflow) }
+        let (frame_len, locals_pos) = (self.read_u32(fp), self.read_u32(fp + 4));
+        if locals_pos > frame_len || fp as u64 + frame_len as u64 > top as u64 { return Err(StackError::Underflow) }
+        Ok((frame_len, locals_pos))
+    }
+
+    // Empties the stack, for a new outermost function to start from scratch.
+    pub fn clear(&mut self) {
+        self.sp = 0;
+        self.fp = 0;
+        self.locals_pos = 0;
+        self.values_pos = 0;
+    }
+
+    // Drops the current frame but keeps the stub below it, for a new frame to take its place.
+    pub fn discard_frame(&mut self) {
+        self.sp = self.fp;
+    }
+
+    // Discards the current frame and returns the stub of its caller, or None when
+    // the outermost function returned.
+    pub fn leave_frame(&mut self) -> Result<Option<CallStub>, StackError> {
+        self.sp = self.fp;
+        if self.sp == 0 { return Ok(None) }
+        self.pop_call_stub().map(Some)
+    }
+
+    // Continuations
+    // CATCH tokens are the stack pointer right after the stub was pushed. A token is
+    // only valid while that stub is still on the stack: it must sit in the value stack
+    // of a live frame, and name that frame as its own. Once the frame returned, or the
+    // values were popped, throwing to it would unwind into garbage.
+    pub fn is_catch_token(&self, token: u32) -> bool {
+        if !token.is_multiple_of(4) || token < 16 || token > self.sp { return false }
+        let stub = token - 16;
+        let frame_ptr = self.read_u32(stub + 12);
+
+        // Walk the live frames from the innermost one, each caller's values end at the
+        // call stub right below its callee's frame.
+        let (mut fp, mut top) = (self.fp, self.sp);
+        loop {
+            if frame_ptr == fp {
+                return stub >= fp + self.read_u32(fp) && token <= top
+            }
+            if fp < 16 { return false }
+            top = fp - 16;
+            let caller = self.read_u32(fp - 4);
+            // Each caller lies below its callee, anything else is a broken chain.
+            if caller >= fp { return false }
+            fp = caller;
+        }
+    }
+
+    pub fn unwind_to(&mut self, token: u32) -> Result<CallStub, StackError> {
+        self.sp = token;
+        self.pop_call_stub()
+    }
+
+    // Serialization
+    // Everything up to sp, in the spec's layout, as stored in a save file.
+    pub fn contents(&self) -> &[u8] {
+        &self.raw[..self.sp as usize]
+    }
+
+    // Replaces the whole stack with saved contents, which must end with a call stub:
+    // popping it is what brings back the frame that was current. Every frame below
+    // must be reachable through the stubs in between, down to the one at 0.
+    pub fn restore(&mut self, contents: &[u8]) -> Result<CallStub, StackError> {
+        if contents.len() > self.raw.len() || !contents.len().is_multiple_of(4) { return Err(StackError::Overflow) }
+        self.raw[..contents.len()].copy_from_slice(contents);
+        self.sp = contents.len() as u32;
+        let stub = self.pop_call_stub()?;
+
+        let mut fp = self.fp;
+        while fp != 0 {
+            if fp < 16 { return Err(StackError::Underflow) }
+            let caller = self.read_u32(fp - 4);
+            self.frame_below(caller, fp - 16)?;
+            fp = caller;
+        }
+        Ok(stub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stack;
+    use crate::glulx_terp::{test_support::{function, op, story, Arg}, GlulxTerp};
+
+    #[test]
+    fn walks_stop_at_a_frame_pointer_that_does_not_go_down() {
+        // An empty frame at 28 whose caller's stub points back at 28.
+        let words = [12u32, 12, 0, 0, 0, 0, 28, 12, 12, 0, 0, 0, 0, 28];
+        let mut stack = Stack::new(0x100);
+        stack.raw[..56].copy_from_slice(&words.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<_>>());
+        stack.sp = 56;
+        stack.fp = 28;
+        stack.values_pos = 40;
+        assert_eq!(stack.depth(), 1);
+        assert!(!stack.is_catch_token(16));
+    }
+
+    #[test]
+    fn sp_stays_aligned_across_calls() {
+        // Byte locals need padding, the C0 function at 0x80 gets its two arguments on the stack.
+        let mut code = function(&[(1, 3)], 0xC1, &[
+            op(0x48, &[Arg::Const(0x100), Arg::Zero, Arg::Stack]),
+            op(0x48, &[Arg::Const(0x100), Arg::Const(1), Arg::Stack]),
+            op(0x30, &[Arg::Const(0x80), Arg::Const(2), Arg::Stack]),
+            op(0x31, &[Arg::Stack]),
+        ]);
+        code.resize(0x40, 0);
+        code.extend(function(&[(1, 1)], 0xC0, &[op(0x31, &[Arg::Const(5)])]));
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 8])).unwrap();
+        while !terp.has_halted() {
+            assert!(terp.stack.sp().is_multiple_of(4), "sp {:#X} at pc {:#X}", terp.stack.sp(), terp.pc());
+            terp.step().unwrap();
+        }
+    }
+}
@@ -0,0 +1,11 @@
+// Resource usage counters, updated as instructions execute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub instructions: u64,
+    // High-water mark of the stack pointer, in bytes.
+    pub peak_stack: u32,
+    // Largest size the memory map reached, in bytes.
+    pub peak_memory: u32,
+    // Successful MALLOCs.
+    pub allocations: u64,
+}
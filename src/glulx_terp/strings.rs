@@ -0,0 +1,262 @@
+use super::{iosys::{mode, stub}, stack::CallStub, Errors, GlulxTerp};
+
+// Decoding table node types.
+mod node {
+    pub const BRANCH: u8 = 0x00;
+    pub const TERMINATOR: u8 = 0x01;
+    pub const CHAR: u8 = 0x02;
+    pub const STRING: u8 = 0x03;
+    pub const UNICHAR: u8 = 0x04;
+    pub const UNISTRING: u8 = 0x05;
+    pub const INDIRECT: u8 = 0x08;
+    pub const DOUBLE_INDIRECT: u8 = 0x09;
+    pub const INDIRECT_ARGS: u8 = 0x0A;
+    pub const DOUBLE_INDIRECT_ARGS: u8 = 0x0B;
+}
+
+// Where printing a string is at, which is also what the stub resuming it records.
+#[derive(Debug, Clone, Copy)]
+pub(in crate::glulx_terp) enum StringPos {
+    // Address of the next character of an E0 string.
+    Latin1(u32),
+    // Address of the next character of an E2 string.
+    Unicode(u32),
+    // The next bit of an E1 string, counting from the low bit of the byte at `addr`.
+    Compressed { addr: u32, bit: u32 },
+}
+
+impl StringPos {
+    fn stub(self) -> CallStub {
+        let (dest_type, dest_addr, pc) = match self {
+            Self::Latin1(addr) => (stub::RESUME_LATIN1, 0, addr),
+            Self::Unicode(addr) => (stub::RESUME_UNICODE, 0, addr),
+            Self::Compressed { addr, bit } => (stub::RESUME_COMPRESSED, bit, addr),
+        };
+        CallStub { dest_type, dest_addr, pc, frame_ptr: 0 }
+    }
+
+    pub(in crate::glulx_terp) fn from_stub(stub: CallStub) -> Option<Self> {
+        match stub.dest_type {
+            stub::RESUME_LATIN1 => Some(Self::Latin1(stub.pc)),
+            stub::RESUME_UNICODE => Some(Self::Unicode(stub.pc)),
+            stub::RESUME_COMPRESSED => Some(Self::Compressed { addr: stub.pc, bit: stub.dest_addr }),
+            _ => None,
+        }
+    }
+}
+
+// What a string printed so far asks for next.
+enum Next {
+    Continue(StringPos),
+    // A function or the filter was called, it resumes printing when it returns.
+    Called,
+    End,
+}
+
+impl GlulxTerp {
+    fn string_start(&self, addr: u32) -> Result<StringPos, Errors> {
+        match self.memory.get_u8(addr).map_err(Errors::MemoryError)? {
+            0xE0 => Ok(StringPos::Latin1(addr + 1)),
+            0xE1 => Ok(StringPos::Compressed { addr: addr + 1, bit: 0 }),
+            // The type byte is padded to a word.
+            0xE2 => Ok(StringPos::Unicode(addr + 4)),
+            _ => Err(Errors::InvalidString(addr)),
+        }
+    }
+
+    // STREAMSTR
+    pub(in crate::glulx_terp) fn stream_str(&mut self, addr: u32) -> Result<(), Errors> {
+        let start = self.string_start(addr)?;
+        self.stream_string(start, false)
+    }
+
+    // Prints from `pos` until the string ends. `substring` tells whether a RESUME_CODE
+    // stub is on the stack: it's pushed the first time printing has to call a function,
+    // the filter or one embedded in the string, the stubs for the rest of the output
+    // going on top. Strings embedded in a compressed one are printed the same way, and
+    // when one ends the stub below tells what comes next.
+    pub(in crate::glulx_terp) fn stream_string(&mut self, mut pos: StringPos, mut substring: bool) -> Result<(), Errors> {
+        loop {
+            let next = match pos {
+                StringPos::Latin1(addr) => {
+                    let ch = self.memory.get_u8(addr).map_err(Errors::MemoryError)? as u32;
+                    self.string_char(ch, StringPos::Latin1(addr + 1), &mut substring)?
+                }
+                StringPos::Unicode(addr) => {
+                    let ch = self.memory.get_u32(addr).map_err(Errors::MemoryError)?;
+                    self.string_char(ch, StringPos::Unicode(addr + 4), &mut substring)?
+                }
+                StringPos::Compressed { addr, bit } => self.decode_node(addr, bit, &mut substring)?,
+            };
+
+            match next {
+                Next::Continue(next) => pos = next,
+                Next::Called => return Ok(()),
+                Next::End => {
+                    if !substring { return Ok(()) }
+                    let stub = self.stack.pop_call_stub().map_err(Errors::StackError)?;
+                    match StringPos::from_stub(stub) {
+                        Some(outer) => pos = outer,
+                        None if stub.dest_type == stub::RESUME_CODE => {
+                            self.pc = stub.pc;
+                            return Ok(())
+                        }
+                        None => return Err(Errors::InvalidCallStub(stub.dest_type)),
+                    }
+                }
+            }
+        }
+    }
+
+    // A character of an uncompressed string, 0 ending it.
+    fn string_char(&mut self, ch: u32, next: StringPos, substring: &mut bool) -> Result<Next, Errors> {
+        if ch == 0 { return Ok(Next::End) }
+        self.emit(ch, next, substring)
+    }
+
+    fn emit(&mut self, ch: u32, next: StringPos, substring: &mut bool) -> Result<Next, Errors> {
+        if self.iosys.mode != mode::FILTER {
+            self.stream_char(ch)?;
+            return Ok(Next::Continue(next))
+        }
+        self.call_for_string(self.iosys.rock, &[ch], next, substring)?;
+        Ok(Next::Called)
+    }
+
+    // Calls `addr`, with printing resuming at `next` once it returns.
+    fn call_for_string(&mut self, addr: u32, args: &[u32], next: StringPos, substring: &mut bool) -> Result<(), Errors> {
+        self.push_string_stub(next, substring)?;
+        self.enter_function(addr, args)
+    }
+
+    fn push_string_stub(&mut self, next: StringPos, substring: &mut bool) -> Result<(), Errors> {
+        if !*substring {
+            self.stack.push_call_stub(stub::RESUME_CODE, 0, self.pc).map_err(Errors::StackError)?;
+            *substring = true;
+        }
+        let stub = next.stub();
+        self.stack.push_call_stub(stub.dest_type, stub.dest_addr, stub.pc).map_err(Errors::StackError)
+    }
+
+    // Follows the bits from `addr` down the decoding table to a leaf and acts on it.
+    fn decode_node(&mut self, mut addr: u32, mut bit: u32, substring: &mut bool) -> Result<Next, Errors> {
+        let table = self.string_table;
+        if table == 0 { return Err(Errors::NoStringTable) }
+        let get_u8 = |terp: &Self, pos| terp.memory.get_u8(pos).map_err(Errors::MemoryError);
+        let get_u32 = |terp: &Self, pos| terp.memory.get_u32(pos).map_err(Errors::MemoryError);
+
+        let mut node = get_u32(self, table + 8)?;
+        let kind = loop {
+            let kind = get_u8(self, node)?;
+            if kind != node::BRANCH { break kind }
+            let right = (get_u8(self, addr)? >> bit) & 1;
+            bit += 1;
+            if bit == 8 {
+                bit = 0;
+                addr += 1;
+            }
+            node = get_u32(self, node + 1 + 4 * right as u32)?;
+        };
+        let next = StringPos::Compressed { addr, bit };
+
+        match kind {
+            node::TERMINATOR => Ok(Next::End),
+            node::CHAR => self.emit(get_u8(self, node + 1)? as u32, next, substring),
+            node::UNICHAR => self.emit(get_u32(self, node + 1)?, next, substring),
+            // Through a filter these are printed like embedded strings, one character per call.
+            node::STRING | node::UNISTRING => {
+                let inner = if kind == node::STRING { StringPos::Latin1(node + 1) } else { StringPos::Unicode(node + 1) };
+                if self.iosys.mode == mode::FILTER {
+                    self.push_string_stub(next, substring)?;
+                    return Ok(Next::Continue(inner))
+                }
+                self.stream_string(inner, false)?;
+                Ok(Next::Continue(next))
+            }
+            node::INDIRECT | node::DOUBLE_INDIRECT | node::INDIRECT_ARGS | node::DOUBLE_INDIRECT_ARGS => {
+                let mut target = get_u32(self, node + 1)?;
+                if matches!(kind, node::DOUBLE_INDIRECT | node::DOUBLE_INDIRECT_ARGS) {
+                    target = get_u32(self, target)?;
+                }
+                let mut args = Vec::new();
+                if matches!(kind, node::INDIRECT_ARGS | node::DOUBLE_INDIRECT_ARGS) {
+                    let count = get_u32(self, node + 5)?;
+                    for index in 0..count {
+                        args.push(get_u32(self, node + 9 + 4 * index)?);
+                    }
+                }
+
+                // Strings ignore the arguments, functions have their result discarded.
+                match get_u8(self, target)? {
+                    0xE0..=0xE2 => {
+                        let inner = self.string_start(target)?;
+                        self.push_string_stub(next, substring)?;
+                        Ok(Next::Continue(inner))
+                    }
+                    0xC0 | 0xC1 => {
+                        self.call_for_string(target, &args, next, substring)?;
+                        Ok(Next::Called)
+                    }
+                    _ => Err(Errors::InvalidString(target)),
+                }
+            }
+            _ => Err(Errors::InvalidString(node)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glulx_terp::{glk::backend::HeadlessBackend, test_support::{function, glk, op, story, Arg}, GlulxTerp};
+
+    fn branch(left: u32, right: u32) -> Vec<u8> {
+        [[0].as_slice(), &left.to_be_bytes(), &right.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn compressed_strings_walk_the_decoding_tree() {
+        // Prints the compressed string at 0x290 with the tree at 0x200, then the same
+        // through the filter at 0x100. The rest are a Latin-1 and a Unicode string.
+        let main = function(&[], 0xC1, &[
+            glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Stack),
+            glk(0x2F, vec![Arg::Stack], Arg::Zero),
+            op(0x149, &[Arg::Const(2), Arg::Zero]),
+            op(0x141, &[Arg::Const(0x200)]),
+            op(0x72, &[Arg::Const(0x290)]),
+            op(0x72, &[Arg::Const(0x2A0)]),
+            op(0x72, &[Arg::Const(0x2B0)]),
+            op(0x149, &[Arg::Const(1), Arg::Const(0x100)]),
+            op(0x72, &[Arg::Const(0x290)]),
+            op(0x70, &[Arg::Const('.' as i32)]),
+            op(0x140, &[Arg::Addr(0x2F0)]),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        let mut ram = function(&[(4, 1)], 0xC1, &[
+            glk(0x80, vec![Arg::Local(0)], Arg::Zero),
+            op(0x31, &[Arg::Zero]),
+        ]);
+        ram.resize(0x200, 0);
+        // Table header (length, node count, root), then the nodes. The 0x08 node at
+        // 0x270 is an indirect reference to the string at 0x280.
+        for (addr, bytes) in [
+            (0x200, vec![0, 0, 0, 0x90, 0, 0, 0, 8, 0, 0, 2, 0x10]),
+            (0x210, branch(0x220, 0x230)),
+            (0x220, branch(0x240, 0x250)),
+            (0x230, branch(0x260, 0x270)),
+            (0x240, vec![1]),
+            (0x250, vec![2, b'h']),
+            (0x260, vec![3, b'e', b'y', 0]),
+            (0x270, vec![8, 0, 0, 2, 0x80]),
+            (0x280, vec![0xE0, b'!', b'?', 0]),
+            (0x290, vec![0xE1, 0xB6, 0x00]),
+            (0x2A0, vec![0xE0, b'|', 0]),
+            (0x2B0, vec![0xE2, 0, 0, 0, 0, 0, 0x26, 0x3A, 0, 0, 0, 0]),
+        ] {
+            ram[addr - 0x100..addr - 0x100 + bytes.len()].copy_from_slice(&bytes);
+        }
+        let mut terp = GlulxTerp::from_bytes(story(&main, &ram)).unwrap();
+        terp.run().unwrap();
+        assert_eq!(terp.glk_backend::<HeadlessBackend>().unwrap().transcript(), "hey!?h|\u{263A}hey!?h.");
+        assert_eq!(terp.memory.get_u32(0x2F0).unwrap(), 0x200);
+    }
+}
@@ -0,0 +1,68 @@
+use super::{glk::backend::HeadlessBackend, Errors, GlulxTerp};
+
+// Plays a story turn by turn against the headless backend, so tests can
+// read like a play session: run to the prompt, type a line, check the output.
+pub struct TestDriver {
+    terp: GlulxTerp,
+    // How much of the transcript was already returned by run_to_prompt.
+    seen: usize,
+}
+
+impl TestDriver {
+    pub fn new(story: Vec<u8>) -> Result<Self, Errors> {
+        let mut terp = GlulxTerp::from_bytes(story)?;
+        terp.set_glk_backend(Box::new(HeadlessBackend::default()));
+        Ok(Self { terp, seen: 0 })
+    }
+
+    pub fn terp(&mut self) -> &mut GlulxTerp {
+        &mut self.terp
+    }
+
+    // Runs until the story waits for input (or ends) and returns what it printed since the last prompt.
+    pub fn run_to_prompt(&mut self) -> Result<String, Errors> {
+        self.terp.run_until(|_| false)?;
+
+        let output = self.output();
+        let fresh = output[self.seen..].to_string();
+        self.seen = output.len();
+        Ok(fresh)
+    }
+
+    pub fn input(&mut self, line: &str) -> Result<(), Errors> {
+        self.terp.provide_input(line)
+    }
+
+    // Everything printed so far, including echoed input.
+    pub fn output(&self) -> &str {
+        self.terp.glk_backend::<HeadlessBackend>()
+            .map(HeadlessBackend::transcript)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestDriver;
+    use crate::glulx_terp::test_support::echo_story;
+
+    #[test]
+    fn answer_is_echoed_back() {
+        let mut driver = TestDriver::new(echo_story(2)).unwrap();
+        assert_eq!(driver.run_to_prompt().unwrap(), "What is your name?\n>");
+        driver.input("Ada").unwrap();
+        assert_eq!(driver.run_to_prompt().unwrap(), "Ada\nHello, Ada!\nWhat is your name?\n>");
+    }
+
+    #[test]
+    fn output_keeps_the_whole_session() {
+        let mut driver = TestDriver::new(echo_story(2)).unwrap();
+        for name in ["Ada", "Grace"] {
+            driver.run_to_prompt().unwrap();
+            driver.input(name).unwrap();
+        }
+        assert_eq!(driver.run_to_prompt().unwrap(), "Grace\nHello, Grace!\n");
+        assert!(driver.terp().has_halted());
+        assert_eq!(driver.output(), "What is your name?\n>Ada\nHello, Ada!\nWhat is your name?\n>Grace\nHello, Grace!\n");
+    }
+}
@@ -0,0 +1,142 @@
+// Builders for the tiny stories the unit tests run.
+
+// An operand, encoded with the smallest addressing mode that fits.
+pub enum Arg {
+    Zero,
+    Const(i32),
+    Addr(u32),
+    Stack,
+    Local(u32),
+}
+
+// Assembles one instruction.
+pub fn op(code: u32, args: &[Arg]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match code {
+        0..=0x7F => out.push(code as u8),
+        0x80..=0x3FFF => out.extend((code as u16 | 0x8000).to_be_bytes()),
+        _ => out.extend((code | 0xC000_0000).to_be_bytes()),
+    }
+
+    let mut modes = Vec::new();
+    let mut data = Vec::new();
+    for arg in args {
+        match *arg {
+            Arg::Zero => modes.push(0),
+            Arg::Const(value) if (-0x80..0x80).contains(&value) => { modes.push(1); data.push(value as u8) }
+            Arg::Const(value) if (-0x8000..0x8000).contains(&value) => { modes.push(2); data.extend((value as i16).to_be_bytes()) }
+            Arg::Const(value) => { modes.push(3); data.extend(value.to_be_bytes()) }
+            Arg::Addr(addr) => { modes.push(7); data.extend(addr.to_be_bytes()) }
+            Arg::Stack => modes.push(8),
+            Arg::Local(offset) => { modes.push(9); data.push(offset as u8) }
+        }
+    }
+    for pair in modes.chunks(2) {
+        out.push(pair[0] | pair.get(1).map_or(0, |mode| mode << 4));
+    }
+    out.extend(data);
+    out
+}
+
+pub fn push(arg: Arg) -> Vec<u8> {
+    op(0x10, &[arg, Arg::Zero, Arg::Stack])
+}
+
+// Calls a Glk function, its arguments go on the stack last one first.
+pub fn glk(selector: u32, args: Vec<Arg>, result: Arg) -> Vec<u8> {
+    let count = args.len() as i32;
+    let mut out: Vec<u8> = args.into_iter().rev().flat_map(push).collect();
+    out.extend(op(0x130, &[Arg::Const(selector as i32), Arg::Const(count), result]));
+    out
+}
+
+// Prints `text` one STREAMCHAR at a time.
+pub fn print(text: &str) -> Vec<u8> {
+    text.chars().flat_map(|ch| op(0x70, &[Arg::Const(ch as i32)])).collect()
+}
+
+// A function of the given type (0xC0 or 0xC1) with its locals format and body.
+pub fn function(locals: &[(u8, u8)], kind: u8, body: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![kind];
+    for (size, count) in locals {
+        out.extend([*size, *count]);
+    }
+    out.extend([0, 0]);
+    out.extend(body.concat());
+    out
+}
+
+// A story with `code` at 0x40, where the start function is, and `ram` from 0x100
+// rounded up to a whole page. The checksum matches.
+pub fn story(code: &[u8], ram: &[u8]) -> Vec<u8> {
+    let mut image = vec![0; 0x100];
+    image[0x40..0x40 + code.len()].copy_from_slice(code);
+    image.extend(ram);
+    image.resize(0x100 + ram.len().div_ceil(0x100).max(1) * 0x100, 0);
+
+    let length = image.len() as u32;
+    image[0..4].copy_from_slice(b"Glul");
+    for (offset, value) in [(4, 0x0003_0103), (8, 0x100), (12, length), (16, length), (20, 0x1000), (24, 0x40)] {
+        image[offset..offset + 4].copy_from_slice(&u32::to_be_bytes(value));
+    }
+    let checksum = image.chunks(4)
+        .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+        .fold(0u32, u32::wrapping_add);
+    image[32..36].copy_from_slice(&checksum.to_be_bytes());
+    image
+}
+
+// A resource as (usage, number, chunk type, data).
+pub type Chunk = ([u8; 4], u32, [u8; 4], Vec<u8>);
+
+// A Blorb file holding `chunks`.
+pub fn blorb(chunks: &[Chunk]) -> Vec<u8> {
+    let index_len = 4 + 12 * chunks.len() as u32;
+    let mut index = (chunks.len() as u32).to_be_bytes().to_vec();
+    let mut body = Vec::new();
+    let mut pos = 12 + 8 + index_len;
+    for (usage, number, kind, data) in chunks {
+        index.extend([*usage, number.to_be_bytes(), pos.to_be_bytes()].concat());
+        let mut chunk = [kind.as_slice(), &(data.len() as u32).to_be_bytes(), data].concat();
+        if chunk.len() % 2 == 1 { chunk.push(0) }
+        pos += chunk.len() as u32;
+        body.extend(chunk);
+    }
+
+    let form = [b"IFRS".as_slice(), b"RIdx", &index_len.to_be_bytes(), &index, &body].concat();
+    [b"FORM".as_slice(), &(form.len() as u32).to_be_bytes(), &form].concat()
+}
+
+// The start of a PNG file, enough for its size to be read.
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    [b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".as_slice(), &width.to_be_bytes(), &height.to_be_bytes(), &[8, 6, 0, 0, 0]].concat()
+}
+
+// Asks for a name `turns` times and greets whoever answers. The question is at
+// 0x200, the line goes to 0x280 and its event to 0x2C0.
+pub fn echo_story(turns: usize) -> Vec<u8> {
+    let mut body = vec![
+        glk(0x23, vec![Arg::Zero, Arg::Zero, Arg::Zero, Arg::Const(3), Arg::Zero], Arg::Local(0)),
+        glk(0x2F, vec![Arg::Local(0)], Arg::Zero),
+        op(0x149, &[Arg::Const(2), Arg::Zero]),
+    ];
+    body.extend((0..turns).map(|_| op(0x161, &[Arg::Const(0x100), Arg::Local(0), Arg::Zero])));
+    body.push(op(0x31, &[Arg::Zero]));
+    let main = function(&[(4, 1)], 0xC1, &body);
+    let mut ram = function(&[(4, 1)], 0xC1, &[
+        op(0x72, &[Arg::Const(0x200)]),
+        glk(0xD0, vec![Arg::Local(0), Arg::Const(0x280), Arg::Const(32), Arg::Zero], Arg::Zero),
+        glk(0xC0, vec![Arg::Const(0x2C0)], Arg::Zero),
+        op(0x72, &[Arg::Const(0x220)]),
+        glk(0x84, vec![Arg::Const(0x280), Arg::Addr(0x2C8)], Arg::Zero),
+        op(0x72, &[Arg::Const(0x240)]),
+        op(0x31, &[Arg::Zero]),
+    ]);
+    ram.resize(0x200, 0);
+    for (addr, text) in [(0x200, "What is your name?\n>"), (0x220, "Hello, "), (0x240, "!\n")] {
+        let at = addr - 0x100;
+        ram[at] = 0xE0;
+        ram[at + 1..at + 1 + text.len()].copy_from_slice(text.as_bytes());
+    }
+    story(&main, &ram)
+}
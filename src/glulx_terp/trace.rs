@@ -0,0 +1,168 @@
+use super::{exec::ExecState, operations::{OPCode, Operation}, Errors, GlulxTerp};
+
+// One executed instruction: where it was, what it was, the values it loaded and
+// the values it stored, in operand order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u32,
+    pub opcode: OPCode,
+    pub loads: Vec<u32>,
+    pub stores: Vec<u32>,
+}
+
+// Called with the address and decoded instruction before each one executes.
+pub type Tracer = Box<dyn FnMut(u32, &Operation)>;
+
+impl GlulxTerp {
+    // Installs a hook that sees every instruction about to execute, replacing any previous one.
+    pub fn set_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.tracer = None;
+    }
+
+    // Executes up to `n` instructions and records each of them. Stops early once the
+    // story halts, waits for input or pauses at a DEBUGTRAP. An error stops it too and
+    // comes back with the records of the instructions before it.
+    pub fn step_n(&mut self, n: usize) -> Result<Vec<TraceRecord>, (Vec<TraceRecord>, Errors)> {
+        let mut records = Vec::new();
+        self.recording = true;
+        for _ in 0..n {
+            if self.has_halted() || self.awaiting_input() { break }
+            // The failing instruction's record is left out, it may be half filled.
+            let result = self.step();
+            let record = self.record.take();
+            if let Err(err) = result {
+                self.recording = false;
+                return Err((records, err))
+            }
+            records.extend(record);
+            if self.state != ExecState::Running { break }
+        }
+        self.recording = false;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::glulx_terp::{exec::PauseReason, test_support::{function, op, story, Arg}};
+
+    #[test]
+    fn tracer_sees_each_instruction_up_to_the_limit() {
+        // l0 += 1 then jumps back to it, forever.
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x10, &[Arg::Local(0), Arg::Const(1), Arg::Local(0)]),
+            op(0x20, &[Arg::Const(-7)]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        terp.set_trace(Box::new(move |pc, operation| log.borrow_mut().push((pc, operation.code))));
+        assert_eq!(terp.run_with_limit(Some(5)).unwrap(), ExecState::Paused(PauseReason::CycleLimit));
+        assert_eq!(*seen.borrow(), [
+            (0x45, OPCode::ADD), (0x4B, OPCode::JUMP), (0x45, OPCode::ADD), (0x4B, OPCode::JUMP), (0x45, OPCode::ADD),
+        ]);
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 3);
+
+        // Without the tracer the count keeps going from there.
+        terp.clear_trace();
+        terp.run_with_limit(Some(2)).unwrap();
+        assert_eq!(seen.borrow().len(), 5);
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 4);
+    }
+
+    #[test]
+    fn step_n_records_each_instruction() {
+        // l0 = 2 + 3, l1 = l0 * 4, pushes l1 - 1 and l1 << 1, then returns.
+        let code = function(&[(4, 2)], 0xC1, &[
+            op(0x10, &[Arg::Const(2), Arg::Const(3), Arg::Local(0)]),
+            op(0x12, &[Arg::Local(0), Arg::Const(4), Arg::Local(4)]),
+            op(0x11, &[Arg::Local(4), Arg::Const(1), Arg::Stack]),
+            op(0x1C, &[Arg::Local(4), Arg::Const(1), Arg::Stack]),
+            op(0x14, &[Arg::Stack, Arg::Stack, Arg::Stack]),
+            op(0x31, &[Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let records = terp.step_n(5).unwrap();
+
+        let expected = [
+            (0x45, OPCode::ADD, vec![2, 3], vec![5]),
+            (0x4B, OPCode::MUL, vec![5, 4], vec![20]),
+            (0x51, OPCode::SUB, vec![20, 1], vec![19]),
+            (0x56, OPCode::SHIFTL, vec![20, 1], vec![40]),
+            (0x5B, OPCode::MOD, vec![40, 19], vec![2]),
+        ];
+        assert_eq!(records.len(), expected.len());
+        for (record, (pc, opcode, loads, stores)) in records.into_iter().zip(expected) {
+            assert_eq!(record, TraceRecord { pc, opcode, loads, stores });
+        }
+
+        // Only the return is left, nothing runs once the story has halted.
+        assert_eq!(terp.step_n(5).unwrap().len(), 1);
+        assert!(terp.has_halted());
+        assert!(terp.step_n(5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn step_n_stops_at_a_debug_trap() {
+        let code = function(&[], 0xC1, &[op(0x101, &[Arg::Const(7)]), op(0x31, &[Arg::Zero])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let records = terp.step_n(5).unwrap();
+        assert_eq!(records.iter().map(|record| record.opcode).collect::<Vec<_>>(), [OPCode::DEBUGTRAP]);
+        assert_eq!(terp.state(), ExecState::Paused(PauseReason::DebugTrap(7)));
+    }
+
+    #[test]
+    fn step_n_keeps_the_records_before_an_error() {
+        // The second instruction reads past the end of memory.
+        let code = function(&[], 0xC1, &[
+            op(0x10, &[Arg::Const(1), Arg::Const(1), Arg::Stack]),
+            op(0x40, &[Arg::Addr(0x1_0000), Arg::Stack]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let Err((records, error)) = terp.step_n(5) else { panic!("expected an error") };
+        assert!(matches!(error, Errors::MemoryError(_)), "{error:?}");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].stores, [2]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn every_instruction_emits_a_tracing_event() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{field::{Field, Visit}, span, Event, Metadata, Subscriber};
+
+        // Keeps the pc of each event.
+        struct Pcs(Arc<Mutex<Vec<u64>>>);
+
+        impl Visit for Pcs {
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                if field.name() == "pc" { self.0.lock().unwrap().push(value) }
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+        }
+
+        impl Subscriber for Pcs {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool { true }
+            fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id { span::Id::from_u64(1) }
+            fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+            fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+            fn event(&self, event: &Event<'_>) { event.record(&mut Pcs(self.0.clone())) }
+            fn enter(&self, _span: &span::Id) {}
+            fn exit(&self, _span: &span::Id) {}
+        }
+
+        let code = function(&[], 0xC1, &[op(0x50, &[Arg::Stack]), op(0x50, &[Arg::Stack]), op(0x31, &[Arg::Zero])]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[])).unwrap();
+        let pcs = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Pcs(pcs.clone()), || terp.run().unwrap());
+        assert_eq!(*pcs.lock().unwrap(), [0x43, 0x45, 0x47]);
+    }
+}
@@ -0,0 +1,97 @@
+use super::{quetzal::SaveState, Errors, GlulxTerp};
+
+impl GlulxTerp {
+    // SAVEUNDO: keeps a snapshot with the instruction's call stub on top of its stack,
+    // dropping the oldest once `undo_depth` are kept. Returns whether it was saved.
+    pub(in crate::glulx_terp) fn save_undo(&mut self) -> bool {
+        if self.config.undo_depth == 0 { return false }
+        if self.undo.len() == self.config.undo_depth {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(self.save_state());
+        true
+    }
+
+    // RESTOREUNDO: goes back to the latest snapshot, each call one level further back.
+    // Returns false when there is none left.
+    pub(in crate::glulx_terp) fn restore_undo(&mut self) -> Result<bool, Errors> {
+        let Some(state): Option<SaveState> = self.undo.pop_back() else { return Ok(false) };
+        self.restore_state(state)?;
+        Ok(true)
+    }
+
+    pub(in crate::glulx_terp) fn has_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub(in crate::glulx_terp) fn discard_undo(&mut self) {
+        self.undo.pop_back();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glulx_terp::{config::TerpConfig, test_support::{function, op, push, story, Arg}, GlulxTerp};
+
+    // Writes 1, 2 and 3 to 0x100 with a SAVEUNDO after each, then 4. l1 gets gestalt ExtUndo.
+    fn three_turns() -> Vec<u8> {
+        let mut body = Vec::new();
+        for value in 1..=3 {
+            body.push(op(0x4C, &[Arg::Const(0x100), Arg::Zero, Arg::Const(value)]));
+            body.push(op(0x125, &[Arg::Local(0)]));
+        }
+        body.push(op(0x4C, &[Arg::Const(0x100), Arg::Zero, Arg::Const(4)]));
+        body.push(op(0x100, &[Arg::Const(12), Arg::Zero, Arg::Local(4)]));
+        story(&function(&[(4, 2)], 0xC1, &body), &[0; 4])
+    }
+
+    #[test]
+    fn each_restore_goes_back_one_more_level() {
+        let mut terp = GlulxTerp::from_bytes(three_turns()).unwrap();
+        for _ in 0..8 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), 0);
+        assert_eq!(terp.stack.get_local(4, 4).unwrap(), 1);
+
+        for expected in [3, 2, 1] {
+            assert!(terp.restore_undo().unwrap());
+            assert_eq!(terp.memory.get_u32(0x100).unwrap(), expected);
+            assert_eq!(terp.stack.get_local(0, 4).unwrap(), u32::MAX);
+        }
+        assert!(!terp.restore_undo().unwrap());
+    }
+
+    #[test]
+    fn oldest_level_is_dropped_past_the_depth() {
+        let config = TerpConfig { undo_depth: 2, ..Default::default() };
+        let mut terp = GlulxTerp::from_bytes_with_config(three_turns(), config).unwrap();
+        for _ in 0..7 { terp.step().unwrap(); }
+        for expected in [3, 2] {
+            assert!(terp.restore_undo().unwrap());
+            assert_eq!(terp.memory.get_u32(0x100).unwrap(), expected);
+        }
+        assert!(!terp.has_undo());
+    }
+
+    #[test]
+    fn restore_rolls_back_ram_and_stack_but_not_the_protected_range() {
+        let code = function(&[(4, 1)], 0xC1, &[
+            op(0x125, &[Arg::Local(0)]),
+            op(0x10, &[Arg::Const(5), Arg::Zero, Arg::Addr(0x104)]),
+            op(0x10, &[Arg::Const(6), Arg::Zero, Arg::Addr(0x108)]),
+            push(Arg::Const(7)),
+            op(0x127, &[Arg::Const(0x108), Arg::Const(4)]),
+            op(0x126, &[Arg::Zero]),
+        ]);
+        let mut terp = GlulxTerp::from_bytes(story(&code, &[0; 0x10])).unwrap();
+        for _ in 0..5 { terp.step().unwrap(); }
+        assert_eq!(terp.stack.count(), 1);
+
+        // Back right after the SAVEUNDO, which now answers -1.
+        terp.step().unwrap();
+        assert_eq!(terp.pc, 0x49);
+        assert_eq!(terp.stack.get_local(0, 4).unwrap(), u32::MAX);
+        assert_eq!(terp.stack.count(), 0);
+        assert_eq!(terp.memory.get_u32(0x104).unwrap(), 0);
+        assert_eq!(terp.memory.get_u32(0x108).unwrap(), 6);
+    }
+}
@@ -0,0 +1,5 @@
+// The Glulx virtual machine, for front ends and harnesses to embed. The CLI in
+// main.rs is one of them.
+pub mod glulx_terp;
+
+pub use glulx_terp::{GlulxTerp, Errors, memory::{Memory, MemoryError}, operations::Operation};
@@ -1,6 +1,5 @@
-mod glulx_terp;
-use std::{env, fs::File, path::Path};
-use crate::glulx_terp::GlulxTerp;
+use std::{env, fs::File, io::Read, path::Path};
+use glulx_terp_rs::glulx_terp::{self, GlulxTerp, exec::{ExecState, PauseReason}, glk::console::ConsoleBackend, memory::{Header, Memory}};
 
 #[derive(Debug)]
 pub enum Errors {
@@ -9,25 +8,76 @@ pub enum Errors {
     Interpreter(glulx_terp::Errors),
 }
 
+fn print_info(header: &Header) {
+    let version = header.version;
+    println!("{{");
+    println!("  \"header\": {{");
+    println!("    \"version\": \"{}.{}.{}\",", version.major, version.minor, version.patch);
+    println!("    \"ram_start\": {},", header.ram_start);
+    println!("    \"ext_start\": {},", header.ext_start);
+    println!("    \"end_mem\": {},", header.end_mem);
+    println!("    \"stack_size\": {},", header.stack_size);
+    println!("    \"start_func\": {},", header.start_func);
+    println!("    \"decoding_tree\": {},", header.decoding_tree);
+    println!("    \"checksum\": {}", header.checksum);
+    println!("  }},");
+    println!("  \"memory_map\": {{");
+    println!("    \"rom_size\": {},", header.ram_start);
+    println!("    \"ram_size\": {},", header.ext_start.saturating_sub(header.ram_start));
+    println!("    \"extended_size\": {}", header.end_mem.saturating_sub(header.ext_start));
+    println!("  }}");
+    println!("}}");
+}
 
 fn main() -> Result<(), Errors> {
     let args: Vec<String> = env::args().collect();
 
-    let Some(path) = args.get(1) else {
+    let info = args.iter().skip(1).any(|arg| arg == "--info");
+    let disasm = args.iter().skip(1).any(|arg| arg == "--disasm");
+    let Some(path) = args.iter().skip(1).find(|arg| !arg.starts_with("--")) else {
         return Err(Errors::TargetArgNotFound)
     };
 
     let path = Path::new(path);
 
-    println!("Trying to load: {path:?}");
-
     let mut file = File::open(path).map_err(Errors::TargetLoading)?;
 
+    if info {
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw).map_err(Errors::TargetLoading)?;
+        let memory = Memory::from_blorb_or_raw(raw)
+            .map_err(|err| Errors::Interpreter(glulx_terp::Errors::MemoryError(err)))?;
+        print_info(memory.get_header());
+        return Ok(())
+    }
+
+    if disasm {
+        let terp = GlulxTerp::from_reader(&mut file).map_err(Errors::Interpreter)?;
+        let (listing, stop, err) = terp.linear_listing(terp.memory().get_header().start_func);
+        for entry in listing {
+            println!("{entry}");
+        }
+        println!("{stop:08x}: stopped, {err:?}");
+        return Ok(())
+    }
+
+    println!("Trying to load: {path:?}");
+
     let mut terp = GlulxTerp::from_reader(&mut file)
         .map_err(Errors::Interpreter)?;
     println!("Successfully loaded target.");
+    let memory = terp.memory();
+    if memory.checksum() != memory.get_header().checksum {
+        eprintln!("Warning: the checksum doesn't match the header, the story file may be damaged.");
+    }
+    terp.set_glk_backend(Box::new(ConsoleBackend::stdio()));
+    terp.set_story_path(path);
+
+    match terp.run().map_err(Errors::Interpreter)? {
+        ExecState::Paused(PauseReason::DebugTrap(value)) => eprintln!("Stopped at debug trap {value}, pc {:#x}.", terp.pc()),
+        ExecState::Paused(PauseReason::AwaitingInput) => eprintln!("Stopped waiting for input, none is left to read."),
+        _ => {}
+    }
 
-    terp.run();
-    
     Ok(())
 }
@@ -0,0 +1,36 @@
+use std::{collections::HashMap, process::Command};
+use glulx_terp_rs::Memory;
+
+const STORY: &str = "assets/glulxercise.ulx";
+
+// The output is flat enough to read back as `"key": value` pairs, one per line.
+fn fields(json: &str) -> HashMap<String, String> {
+    json.lines()
+        .filter_map(|line| line.trim().trim_end_matches(',').split_once(": "))
+        .filter(|(_, value)| !value.starts_with('{'))
+        .map(|(key, value)| (key.trim_matches('"').to_string(), value.trim_matches('"').to_string()))
+        .collect()
+}
+
+#[test]
+fn info_prints_the_header_as_json() {
+    let output = Command::new(env!("CARGO_BIN_EXE_glulx-terp-rs")).args(["--info", STORY]).output().unwrap();
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert!(json.starts_with('{') && json.trim_end().ends_with('}'));
+    let fields = fields(&json);
+
+    let memory = Memory::new(std::fs::read(STORY).unwrap()).unwrap();
+    let header = memory.get_header();
+    let version = header.version;
+    assert_eq!(fields["version"], format!("{}.{}.{}", version.major, version.minor, version.patch));
+    for (key, value) in [
+        ("ram_start", header.ram_start), ("ext_start", header.ext_start), ("end_mem", header.end_mem),
+        ("stack_size", header.stack_size), ("start_func", header.start_func),
+        ("decoding_tree", header.decoding_tree), ("checksum", header.checksum),
+        ("rom_size", header.ram_start), ("ram_size", header.ext_start - header.ram_start),
+        ("extended_size", header.end_mem - header.ext_start),
+    ] {
+        assert_eq!(fields[key], value.to_string(), "{key}");
+    }
+}